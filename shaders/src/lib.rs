@@ -36,8 +36,7 @@ pub fn main_vs(
     in_pos: Vec3,
     in_tex: Vec2,
     in_normal: Vec3,
-    in_tangent: Vec3,
-    in_bitangent: Vec3,
+    in_tangent: Vec4,
     model0: Vec4,
     model1: Vec4,
     model2: Vec4,
@@ -62,8 +61,8 @@ pub fn main_vs(
 
     //construct the tanget matrix
     let world_normal = (normal_mat * in_normal).normalize();
-    let world_tangent = (normal_mat * in_tangent).normalize();
-    let world_bitangent = (normal_mat * in_bitangent).normalize();
+    let world_tangent = (normal_mat * in_tangent.truncate()).normalize();
+    let world_bitangent = world_normal.cross(world_tangent) * in_tangent.w;
 
     let tangent_mat = Mat3::from_cols(world_tangent, world_bitangent, world_normal);
 
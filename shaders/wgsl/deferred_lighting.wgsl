@@ -0,0 +1,183 @@
+// Vertex shader
+
+[[block]]
+struct Camera {
+    view_pos: vec4<f32>;
+    view_proj: mat4x4<f32>;
+    inv_view_proj: mat4x4<f32>;
+};
+[[group(1), binding(0)]]
+var<uniform> camera: Camera;
+
+[[block]]
+struct DebugView {
+    view: u32;
+};
+[[group(1), binding(1)]]
+var<uniform> debug_view: DebugView;
+
+// White when SSAO is turned off
+[[group(1), binding(2)]]
+var t_ao: texture_2d<f32>;
+
+[[block]]
+struct Fog {
+    color: vec3<f32>;
+    mode: u32;
+    start: f32;
+    end: f32;
+    density: f32;
+    height: f32;
+    height_density: f32;
+    height_falloff: f32;
+};
+[[group(1), binding(3)]]
+var<uniform> fog: Fog;
+
+struct Light {
+    position: vec3<f32>;
+    radius: f32;
+    color: vec3<f32>;
+    intensity: f32;
+};
+[[block]]
+struct Lights {
+    count: u32;
+    data: [[stride(32)]] array<Light>;
+};
+[[group(2), binding(0)]]
+var<storage, read> lights: Lights;
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+};
+
+// A single triangle that covers the whole screen
+[[stage(vertex)]]
+fn main([[builtin(vertex_index)]] vertex_index: u32) -> VertexOutput {
+    let x = f32(i32(vertex_index & 1u) * 4 - 1);
+    let y = f32(i32(vertex_index >> 1u) * 4 - 1);
+
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(x, y, 0.0, 1.0);
+    return out;
+}
+
+// Fragment shader
+
+// See FogSettings in fog.rs
+fn apply_fog(color: vec3<f32>, world_position: vec3<f32>) -> vec3<f32> {
+    let to_camera = camera.view_pos.xyz - world_position;
+    let distance = length(to_camera);
+
+    var amount: f32 = 0.0;
+    if (fog.mode == 1u) {
+        amount = clamp((distance - fog.start) / (fog.end - fog.start), 0.0, 1.0);
+    }
+    if (fog.mode == 2u) {
+        amount = 1.0 - exp(-fog.density * distance);
+    }
+    if (fog.mode == 3u) {
+        let d = fog.density * distance;
+        amount = 1.0 - exp(-d * d);
+    }
+
+    // Density falls off exponentially with height, integrated along the ray
+    // from the camera to the fragment
+    if (fog.height_density > 0.0) {
+        let camera_density = exp(-fog.height_falloff * (camera.view_pos.y - fog.height));
+        let rise = -to_camera.y * fog.height_falloff;
+        var along_ray: f32 = 1.0;
+        if (abs(rise) > 0.0001) {
+            along_ray = (1.0 - exp(-rise)) / rise;
+        }
+        let height_amount = 1.0 - exp(-fog.height_density * camera_density * along_ray * distance);
+        amount = 1.0 - (1.0 - amount) * (1.0 - clamp(height_amount, 0.0, 1.0));
+    }
+
+    return mix(color, fog.color, amount);
+}
+
+[[group(0), binding(0)]]
+var t_albedo: texture_2d<f32>;
+[[group(0), binding(1)]]
+var t_normal: texture_2d<f32>;
+[[group(0), binding(2)]]
+var t_material: texture_2d<f32>;
+[[group(0), binding(3)]]
+var t_depth: texture_depth_2d;
+[[group(0), binding(4)]]
+var t_emission: texture_2d<f32>;
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let coords = vec2<i32>(in.clip_position.xy);
+    let depth = textureLoad(t_depth, coords, 0);
+
+    // Nothing was drawn here, leave the clear color alone
+    if (depth >= 1.0) {
+        discard;
+    }
+
+    let albedo = textureLoad(t_albedo, coords, 0);
+    let normal = normalize(textureLoad(t_normal, coords, 0).xyz);
+    let material = textureLoad(t_material, coords, 0);
+    let specular_factor = material.r;
+    let shininess = max(material.g * 256.0, 1.0);
+    let emission = textureLoad(t_emission, coords, 0).xyz;
+
+    // Rebuild the world position from the depth buffer
+    let uv = in.clip_position.xy / vec2<f32>(textureDimensions(t_depth));
+    let ndc = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth, 1.0);
+    let world = camera.inv_view_proj * ndc;
+    let world_position = world.xyz / world.w;
+
+    let view_dir = normalize(camera.view_pos.xyz - world_position);
+
+    // The first light is the main scene light, it also drives the ambient term
+    let ambient_occlusion = textureLoad(t_ao, coords, 0).r;
+    let ambient_color = lights.data[0].color * lights.data[0].intensity * 0.1 * ambient_occlusion;
+
+    var lighting: vec3<f32> = ambient_color;
+    var i: u32 = 0u;
+    loop {
+        if (i >= lights.count) {
+            break;
+        }
+        let light = lights.data[i];
+
+        let to_light = light.position - world_position;
+        let distance = length(to_light);
+        let light_dir = to_light / distance;
+        let half_dir = normalize(view_dir + light_dir);
+
+        // A radius of zero means the light doesn't fall off
+        var attenuation: f32 = 1.0;
+        if (light.radius > 0.0) {
+            let falloff = clamp(1.0 - (distance * distance) / (light.radius * light.radius), 0.0, 1.0);
+            attenuation = falloff * falloff;
+        }
+
+        let diffuse_strength = max(dot(normal, light_dir), 0.0);
+        let specular_strength = pow(max(dot(normal, half_dir), 0.0), shininess) * specular_factor;
+
+        lighting = lighting + (diffuse_strength + specular_strength) * light.color * light.intensity * attenuation;
+
+        continuing {
+            i = i + 1u;
+        }
+    }
+
+    // See DebugView in render.rs
+    if (debug_view.view == 1u) {
+        return vec4<f32>(albedo.xyz, 1.0);
+    }
+    if (debug_view.view == 2u) {
+        return vec4<f32>(normal * 0.5 + 0.5, 1.0);
+    }
+    if (debug_view.view == 3u) {
+        return vec4<f32>(lighting, 1.0);
+    }
+
+    return vec4<f32>(apply_fog(lighting * albedo.xyz + emission, world_position), albedo.a);
+}
@@ -0,0 +1,85 @@
+// Depth only pre-pass so SSAO has a depth buffer to work with before the
+// forward pass shades anything.
+
+[[block]]
+struct Camera {
+    view_pos: vec4<f32>;
+    view_proj: mat4x4<f32>;
+};
+[[group(1), binding(0)]]
+var<uniform> camera: Camera;
+
+// See shader.wgsl
+[[block]]
+struct Dequantize {
+    offset: vec3<f32>;
+    scale: vec3<f32>;
+};
+[[group(3), binding(0)]]
+var<uniform> dequantize: Dequantize;
+
+[[block]]
+struct Joints {
+    matrices: [[stride(64)]] array<mat4x4<f32>>;
+};
+[[group(3), binding(1)]]
+var<storage, read> joints: Joints;
+
+struct VertexInput {
+    [[location(0)]] position: vec3<f32>;
+};
+struct CompactVertexInput {
+    [[location(0)]] position: vec4<f32>;
+};
+struct InstanceInput {
+    [[location(4)]] model_matrix_0: vec4<f32>;
+    [[location(5)]] model_matrix_1: vec4<f32>;
+    [[location(6)]] model_matrix_2: vec4<f32>;
+    [[location(7)]] model_matrix_3: vec4<f32>;
+};
+struct SkinInput {
+    [[location(11)]] joints: vec4<u32>;
+    [[location(12)]] weights: vec4<f32>;
+};
+
+fn transform_vertex(position: vec3<f32>, instance: InstanceInput) -> vec4<f32> {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+
+    return camera.view_proj * model_matrix * vec4<f32>(position, 1.0);
+}
+
+[[stage(vertex)]]
+fn main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> [[builtin(position)]] vec4<f32> {
+    return transform_vertex(model.position, instance);
+}
+
+[[stage(vertex)]]
+fn main_compact(
+    model: CompactVertexInput,
+    instance: InstanceInput,
+) -> [[builtin(position)]] vec4<f32> {
+    let position = dequantize.offset + model.position.xyz * dequantize.scale;
+    return transform_vertex(position, instance);
+}
+
+[[stage(vertex)]]
+fn main_skinned(
+    model: VertexInput,
+    instance: InstanceInput,
+    skin: SkinInput,
+) -> [[builtin(position)]] vec4<f32> {
+    let p = vec4<f32>(model.position, 1.0);
+    let position = joints.matrices[skin.joints.x] * p * skin.weights.x
+        + joints.matrices[skin.joints.y] * p * skin.weights.y
+        + joints.matrices[skin.joints.z] * p * skin.weights.z
+        + joints.matrices[skin.joints.w] * p * skin.weights.w;
+    return transform_vertex(position.xyz, instance);
+}
@@ -0,0 +1,299 @@
+// Vertex shader
+
+[[block]]
+struct Camera {
+    view_pos: vec4<f32>;
+    view_proj: mat4x4<f32>;
+};
+[[group(1), binding(0)]]
+var<uniform> camera: Camera;
+
+// See shader.wgsl
+[[block]]
+struct Dequantize {
+    offset: vec3<f32>;
+    scale: vec3<f32>;
+};
+[[group(3), binding(0)]]
+var<uniform> dequantize: Dequantize;
+
+// See shader.wgsl
+[[block]]
+struct Joints {
+    matrices: [[stride(64)]] array<mat4x4<f32>>;
+};
+[[group(3), binding(1)]]
+var<storage, read> joints: Joints;
+
+struct VertexInput {
+    [[location(0)]] position: vec3<f32>;
+    [[location(1)]] tex_coords: vec2<f32>;
+    [[location(2)]] normal: vec3<f32>;
+    // w is the handedness of the bitangent
+    [[location(3)]] tangent: vec4<f32>;
+    // Which layer of the diffuse texture array to sample
+    [[location(13)]] layer: u32;
+};
+struct InstanceInput {
+    [[location(4)]] model_matrix_0: vec4<f32>;
+    [[location(5)]] model_matrix_1: vec4<f32>;
+    [[location(6)]] model_matrix_2: vec4<f32>;
+    [[location(7)]] model_matrix_3: vec4<f32>;
+    [[location(8)]] normal_matrix_0: vec3<f32>;
+    [[location(9)]] normal_matrix_1: vec3<f32>;
+    [[location(10)]] normal_matrix_2: vec3<f32>;
+};
+
+struct SkinInput {
+    [[location(11)]] joints: vec4<u32>;
+    [[location(12)]] weights: vec4<f32>;
+};
+
+struct CompactVertexInput {
+    // w is the handedness of the bitangent, 0 for -1 and 1 for 1
+    [[location(0)]] position: vec4<f32>;
+    [[location(1)]] tex_coords: vec2<f32>;
+    // Both octahedral encoded
+    [[location(2)]] normal: vec2<f32>;
+    [[location(3)]] tangent: vec2<f32>;
+    [[location(13)]] layer: u32;
+};
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] tex_coords: vec2<f32>;
+    [[location(1)]] world_normal: vec3<f32>;
+    [[location(2)]] world_tangent: vec4<f32>;
+    [[location(3)]] world_position: vec3<f32>;
+    [[location(4), interpolate(flat)]] layer: u32;
+};
+
+// Unfolds a unit vector from the octahedron it was flattened onto.
+fn decode_octahedral(e: vec2<f32>) -> vec3<f32> {
+    let z = 1.0 - abs(e.x) - abs(e.y);
+    // The lower half was folded over the diagonals
+    let fold = max(-z, 0.0);
+    let xy = e + select(vec2<f32>(fold, fold), vec2<f32>(-fold, -fold), e >= vec2<f32>(0.0, 0.0));
+    return normalize(vec3<f32>(xy, z));
+}
+
+fn decode_vertex(model: CompactVertexInput) -> VertexInput {
+    let handedness = select(-1.0, 1.0, model.position.w > 0.5);
+    return VertexInput(
+        dequantize.offset + model.position.xyz * dequantize.scale,
+        model.tex_coords,
+        decode_octahedral(model.normal),
+        vec4<f32>(decode_octahedral(model.tangent), handedness),
+        model.layer,
+    );
+}
+
+// Blends where each of the vertex's joints takes `p`, which is a direction
+// when w is zero.
+fn skin_point(p: vec4<f32>, skin: SkinInput) -> vec4<f32> {
+    return joints.matrices[skin.joints.x] * p * skin.weights.x
+        + joints.matrices[skin.joints.y] * p * skin.weights.y
+        + joints.matrices[skin.joints.z] * p * skin.weights.z
+        + joints.matrices[skin.joints.w] * p * skin.weights.w;
+}
+
+// Normals need the inverse transpose of the blended matrix to stay at right
+// angles to the surface when a joint scales unevenly. The cofactor matrix is
+// that times the determinant, which normalizing cancels out but for its sign.
+fn skin_normal(normal: vec3<f32>, skin: SkinInput) -> vec3<f32> {
+    let m0 = joints.matrices[skin.joints.x];
+    let m1 = joints.matrices[skin.joints.y];
+    let m2 = joints.matrices[skin.joints.z];
+    let m3 = joints.matrices[skin.joints.w];
+    let w = skin.weights;
+    let x = (m0[0] * w.x + m1[0] * w.y + m2[0] * w.z + m3[0] * w.w).xyz;
+    let y = (m0[1] * w.x + m1[1] * w.y + m2[1] * w.z + m3[1] * w.w).xyz;
+    let z = (m0[2] * w.x + m1[2] * w.y + m2[2] * w.z + m3[2] * w.w).xyz;
+    let cofactor = mat3x3<f32>(cross(y, z), cross(z, x), cross(x, y));
+    let handedness = select(1.0, -1.0, dot(x, cross(y, z)) < 0.0);
+    return normalize(cofactor * normal) * handedness;
+}
+
+fn skin_vertex(model: VertexInput, skin: SkinInput) -> VertexInput {
+    let tangent = skin_point(vec4<f32>(model.tangent.xyz, 0.0), skin).xyz;
+    return VertexInput(
+        skin_point(vec4<f32>(model.position, 1.0), skin).xyz,
+        model.tex_coords,
+        skin_normal(model.normal, skin),
+        vec4<f32>(normalize(tangent), model.tangent.w),
+        model.layer,
+    );
+}
+
+fn transform_vertex(model: VertexInput, instance: InstanceInput) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    let normal_matrix = mat3x3<f32>(
+        instance.normal_matrix_0,
+        instance.normal_matrix_1,
+        instance.normal_matrix_2,
+    );
+
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+
+    var out: VertexOutput;
+    out.clip_position = camera.view_proj * world_position;
+    out.tex_coords = model.tex_coords;
+    out.layer = model.layer;
+    out.world_normal = normal_matrix * model.normal;
+    out.world_tangent = vec4<f32>(normal_matrix * model.tangent.xyz, model.tangent.w);
+    out.world_position = world_position.xyz;
+    return out;
+}
+
+[[stage(vertex)]]
+fn main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    return transform_vertex(model, instance);
+}
+
+[[stage(vertex)]]
+fn main_compact(
+    model: CompactVertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    return transform_vertex(decode_vertex(model), instance);
+}
+
+[[stage(vertex)]]
+fn main_skinned(
+    model: VertexInput,
+    instance: InstanceInput,
+    skin: SkinInput,
+) -> VertexOutput {
+    return transform_vertex(skin_vertex(model, skin), instance);
+}
+
+// Fragment shader
+
+// All arrays, so materials that only differ by their diffuse texture can be
+// merged into one with a layer each. The rest only ever have the one layer.
+[[group(0), binding(0)]]
+var t_diffuse: texture_2d_array<f32>;
+[[group(0), binding(1)]]
+var s_diffuse: sampler;
+[[group(0), binding(2)]]
+var t_normal: texture_2d_array<f32>;
+[[group(0), binding(3)]]
+var s_normal: sampler;
+[[group(0), binding(4)]]
+var t_specular: texture_2d_array<f32>;
+[[group(0), binding(5)]]
+var s_specular: sampler;
+[[group(0), binding(6)]]
+var t_shininess: texture_2d_array<f32>;
+[[group(0), binding(7)]]
+var s_shininess: sampler;
+[[group(0), binding(8)]]
+var t_emissive: texture_2d_array<f32>;
+[[group(0), binding(9)]]
+var s_emissive: sampler;
+[[group(0), binding(10)]]
+var t_opacity: texture_2d_array<f32>;
+[[group(0), binding(11)]]
+var s_opacity: sampler;
+[[group(0), binding(12)]]
+var t_height: texture_2d_array<f32>;
+[[group(0), binding(13)]]
+var s_height: sampler;
+// See MaterialUniform in model.rs
+[[block]]
+struct Material {
+    diffuse: vec3<f32>;
+    opacity: f32;
+    specular: vec3<f32>;
+    shininess: f32;
+    emissive: vec3<f32>;
+    height_scale: f32;
+    parallax_layers: u32;
+};
+[[group(0), binding(14)]]
+var<uniform> material: Material;
+
+// Parallax occlusion mapping, marches view_dir (in tangent space) through
+// the height map and interpolates between the layers either side of where
+// it hits. See MaterialFactors::height_scale in model.rs
+fn parallax_occlusion(tex_coords: vec2<f32>, view_dir: vec3<f32>) -> vec2<f32> {
+    if (material.height_scale <= 0.0) {
+        return tex_coords;
+    }
+
+    let layer_depth = 1.0 / f32(material.parallax_layers);
+    // Clamp z so glancing angles don't shoot off across the texture
+    let delta = view_dir.xy / max(view_dir.z, 0.05) * material.height_scale * layer_depth;
+
+    // Non-uniform control flow, so no implicit derivatives in here
+    var uv: vec2<f32> = tex_coords;
+    var depth: f32 = 0.0;
+    var surface_depth: f32 = 1.0 - textureSampleLevel(t_height, s_height, uv, 0, 0.0).r;
+    loop {
+        if (depth >= surface_depth || depth >= 1.0) {
+            break;
+        }
+        uv = uv - delta;
+        depth = depth + layer_depth;
+        surface_depth = 1.0 - textureSampleLevel(t_height, s_height, uv, 0, 0.0).r;
+    }
+
+    let previous_uv = uv + delta;
+    let after = surface_depth - depth;
+    let before = 1.0 - textureSampleLevel(t_height, s_height, previous_uv, 0, 0.0).r - (depth - layer_depth);
+    let weight = after / (after - before);
+    return mix(uv, previous_uv, weight);
+}
+
+struct GBufferOutput {
+    [[location(0)]] albedo: vec4<f32>;
+    [[location(1)]] normal: vec4<f32>;
+    [[location(2)]] material: vec4<f32>;
+    [[location(3)]] emission: vec4<f32>;
+};
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> GBufferOutput {
+    // Unlike the forward path we light in world space, so take the normal
+    // map out of tangent space here
+    let world_normal = normalize(in.world_normal);
+    let world_tangent = normalize(in.world_tangent.xyz);
+    let world_bitangent = cross(world_normal, world_tangent) * in.world_tangent.w;
+    let tangent_matrix = mat3x3<f32>(
+        world_tangent,
+        world_bitangent,
+        world_normal,
+    );
+
+    // Parallax still needs the view direction in tangent space
+    let view_dir = transpose(tangent_matrix) * normalize(camera.view_pos.xyz - in.world_position);
+    let tex_coords = parallax_occlusion(in.tex_coords, view_dir);
+
+    // There's no blending into the G-buffer, so opacity is ignored here
+    let object_color = textureSample(t_diffuse, s_diffuse, tex_coords, i32(in.layer))
+        * vec4<f32>(material.diffuse, 1.0);
+    let specular = material.specular * textureSample(t_specular, s_specular, tex_coords, 0).xyz;
+    let shininess = material.shininess * textureSample(t_shininess, s_shininess, tex_coords, 0).r;
+    let emissive = material.emissive * textureSample(t_emissive, s_emissive, tex_coords, 0).xyz;
+    let object_normal: vec4<f32> = textureSample(t_normal, s_normal, tex_coords, 0);
+
+    let tangent_normal = object_normal.xyz * 2.0 - 1.0;
+
+    var out: GBufferOutput;
+    out.albedo = object_color;
+    out.normal = vec4<f32>(normalize(tangent_matrix * tangent_normal), 0.0);
+    // r: specular strength, g: shininess / 256. The specular color doesn't
+    // fit, so it gets boiled down to its brightest channel.
+    let specular_strength = max(specular.r, max(specular.g, specular.b));
+    out.material = vec4<f32>(specular_strength, shininess / 256.0, 0.0, 0.0);
+    out.emission = vec4<f32>(emissive, 0.0);
+    return out;
+}
@@ -11,29 +11,54 @@ var<uniform> camera: Camera;
 [[block]]
 struct Light {
     position: vec3<f32>;
+    radius: f32;
     color: vec3<f32>;
+    intensity: f32;
 };
 [[group(1), binding(0)]]
 var<uniform> light: Light;
 
+// See shader.wgsl, this pipeline has no materials so it comes earlier
+[[block]]
+struct Dequantize {
+    offset: vec3<f32>;
+    scale: vec3<f32>;
+};
+[[group(2), binding(0)]]
+var<uniform> dequantize: Dequantize;
+
 struct VertexInput {
     [[location(0)]] position: vec3<f32>;
 };
+struct CompactVertexInput {
+    [[location(0)]] position: vec4<f32>;
+};
 
 struct VertexOutput {
     [[builtin(position)]] clip_position: vec4<f32>;
     [[location(0)]] color: vec3<f32>;
 };
 
+fn transform_vertex(position: vec3<f32>) -> VertexOutput {
+    let scale = 0.25;
+    var out: VertexOutput;
+    out.clip_position = camera.view_proj * vec4<f32>(position * scale + light.position, 1.0);
+    out.color = light.color * light.intensity;
+    return out;
+}
+
 [[stage(vertex)]]
 fn main(
     model: VertexInput,
 ) -> VertexOutput {
-    let scale = 0.25;
-    var out: VertexOutput;
-    out.clip_position = camera.view_proj * vec4<f32>(model.position * scale + light.position, 1.0);
-    out.color = light.color;
-    return out;
+    return transform_vertex(model.position);
+}
+
+[[stage(vertex)]]
+fn main_compact(
+    model: CompactVertexInput,
+) -> VertexOutput {
+    return transform_vertex(dequantize.offset + model.position.xyz * dequantize.scale);
 }
 
 // Fragment shader
@@ -0,0 +1,29 @@
+// Fills in one mip level from the one above it, see MipmapGenerator in
+// texture.rs. The target is half the size, so a single bilinear tap lands
+// between four source texels and averages them.
+
+[[group(0), binding(0)]]
+var t_source: texture_2d<f32>;
+[[group(0), binding(1)]]
+var s_source: sampler;
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] uv: vec2<f32>;
+};
+
+[[stage(vertex)]]
+fn fullscreen([[builtin(vertex_index)]] vertex_index: u32) -> VertexOutput {
+    let x = f32(i32(vertex_index & 1u) * 4 - 1);
+    let y = f32(i32(vertex_index >> 1u) * 4 - 1);
+
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(x, y, 0.0, 1.0);
+    out.uv = vec2<f32>(x * 0.5 + 0.5, 0.5 - y * 0.5);
+    return out;
+}
+
+[[stage(fragment)]]
+fn downsample(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    return textureSample(t_source, s_source, in.uv);
+}
@@ -0,0 +1,202 @@
+// Every post effect is a fullscreen triangle that reads `t_source` and writes
+// one pixel of the next target. See post.rs for the order they run in.
+
+[[block]]
+struct PostSettings {
+    bloom_threshold: f32;
+    bloom_knee: f32;
+    bloom_intensity: f32;
+    exposure: f32;
+    lut_strength: f32;
+    vignette_intensity: f32;
+    vignette_radius: f32;
+    vignette_softness: f32;
+};
+
+[[group(0), binding(0)]]
+var t_source: texture_2d<f32>;
+[[group(0), binding(1)]]
+var s_source: sampler;
+
+[[group(1), binding(0)]]
+var<uniform> settings: PostSettings;
+
+// Only bound for the composite pass
+[[group(2), binding(0)]]
+var t_bloom: texture_2d<f32>;
+
+// Only bound for the color grading pass
+[[group(2), binding(1)]]
+var t_lut: texture_3d<f32>;
+[[group(2), binding(2)]]
+var s_lut: sampler;
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] uv: vec2<f32>;
+};
+
+[[stage(vertex)]]
+fn fullscreen([[builtin(vertex_index)]] vertex_index: u32) -> VertexOutput {
+    let x = f32(i32(vertex_index & 1u) * 4 - 1);
+    let y = f32(i32(vertex_index >> 1u) * 4 - 1);
+
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(x, y, 0.0, 1.0);
+    out.uv = vec2<f32>(x * 0.5 + 0.5, 0.5 - y * 0.5);
+    return out;
+}
+
+fn texel_size() -> vec2<f32> {
+    return 1.0 / vec2<f32>(textureDimensions(t_source));
+}
+
+fn luma(color: vec3<f32>) -> f32 {
+    // FXAA wants something close to perceptual, sqrt is a cheap gamma
+    return dot(sqrt(max(color, vec3<f32>(0.0))), vec3<f32>(0.299, 0.587, 0.114));
+}
+
+// Bloom
+
+[[stage(fragment)]]
+fn threshold(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let color = textureSample(t_source, s_source, in.uv).rgb;
+    let brightness = max(color.r, max(color.g, color.b));
+
+    // Soft knee so the cutoff doesn't pop
+    let knee = settings.bloom_knee;
+    var soft: f32 = clamp(brightness - settings.bloom_threshold + knee, 0.0, 2.0 * knee);
+    soft = soft * soft / (4.0 * knee + 0.0001);
+    let contribution = max(soft, brightness - settings.bloom_threshold) / max(brightness, 0.0001);
+
+    return vec4<f32>(color * contribution, 1.0);
+}
+
+[[stage(fragment)]]
+fn downsample(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    // Four bilinear taps between the source texels average a 4x4 block
+    let texel = texel_size();
+    var color: vec3<f32> = textureSample(t_source, s_source, in.uv + texel * vec2<f32>(-1.0, -1.0)).rgb;
+    color = color + textureSample(t_source, s_source, in.uv + texel * vec2<f32>(1.0, -1.0)).rgb;
+    color = color + textureSample(t_source, s_source, in.uv + texel * vec2<f32>(-1.0, 1.0)).rgb;
+    color = color + textureSample(t_source, s_source, in.uv + texel * vec2<f32>(1.0, 1.0)).rgb;
+    return vec4<f32>(color * 0.25, 1.0);
+}
+
+[[stage(fragment)]]
+fn upsample(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    // 3x3 tent filter, the pipeline blends this additively onto the next
+    // larger mip
+    let texel = texel_size();
+    var color: vec3<f32> = textureSample(t_source, s_source, in.uv).rgb * 4.0;
+    color = color + textureSample(t_source, s_source, in.uv + texel * vec2<f32>(-1.0, 0.0)).rgb * 2.0;
+    color = color + textureSample(t_source, s_source, in.uv + texel * vec2<f32>(1.0, 0.0)).rgb * 2.0;
+    color = color + textureSample(t_source, s_source, in.uv + texel * vec2<f32>(0.0, -1.0)).rgb * 2.0;
+    color = color + textureSample(t_source, s_source, in.uv + texel * vec2<f32>(0.0, 1.0)).rgb * 2.0;
+    color = color + textureSample(t_source, s_source, in.uv + texel * vec2<f32>(-1.0, -1.0)).rgb;
+    color = color + textureSample(t_source, s_source, in.uv + texel * vec2<f32>(1.0, -1.0)).rgb;
+    color = color + textureSample(t_source, s_source, in.uv + texel * vec2<f32>(-1.0, 1.0)).rgb;
+    color = color + textureSample(t_source, s_source, in.uv + texel * vec2<f32>(1.0, 1.0)).rgb;
+    return vec4<f32>(color / 16.0, 1.0);
+}
+
+// Composite
+
+// Narkowicz's fit of the ACES filmic curve
+fn aces(x: vec3<f32>) -> vec3<f32> {
+    let a = 2.51;
+    let b = 0.03;
+    let c = 2.43;
+    let d = 0.59;
+    let e = 0.14;
+    return clamp((x * (a * x + b)) / (x * (c * x + d) + e), vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+[[stage(fragment)]]
+fn composite(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let scene = textureSample(t_source, s_source, in.uv);
+    let bloom = textureSample(t_bloom, s_source, in.uv).rgb;
+
+    let hdr = (scene.rgb + bloom * settings.bloom_intensity) * settings.exposure;
+    return vec4<f32>(aces(hdr), scene.a);
+}
+
+// Color grading
+
+[[stage(fragment)]]
+fn grade(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let color = textureSample(t_source, s_source, in.uv);
+
+    // Sample at texel centers so 0 and 1 land on the first and last entry
+    let size = f32(textureDimensions(t_lut).x);
+    let uvw = color.rgb * ((size - 1.0) / size) + 0.5 / size;
+    let graded = textureSample(t_lut, s_lut, uvw).rgb;
+
+    return vec4<f32>(mix(color.rgb, graded, settings.lut_strength), color.a);
+}
+
+// FXAA
+
+[[stage(fragment)]]
+fn fxaa(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let reduce_min = 1.0 / 128.0;
+    let reduce_mul = 1.0 / 8.0;
+    let span_max = 8.0;
+
+    let texel = texel_size();
+    let center = textureSample(t_source, s_source, in.uv);
+
+    let luma_nw = luma(textureSample(t_source, s_source, in.uv + texel * vec2<f32>(-1.0, -1.0)).rgb);
+    let luma_ne = luma(textureSample(t_source, s_source, in.uv + texel * vec2<f32>(1.0, -1.0)).rgb);
+    let luma_sw = luma(textureSample(t_source, s_source, in.uv + texel * vec2<f32>(-1.0, 1.0)).rgb);
+    let luma_se = luma(textureSample(t_source, s_source, in.uv + texel * vec2<f32>(1.0, 1.0)).rgb);
+    let luma_m = luma(center.rgb);
+
+    let luma_min = min(luma_m, min(min(luma_nw, luma_ne), min(luma_sw, luma_se)));
+    let luma_max = max(luma_m, max(max(luma_nw, luma_ne), max(luma_sw, luma_se)));
+
+    // Blur along the edge, perpendicular to the luma gradient
+    var dir: vec2<f32> = vec2<f32>(
+        -((luma_nw + luma_ne) - (luma_sw + luma_se)),
+        (luma_nw + luma_sw) - (luma_ne + luma_se),
+    );
+    let dir_reduce = max((luma_nw + luma_ne + luma_sw + luma_se) * (0.25 * reduce_mul), reduce_min);
+    let rcp_dir_min = 1.0 / (min(abs(dir.x), abs(dir.y)) + dir_reduce);
+    dir = clamp(dir * rcp_dir_min, vec2<f32>(-span_max), vec2<f32>(span_max)) * texel;
+
+    let rgb_a = 0.5 * (
+        textureSample(t_source, s_source, in.uv + dir * (1.0 / 3.0 - 0.5)).rgb +
+        textureSample(t_source, s_source, in.uv + dir * (2.0 / 3.0 - 0.5)).rgb
+    );
+    let rgb_b = rgb_a * 0.5 + 0.25 * (
+        textureSample(t_source, s_source, in.uv + dir * -0.5).rgb +
+        textureSample(t_source, s_source, in.uv + dir * 0.5).rgb
+    );
+
+    let luma_b = luma(rgb_b);
+    if (luma_b < luma_min || luma_b > luma_max) {
+        return vec4<f32>(rgb_a, center.a);
+    }
+    return vec4<f32>(rgb_b, center.a);
+}
+
+// Vignette
+
+[[stage(fragment)]]
+fn vignette(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let color = textureSample(t_source, s_source, in.uv);
+
+    let distance = length(in.uv - vec2<f32>(0.5));
+    let inner = settings.vignette_radius - settings.vignette_softness;
+    let t = clamp((distance - inner) / max(settings.vignette_softness, 0.0001), 0.0, 1.0);
+    let falloff = t * t * (3.0 - 2.0 * t);
+
+    return vec4<f32>(color.rgb * (1.0 - falloff * settings.vignette_intensity), color.a);
+}
+
+// Copies the final image into the swapchain
+
+[[stage(fragment)]]
+fn blit(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    return textureSample(t_source, s_source, in.uv);
+}
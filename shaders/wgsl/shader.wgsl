@@ -8,29 +8,94 @@ struct Camera {
 [[group(1), binding(0)]]
 var<uniform> camera: Camera;
 
+[[block]]
+struct DebugView {
+    view: u32;
+};
+[[group(1), binding(1)]]
+var<uniform> debug_view: DebugView;
+
+// White when SSAO is turned off
+[[group(1), binding(2)]]
+var t_ao: texture_2d<f32>;
+
+[[block]]
+struct Fog {
+    color: vec3<f32>;
+    mode: u32;
+    start: f32;
+    end: f32;
+    density: f32;
+    height: f32;
+    height_density: f32;
+    height_falloff: f32;
+};
+[[group(1), binding(3)]]
+var<uniform> fog: Fog;
+
 [[block]]
 struct Light {
     position: vec3<f32>;
+    radius: f32;
     color: vec3<f32>;
+    intensity: f32;
 };
 [[group(2), binding(0)]]
 var<uniform> light: Light;
 
+// Only used by the compact vertex layouts, takes their positions back to
+// model space. Ones that aren't quantized get an offset of zero and a
+// scale of one.
+[[block]]
+struct Dequantize {
+    offset: vec3<f32>;
+    scale: vec3<f32>;
+};
+[[group(3), binding(0)]]
+var<uniform> dequantize: Dequantize;
+
+// Only used by skinned meshes, where each joint has moved the mesh from
+// how it was bound. Binding 1 so it doesn't clash with Dequantize, the two
+// are never used together.
+[[block]]
+struct Joints {
+    matrices: [[stride(64)]] array<mat4x4<f32>>;
+};
+[[group(3), binding(1)]]
+var<storage, read> joints: Joints;
+
 struct VertexInput {
     [[location(0)]] position: vec3<f32>;
     [[location(1)]] tex_coords: vec2<f32>;
     [[location(2)]] normal: vec3<f32>;
-    [[location(3)]] tangent: vec3<f32>;
-    [[location(4)]] bitangent: vec3<f32>;
+    // w is the handedness of the bitangent
+    [[location(3)]] tangent: vec4<f32>;
+    // Which layer of the diffuse texture array to sample
+    [[location(13)]] layer: u32;
 };
 struct InstanceInput {
-    [[location(5)]] model_matrix_0: vec4<f32>;
-    [[location(6)]] model_matrix_1: vec4<f32>;
-    [[location(7)]] model_matrix_2: vec4<f32>;
-    [[location(8)]] model_matrix_3: vec4<f32>;
-    [[location(9)]] normal_matrix_0: vec3<f32>;
-    [[location(10)]] normal_matrix_1: vec3<f32>;
-    [[location(11)]] normal_matrix_2: vec3<f32>;
+    [[location(4)]] model_matrix_0: vec4<f32>;
+    [[location(5)]] model_matrix_1: vec4<f32>;
+    [[location(6)]] model_matrix_2: vec4<f32>;
+    [[location(7)]] model_matrix_3: vec4<f32>;
+    [[location(8)]] normal_matrix_0: vec3<f32>;
+    [[location(9)]] normal_matrix_1: vec3<f32>;
+    [[location(10)]] normal_matrix_2: vec3<f32>;
+};
+
+struct SkinInput {
+    [[location(11)]] joints: vec4<u32>;
+    [[location(12)]] weights: vec4<f32>;
+};
+
+struct CompactVertexInput {
+    // w is the handedness of the bitangent, 0 for -1 and 1 for 1
+    [[location(0)]] position: vec4<f32>;
+    [[location(1)]] tex_coords: vec2<f32>;
+    // Both octahedral encoded
+    [[location(2)]] normal: vec2<f32>;
+    [[location(3)]] tangent: vec2<f32>;
+    [[location(13)]] layer: u32;
 };
 
 struct VertexOutput {
@@ -39,13 +104,68 @@ struct VertexOutput {
     [[location(1)]] tangent_position: vec3<f32>;
     [[location(2)]] tangent_light_position: vec3<f32>;
     [[location(3)]] tangent_view_position: vec3<f32>;
+    [[location(4)]] world_position: vec3<f32>;
+    [[location(5), interpolate(flat)]] layer: u32;
 };
 
-[[stage(vertex)]]
-fn main(
-    model: VertexInput,
-    instance: InstanceInput,
-) -> VertexOutput {
+// Unfolds a unit vector from the octahedron it was flattened onto.
+fn decode_octahedral(e: vec2<f32>) -> vec3<f32> {
+    let z = 1.0 - abs(e.x) - abs(e.y);
+    // The lower half was folded over the diagonals
+    let fold = max(-z, 0.0);
+    let xy = e + select(vec2<f32>(fold, fold), vec2<f32>(-fold, -fold), e >= vec2<f32>(0.0, 0.0));
+    return normalize(vec3<f32>(xy, z));
+}
+
+fn decode_vertex(model: CompactVertexInput) -> VertexInput {
+    let handedness = select(-1.0, 1.0, model.position.w > 0.5);
+    return VertexInput(
+        dequantize.offset + model.position.xyz * dequantize.scale,
+        model.tex_coords,
+        decode_octahedral(model.normal),
+        vec4<f32>(decode_octahedral(model.tangent), handedness),
+        model.layer,
+    );
+}
+
+// Blends where each of the vertex's joints takes `p`, which is a direction
+// when w is zero.
+fn skin_point(p: vec4<f32>, skin: SkinInput) -> vec4<f32> {
+    return joints.matrices[skin.joints.x] * p * skin.weights.x
+        + joints.matrices[skin.joints.y] * p * skin.weights.y
+        + joints.matrices[skin.joints.z] * p * skin.weights.z
+        + joints.matrices[skin.joints.w] * p * skin.weights.w;
+}
+
+// Normals need the inverse transpose of the blended matrix to stay at right
+// angles to the surface when a joint scales unevenly. The cofactor matrix is
+// that times the determinant, which normalizing cancels out but for its sign.
+fn skin_normal(normal: vec3<f32>, skin: SkinInput) -> vec3<f32> {
+    let m0 = joints.matrices[skin.joints.x];
+    let m1 = joints.matrices[skin.joints.y];
+    let m2 = joints.matrices[skin.joints.z];
+    let m3 = joints.matrices[skin.joints.w];
+    let w = skin.weights;
+    let x = (m0[0] * w.x + m1[0] * w.y + m2[0] * w.z + m3[0] * w.w).xyz;
+    let y = (m0[1] * w.x + m1[1] * w.y + m2[1] * w.z + m3[1] * w.w).xyz;
+    let z = (m0[2] * w.x + m1[2] * w.y + m2[2] * w.z + m3[2] * w.w).xyz;
+    let cofactor = mat3x3<f32>(cross(y, z), cross(z, x), cross(x, y));
+    let handedness = select(1.0, -1.0, dot(x, cross(y, z)) < 0.0);
+    return normalize(cofactor * normal) * handedness;
+}
+
+fn skin_vertex(model: VertexInput, skin: SkinInput) -> VertexInput {
+    let tangent = skin_point(vec4<f32>(model.tangent.xyz, 0.0), skin).xyz;
+    return VertexInput(
+        skin_point(vec4<f32>(model.position, 1.0), skin).xyz,
+        model.tex_coords,
+        skin_normal(model.normal, skin),
+        vec4<f32>(normalize(tangent), model.tangent.w),
+        model.layer,
+    );
+}
+
+fn transform_vertex(model: VertexInput, instance: InstanceInput) -> VertexOutput {
     let model_matrix = mat4x4<f32>(
         instance.model_matrix_0,
         instance.model_matrix_1,
@@ -60,8 +180,8 @@ fn main(
 
     // Construct the tangent matrix
     let world_normal = normalize(normal_matrix * model.normal);
-    let world_tangent = normalize(normal_matrix * model.tangent);
-    let world_bitangent = normalize(normal_matrix * model.bitangent);
+    let world_tangent = normalize(normal_matrix * model.tangent.xyz);
+    let world_bitangent = cross(world_normal, world_tangent) * model.tangent.w;
     let tangent_matrix = transpose(mat3x3<f32>(
         world_tangent,
         world_bitangent,
@@ -73,45 +193,193 @@ fn main(
     var out: VertexOutput;
     out.clip_position = camera.view_proj * world_position;
     out.tex_coords = model.tex_coords;
+    out.layer = model.layer;
     out.tangent_position = tangent_matrix * world_position.xyz;
     out.tangent_view_position = tangent_matrix * camera.view_pos.xyz;
     out.tangent_light_position = tangent_matrix * light.position;
+    out.world_position = world_position.xyz;
     return out;
 }
 
+[[stage(vertex)]]
+fn main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    return transform_vertex(model, instance);
+}
+
+[[stage(vertex)]]
+fn main_compact(
+    model: CompactVertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    return transform_vertex(decode_vertex(model), instance);
+}
+
+[[stage(vertex)]]
+fn main_skinned(
+    model: VertexInput,
+    instance: InstanceInput,
+    skin: SkinInput,
+) -> VertexOutput {
+    return transform_vertex(skin_vertex(model, skin), instance);
+}
+
 // Fragment shader
 
+// See FogSettings in fog.rs
+fn apply_fog(color: vec3<f32>, world_position: vec3<f32>) -> vec3<f32> {
+    let to_camera = camera.view_pos.xyz - world_position;
+    let distance = length(to_camera);
+
+    var amount: f32 = 0.0;
+    if (fog.mode == 1u) {
+        amount = clamp((distance - fog.start) / (fog.end - fog.start), 0.0, 1.0);
+    }
+    if (fog.mode == 2u) {
+        amount = 1.0 - exp(-fog.density * distance);
+    }
+    if (fog.mode == 3u) {
+        let d = fog.density * distance;
+        amount = 1.0 - exp(-d * d);
+    }
+
+    // Density falls off exponentially with height, integrated along the ray
+    // from the camera to the fragment
+    if (fog.height_density > 0.0) {
+        let camera_density = exp(-fog.height_falloff * (camera.view_pos.y - fog.height));
+        let rise = -to_camera.y * fog.height_falloff;
+        var along_ray: f32 = 1.0;
+        if (abs(rise) > 0.0001) {
+            along_ray = (1.0 - exp(-rise)) / rise;
+        }
+        let height_amount = 1.0 - exp(-fog.height_density * camera_density * along_ray * distance);
+        amount = 1.0 - (1.0 - amount) * (1.0 - clamp(height_amount, 0.0, 1.0));
+    }
+
+    return mix(color, fog.color, amount);
+}
+
+// All arrays, so materials that only differ by their diffuse texture can be
+// merged into one with a layer each. The rest only ever have the one layer.
 [[group(0), binding(0)]]
-var t_diffuse: texture_2d<f32>;
+var t_diffuse: texture_2d_array<f32>;
 [[group(0), binding(1)]]
 var s_diffuse: sampler;
 [[group(0), binding(2)]]
-var t_normal: texture_2d<f32>;
+var t_normal: texture_2d_array<f32>;
 [[group(0), binding(3)]]
 var s_normal: sampler;
+[[group(0), binding(4)]]
+var t_specular: texture_2d_array<f32>;
+[[group(0), binding(5)]]
+var s_specular: sampler;
+[[group(0), binding(6)]]
+var t_shininess: texture_2d_array<f32>;
+[[group(0), binding(7)]]
+var s_shininess: sampler;
+[[group(0), binding(8)]]
+var t_emissive: texture_2d_array<f32>;
+[[group(0), binding(9)]]
+var s_emissive: sampler;
+[[group(0), binding(10)]]
+var t_opacity: texture_2d_array<f32>;
+[[group(0), binding(11)]]
+var s_opacity: sampler;
+[[group(0), binding(12)]]
+var t_height: texture_2d_array<f32>;
+[[group(0), binding(13)]]
+var s_height: sampler;
+// See MaterialUniform in model.rs
+[[block]]
+struct Material {
+    diffuse: vec3<f32>;
+    opacity: f32;
+    specular: vec3<f32>;
+    shininess: f32;
+    emissive: vec3<f32>;
+    height_scale: f32;
+    parallax_layers: u32;
+};
+[[group(0), binding(14)]]
+var<uniform> material: Material;
+
+// Parallax occlusion mapping, marches view_dir (in tangent space) through
+// the height map and interpolates between the layers either side of where
+// it hits. See MaterialFactors::height_scale in model.rs
+fn parallax_occlusion(tex_coords: vec2<f32>, view_dir: vec3<f32>) -> vec2<f32> {
+    if (material.height_scale <= 0.0) {
+        return tex_coords;
+    }
+
+    let layer_depth = 1.0 / f32(material.parallax_layers);
+    // Clamp z so glancing angles don't shoot off across the texture
+    let delta = view_dir.xy / max(view_dir.z, 0.05) * material.height_scale * layer_depth;
+
+    // Non-uniform control flow, so no implicit derivatives in here
+    var uv: vec2<f32> = tex_coords;
+    var depth: f32 = 0.0;
+    var surface_depth: f32 = 1.0 - textureSampleLevel(t_height, s_height, uv, 0, 0.0).r;
+    loop {
+        if (depth >= surface_depth || depth >= 1.0) {
+            break;
+        }
+        uv = uv - delta;
+        depth = depth + layer_depth;
+        surface_depth = 1.0 - textureSampleLevel(t_height, s_height, uv, 0, 0.0).r;
+    }
+
+    let previous_uv = uv + delta;
+    let after = surface_depth - depth;
+    let before = 1.0 - textureSampleLevel(t_height, s_height, previous_uv, 0, 0.0).r - (depth - layer_depth);
+    let weight = after / (after - before);
+    return mix(uv, previous_uv, weight);
+}
 
 [[stage(fragment)]]
 fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
-    let object_color: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.tex_coords);
-    let object_normal: vec4<f32> = textureSample(t_normal, s_normal, in.tex_coords);
+    let view_dir = normalize(in.tangent_view_position - in.tangent_position);
+    let tex_coords = parallax_occlusion(in.tex_coords, view_dir);
+
+    let opacity = material.opacity * textureSample(t_opacity, s_opacity, tex_coords, 0).r;
+    let object_color = textureSample(t_diffuse, s_diffuse, tex_coords, i32(in.layer))
+        * vec4<f32>(material.diffuse, opacity);
+    let specular = material.specular * textureSample(t_specular, s_specular, tex_coords, 0).xyz;
+    let shininess = max(material.shininess * textureSample(t_shininess, s_shininess, tex_coords, 0).r, 1.0);
+    let emissive = material.emissive * textureSample(t_emissive, s_emissive, tex_coords, 0).xyz;
+    let object_normal: vec4<f32> = textureSample(t_normal, s_normal, tex_coords, 0);
     
     // We don't need (or want) much ambient light, so 0.1 is fine
     let ambient_strength = 0.1;
-    let ambient_color = light.color * ambient_strength;
+    let ambient_occlusion = textureLoad(t_ao, vec2<i32>(in.clip_position.xy), 0).r;
+    let light_color = light.color * light.intensity;
+    let ambient_color = light_color * ambient_strength * ambient_occlusion;
 
     // Create the lighting vectors
     let tangent_normal = object_normal.xyz * 2.0 - 1.0;
     let light_dir = normalize(in.tangent_light_position - in.tangent_position);
-    let view_dir = normalize(in.tangent_view_position - in.tangent_position);
     let half_dir = normalize(view_dir + light_dir);
 
     let diffuse_strength = max(dot(tangent_normal, light_dir), 0.0);
-    let diffuse_color = light.color * diffuse_strength;
+    let diffuse_color = light_color * diffuse_strength;
+
+    let specular_strength = pow(max(dot(tangent_normal, half_dir), 0.0), shininess);
+    let specular_color = specular_strength * specular * light_color;
 
-    let specular_strength = pow(max(dot(tangent_normal, half_dir), 0.0), 32.0);
-    let specular_color = specular_strength * light.color;
+    let lighting = ambient_color + diffuse_color + specular_color;
+    let result = lighting * object_color.xyz + emissive;
 
-    let result = (ambient_color + diffuse_color + specular_color) * object_color.xyz;
+    // See DebugView in render.rs
+    if (debug_view.view == 1u) {
+        return vec4<f32>(object_color.xyz, 1.0);
+    }
+    if (debug_view.view == 2u) {
+        return vec4<f32>(tangent_normal * 0.5 + 0.5, 1.0);
+    }
+    if (debug_view.view == 3u) {
+        return vec4<f32>(lighting, 1.0);
+    }
 
-    return vec4<f32>(result, object_color.a);
+    return vec4<f32>(apply_fog(result, in.world_position), object_color.a);
 }
\ No newline at end of file
@@ -0,0 +1,151 @@
+[[block]]
+struct Ssao {
+    proj: mat4x4<f32>;
+    inv_proj: mat4x4<f32>;
+    kernel: [[stride(16)]] array<vec4<f32>, 32>;
+    sample_count: u32;
+    radius: f32;
+    bias: f32;
+    intensity: f32;
+};
+[[group(0), binding(0)]]
+var<uniform> ssao: Ssao;
+[[group(0), binding(1)]]
+var t_depth: texture_depth_2d;
+[[group(0), binding(2)]]
+var t_noise: texture_2d<f32>;
+
+// Only bound for the blur pass
+[[group(1), binding(0)]]
+var t_occlusion: texture_2d<f32>;
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+};
+
+[[stage(vertex)]]
+fn fullscreen([[builtin(vertex_index)]] vertex_index: u32) -> VertexOutput {
+    let x = f32(i32(vertex_index & 1u) * 4 - 1);
+    let y = f32(i32(vertex_index >> 1u) * 4 - 1);
+
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(x, y, 0.0, 1.0);
+    return out;
+}
+
+fn depth_size() -> vec2<i32> {
+    return textureDimensions(t_depth);
+}
+
+// Rebuilds the view space position of a pixel from the depth buffer
+fn view_position(coords: vec2<i32>) -> vec3<f32> {
+    let clamped = clamp(coords, vec2<i32>(0, 0), depth_size() - vec2<i32>(1, 1));
+    let depth = textureLoad(t_depth, clamped, 0);
+    let uv = (vec2<f32>(clamped) + 0.5) / vec2<f32>(depth_size());
+    let ndc = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth, 1.0);
+    let view = ssao.inv_proj * ndc;
+    return view.xyz / view.w;
+}
+
+// Picks the neighbor on each axis that's closest in depth so normals don't
+// bleed across silhouettes
+fn view_normal(coords: vec2<i32>, center: vec3<f32>) -> vec3<f32> {
+    let left = view_position(coords + vec2<i32>(-1, 0));
+    let right = view_position(coords + vec2<i32>(1, 0));
+    let up = view_position(coords + vec2<i32>(0, -1));
+    let down = view_position(coords + vec2<i32>(0, 1));
+
+    var dx: vec3<f32> = right - center;
+    if (abs(center.z - left.z) < abs(right.z - center.z)) {
+        dx = center - left;
+    }
+    var dy: vec3<f32> = down - center;
+    if (abs(center.z - up.z) < abs(down.z - center.z)) {
+        dy = center - up;
+    }
+
+    // dy points down the screen, so this faces back towards the camera
+    return normalize(cross(dy, dx));
+}
+
+[[stage(fragment)]]
+fn occlusion(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let coords = vec2<i32>(in.clip_position.xy);
+    if (textureLoad(t_depth, coords, 0) >= 1.0) {
+        return vec4<f32>(1.0);
+    }
+
+    let position = view_position(coords);
+    let normal = view_normal(coords, position);
+
+    // Rotate the kernel around the normal by a tiling noise vector, the blur
+    // pass hides the pattern
+    let random = textureLoad(t_noise, coords % vec2<i32>(4, 4), 0).xyz;
+    let tangent = normalize(random - normal * dot(random, normal));
+    let bitangent = cross(normal, tangent);
+    let tbn = mat3x3<f32>(tangent, bitangent, normal);
+
+    var occluded: f32 = 0.0;
+    var i: u32 = 0u;
+    loop {
+        if (i >= ssao.sample_count) {
+            break;
+        }
+
+        let sample_position = position + (tbn * ssao.kernel[i].xyz) * ssao.radius;
+
+        let offset = ssao.proj * vec4<f32>(sample_position, 1.0);
+        let offset_ndc = offset.xy / offset.w;
+        let sample_uv = vec2<f32>(offset_ndc.x * 0.5 + 0.5, 0.5 - offset_ndc.y * 0.5);
+        let sample_coords = vec2<i32>(sample_uv * vec2<f32>(depth_size()));
+
+        let scene_depth = view_position(sample_coords).z;
+
+        // Ignore occluders that are much further away than the radius
+        let range = clamp(ssao.radius / abs(position.z - scene_depth), 0.0, 1.0);
+        let range_check = range * range * (3.0 - 2.0 * range);
+        if (scene_depth >= sample_position.z + ssao.bias) {
+            occluded = occluded + range_check;
+        }
+
+        continuing {
+            i = i + 1u;
+        }
+    }
+
+    let ao = 1.0 - occluded / f32(max(ssao.sample_count, 1u));
+    return vec4<f32>(pow(ao, ssao.intensity));
+}
+
+[[stage(fragment)]]
+fn blur(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    // Box blur over the same 4x4 area the noise texture tiles over
+    let coords = vec2<i32>(in.clip_position.xy);
+    let size = textureDimensions(t_occlusion) - vec2<i32>(1, 1);
+
+    var result: f32 = 0.0;
+    var y: i32 = -2;
+    loop {
+        if (y >= 2) {
+            break;
+        }
+        var x: i32 = -2;
+        loop {
+            if (x >= 2) {
+                break;
+            }
+            let offset = clamp(coords + vec2<i32>(x, y), vec2<i32>(0, 0), size);
+            result = result + textureLoad(t_occlusion, offset, 0).r;
+
+            continuing {
+                x = x + 1;
+            }
+        }
+
+        continuing {
+            y = y + 1;
+        }
+    }
+
+    return vec4<f32>(result / 16.0);
+}
@@ -0,0 +1,509 @@
+use std::time::Duration;
+
+use anyhow::bail;
+use glam::{Mat4, Quat, Vec3};
+
+/// How a value gets from one keyframe to the next.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Interpolation {
+    /// Holds each keyframe until the next one.
+    Step,
+    /// Lerps vectors and slerps rotations.
+    Linear,
+}
+
+/// Anything keyframes can blend between.
+pub trait Interpolate: Copy {
+    fn interpolate(self, other: Self, t: f32) -> Self;
+}
+
+impl Interpolate for f32 {
+    fn interpolate(self, other: Self, t: f32) -> Self {
+        self + (other - self) * t
+    }
+}
+
+impl Interpolate for Vec3 {
+    fn interpolate(self, other: Self, t: f32) -> Self {
+        self.lerp(other, t)
+    }
+}
+
+impl Interpolate for Quat {
+    fn interpolate(self, other: Self, t: f32) -> Self {
+        // q and -q are the same rotation, heading for whichever is closer
+        // takes the short way round
+        let other = if self.dot(other) < 0.0 { -other } else { other };
+        self.slerp(other, t).normalize()
+    }
+}
+
+/// A value over time, given at a handful of points and interpolated in
+/// between. Before the first keyframe and after the last it holds still.
+#[derive(Debug, Clone)]
+pub struct Keyframes<T> {
+    /// In seconds, in order.
+    times: Vec<f32>,
+    values: Vec<T>,
+    interpolation: Interpolation,
+}
+
+impl<T: Interpolate> Keyframes<T> {
+    pub fn new(
+        times: Vec<f32>,
+        values: Vec<T>,
+        interpolation: Interpolation,
+    ) -> anyhow::Result<Self> {
+        if times.is_empty() {
+            bail!("No keyframes");
+        }
+        if times.len() != values.len() {
+            bail!("{} keyframe times for {} values", times.len(), values.len());
+        }
+        if times.windows(2).any(|pair| pair[1] < pair[0]) {
+            bail!("Keyframe times go backwards");
+        }
+
+        Ok(Self {
+            times,
+            values,
+            interpolation,
+        })
+    }
+
+    /// When the last keyframe is.
+    pub fn duration(&self) -> f32 {
+        self.times[self.times.len() - 1]
+    }
+
+    pub fn sample(&self, time: f32) -> T {
+        // The first keyframe after `time`
+        let next = self.times.partition_point(|&t| t <= time);
+        if next == 0 {
+            return self.values[0];
+        }
+        if next == self.times.len() {
+            return self.values[next - 1];
+        }
+
+        let (start, end) = (self.times[next - 1], self.times[next]);
+        let (from, to) = (self.values[next - 1], self.values[next]);
+        match self.interpolation {
+            Interpolation::Step => from,
+            Interpolation::Linear => from.interpolate(to, (time - start) / (end - start)),
+        }
+    }
+}
+
+/// A translation, rotation and scale, applied in reverse order. Kept apart
+/// rather than as a matrix so each part can be animated on its own.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Transform {
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+}
+
+impl Transform {
+    pub const IDENTITY: Self = Self {
+        translation: Vec3::ZERO,
+        rotation: Quat::IDENTITY,
+        scale: Vec3::ONE,
+    };
+
+    pub fn to_matrix(&self) -> Mat4 {
+        Mat4::from_scale_rotation_translation(self.scale, self.rotation, self.translation)
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+/// Something in the scene a [`Track`] can move. Each property defaults to
+/// doing nothing, so tracks aimed at a property the target doesn't have,
+/// like the scale of a light, are ignored.
+pub trait Animatable {
+    fn set_position(&mut self, _position: Vec3) {}
+    fn set_rotation(&mut self, _rotation: Quat) {}
+    fn set_scale(&mut self, _scale: Vec3) {}
+    fn set_color(&mut self, _color: Vec3) {}
+    fn set_intensity(&mut self, _intensity: f32) {}
+}
+
+/// What a track animates.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// Index into the instance grid.
+    Instance(usize),
+    /// 0 is the main light, the deferred path's point lights follow it.
+    Light(usize),
+    Camera,
+}
+
+/// Which property of its target a track drives, and the keyframes for it.
+#[derive(Debug, Clone)]
+pub enum Property {
+    Position(Keyframes<Vec3>),
+    Rotation(Keyframes<Quat>),
+    Scale(Keyframes<Vec3>),
+    Color(Keyframes<Vec3>),
+    Intensity(Keyframes<f32>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Track {
+    pub target: Target,
+    pub property: Property,
+}
+
+/// A set of tracks played together.
+#[derive(Debug, Clone)]
+pub struct AnimationClip {
+    pub name: String,
+    tracks: Vec<Track>,
+    /// In seconds, when the last track ends.
+    duration: f32,
+}
+
+/// What happens when playback reaches the end of a clip.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LoopMode {
+    /// Stops on the last frame.
+    Once,
+    /// Jumps back to the start.
+    Loop,
+    /// Plays backwards to the start, then forwards again.
+    PingPong,
+}
+
+/// Plays a clip, keeping track of where it's up to.
+#[derive(Debug, Clone)]
+pub struct AnimationPlayer {
+    clip: AnimationClip,
+    pub mode: LoopMode,
+    /// Multiplies the time passed to `advance`, negative plays backwards.
+    pub speed: f32,
+    playing: bool,
+    /// Seconds of playback, wrapped back once per loop so it doesn't lose
+    /// precision. Runs up to twice the clip's duration for ping-pong.
+    elapsed: f32,
+}
+
+impl Property {
+    pub fn duration(&self) -> f32 {
+        match self {
+            Property::Position(keyframes)
+            | Property::Scale(keyframes)
+            | Property::Color(keyframes) => keyframes.duration(),
+            Property::Rotation(keyframes) => keyframes.duration(),
+            Property::Intensity(keyframes) => keyframes.duration(),
+        }
+    }
+}
+
+impl Track {
+    pub fn new(target: Target, property: Property) -> Self {
+        Self { target, property }
+    }
+
+    /// Sets the property on `target` to its value at `time` seconds in.
+    pub fn apply(&self, time: f32, target: &mut dyn Animatable) {
+        match &self.property {
+            Property::Position(keyframes) => target.set_position(keyframes.sample(time)),
+            Property::Rotation(keyframes) => target.set_rotation(keyframes.sample(time)),
+            Property::Scale(keyframes) => target.set_scale(keyframes.sample(time)),
+            Property::Color(keyframes) => target.set_color(keyframes.sample(time)),
+            Property::Intensity(keyframes) => target.set_intensity(keyframes.sample(time)),
+        }
+    }
+}
+
+impl AnimationClip {
+    pub fn new(name: impl Into<String>, tracks: Vec<Track>) -> Self {
+        let duration = tracks
+            .iter()
+            .map(|track| track.property.duration())
+            .fold(0.0, f32::max);
+
+        Self {
+            name: name.into(),
+            tracks,
+            duration,
+        }
+    }
+
+    pub fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.duration
+    }
+}
+
+impl AnimationPlayer {
+    /// Starts playing `clip` from the beginning at normal speed.
+    pub fn new(clip: AnimationClip, mode: LoopMode) -> Self {
+        Self {
+            clip,
+            mode,
+            speed: 1.0,
+            playing: true,
+            elapsed: 0.0,
+        }
+    }
+
+    pub fn clip(&self) -> &AnimationClip {
+        &self.clip
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// Carries on from wherever playback was paused. A clip played once
+    /// that has finished starts over.
+    pub fn play(&mut self) {
+        if self.mode == LoopMode::Once && self.finished() {
+            self.rewind();
+        }
+        self.playing = true;
+    }
+
+    pub fn pause(&mut self) {
+        self.playing = false;
+    }
+
+    /// Pauses and goes back to the start.
+    pub fn stop(&mut self) {
+        self.playing = false;
+        self.rewind();
+    }
+
+    /// Jumps to `time` seconds into playback. For ping-pong, times past the
+    /// clip's duration are on the way back.
+    pub fn seek(&mut self, time: f32) {
+        self.elapsed = time;
+        self.wrap();
+    }
+
+    /// Moves playback on by `dt`, if it's playing.
+    pub fn advance(&mut self, dt: Duration) {
+        if !self.playing {
+            return;
+        }
+
+        self.elapsed += dt.as_secs_f32() * self.speed;
+        if self.mode == LoopMode::Once && self.finished() {
+            self.playing = false;
+        }
+        self.wrap();
+    }
+
+    /// Where playback is up to in the clip, in seconds from its start.
+    pub fn time(&self) -> f32 {
+        let duration = self.clip.duration;
+        match self.mode {
+            // Switching modes can leave a ping-pong's way back behind
+            LoopMode::Once | LoopMode::Loop => self.elapsed.min(duration),
+            LoopMode::PingPong if self.elapsed > duration => 2.0 * duration - self.elapsed,
+            LoopMode::PingPong => self.elapsed,
+        }
+    }
+
+    fn rewind(&mut self) {
+        self.elapsed = if self.speed < 0.0 {
+            self.clip.duration
+        } else {
+            0.0
+        };
+    }
+
+    /// Whether a clip played once has run off either end.
+    fn finished(&self) -> bool {
+        if self.speed < 0.0 {
+            self.elapsed <= 0.0
+        } else {
+            self.elapsed >= self.clip.duration
+        }
+    }
+
+    /// Brings `elapsed` back within one loop of the clip.
+    fn wrap(&mut self) {
+        let duration = self.clip.duration;
+        if duration <= 0.0 {
+            self.elapsed = 0.0;
+            return;
+        }
+
+        self.elapsed = match self.mode {
+            LoopMode::Once => self.elapsed.clamp(0.0, duration),
+            LoopMode::Loop => self.elapsed.rem_euclid(duration),
+            LoopMode::PingPong => self.elapsed.rem_euclid(2.0 * duration),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyframes(times: &[f32], values: &[f32], interpolation: Interpolation) -> Keyframes<f32> {
+        Keyframes::new(times.to_vec(), values.to_vec(), interpolation).unwrap()
+    }
+
+    /// Two seconds long.
+    fn player(mode: LoopMode) -> AnimationPlayer {
+        let keyframes = keyframes(&[0.0, 2.0], &[0.0, 1.0], Interpolation::Linear);
+        let clip = AnimationClip::new(
+            "test",
+            vec![Track::new(Target::Camera, Property::Intensity(keyframes))],
+        );
+        AnimationPlayer::new(clip, mode)
+    }
+
+    fn assert_near(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-5,
+            "{} isn't {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn sample_holds_outside_the_keyframes() {
+        let keyframes = keyframes(&[1.0, 3.0], &[10.0, 20.0], Interpolation::Linear);
+        assert_near(keyframes.sample(0.0), 10.0);
+        assert_near(keyframes.sample(1.0), 10.0);
+        assert_near(keyframes.sample(3.0), 20.0);
+        assert_near(keyframes.sample(5.0), 20.0);
+    }
+
+    #[test]
+    fn sample_lerps_between_keyframes() {
+        let keyframes = keyframes(&[1.0, 3.0], &[10.0, 20.0], Interpolation::Linear);
+        assert_near(keyframes.sample(1.5), 12.5);
+        assert_near(keyframes.sample(2.0), 15.0);
+    }
+
+    #[test]
+    fn step_holds_until_the_next_keyframe() {
+        let keyframes = keyframes(&[1.0, 3.0], &[10.0, 20.0], Interpolation::Step);
+        assert_near(keyframes.sample(2.9), 10.0);
+        assert_near(keyframes.sample(3.0), 20.0);
+    }
+
+    #[test]
+    fn sample_jumps_at_repeated_times() {
+        let keyframes = keyframes(
+            &[0.0, 1.0, 1.0, 2.0],
+            &[0.0, 1.0, 5.0, 6.0],
+            Interpolation::Linear,
+        );
+        assert_near(keyframes.sample(0.5), 0.5);
+        assert_near(keyframes.sample(1.0), 5.0);
+        assert_near(keyframes.sample(1.5), 5.5);
+    }
+
+    #[test]
+    fn new_rejects_bad_keyframes() {
+        assert!(Keyframes::<f32>::new(vec![], vec![], Interpolation::Linear).is_err());
+        assert!(Keyframes::new(vec![0.0, 1.0], vec![0.0], Interpolation::Linear).is_err());
+        assert!(Keyframes::new(vec![1.0, 0.0], vec![0.0, 1.0], Interpolation::Linear).is_err());
+    }
+
+    #[test]
+    fn loop_wraps_back_to_the_start() {
+        let mut player = player(LoopMode::Loop);
+        player.advance(Duration::from_secs_f32(2.5));
+        assert_near(player.time(), 0.5);
+        assert!(player.is_playing());
+    }
+
+    #[test]
+    fn once_stops_at_the_end() {
+        let mut player = player(LoopMode::Once);
+        player.advance(Duration::from_secs_f32(3.0));
+        assert_near(player.time(), 2.0);
+        assert!(!player.is_playing());
+
+        // Playing again starts over
+        player.play();
+        assert_near(player.time(), 0.0);
+        assert!(player.is_playing());
+    }
+
+    #[test]
+    fn ping_pong_comes_back() {
+        let mut player = player(LoopMode::PingPong);
+        player.advance(Duration::from_secs_f32(1.5));
+        assert_near(player.time(), 1.5);
+        player.advance(Duration::from_secs_f32(1.0));
+        assert_near(player.time(), 1.5);
+        player.advance(Duration::from_secs_f32(2.0));
+        assert_near(player.time(), 0.5);
+    }
+
+    #[test]
+    fn negative_speed_plays_backwards() {
+        let mut player = player(LoopMode::Loop);
+        player.speed = -1.0;
+        player.advance(Duration::from_secs_f32(0.5));
+        assert_near(player.time(), 1.5);
+
+        let mut player = self::player(LoopMode::Once);
+        player.speed = -1.0;
+        player.advance(Duration::from_secs_f32(0.5));
+        assert_near(player.time(), 0.0);
+        assert!(!player.is_playing());
+    }
+
+    #[test]
+    fn paused_players_stay_put() {
+        let mut player = player(LoopMode::Loop);
+        player.advance(Duration::from_secs_f32(0.5));
+        player.pause();
+        player.advance(Duration::from_secs_f32(1.0));
+        assert_near(player.time(), 0.5);
+
+        player.stop();
+        assert_near(player.time(), 0.0);
+        assert!(!player.is_playing());
+    }
+
+    #[test]
+    fn wrap_keeps_within_one_loop() {
+        let mut player = player(LoopMode::Loop);
+        player.elapsed = -0.5;
+        player.wrap();
+        assert_near(player.elapsed, 1.5);
+
+        player.mode = LoopMode::PingPong;
+        player.elapsed = 5.0;
+        player.wrap();
+        assert_near(player.elapsed, 1.0);
+
+        player.mode = LoopMode::Once;
+        player.elapsed = 5.0;
+        player.wrap();
+        assert_near(player.elapsed, 2.0);
+    }
+
+    #[test]
+    fn seek_goes_through_wrap() {
+        let mut player = player(LoopMode::PingPong);
+        player.seek(3.0);
+        assert_near(player.time(), 1.0);
+    }
+
+    #[test]
+    fn empty_clips_stay_at_zero() {
+        let mut player = AnimationPlayer::new(AnimationClip::new("empty", vec![]), LoopMode::Loop);
+        player.advance(Duration::from_secs_f32(1.0));
+        assert_near(player.time(), 0.0);
+    }
+}
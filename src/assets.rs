@@ -0,0 +1,498 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    model::{Material, Mesh, MeshData, Model, TextureUsage, VertexEncoding},
+    source::AssetSource,
+    texture::{ImageData, MipmapGenerator, SamplerSettings, Texture},
+};
+
+/// A typed reference to something owned by [`Assets`].
+///
+/// Handles are reference counted, an asset stays loaded for as long as at
+/// least one handle to it is alive and is freed by the next
+/// [`Assets::collect_garbage`] after that.
+pub struct Handle<T> {
+    index: usize,
+    count: Arc<()>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+#[derive(Debug)]
+struct Entry<T, K> {
+    value: T,
+    count: Arc<()>,
+    path: Option<K>,
+}
+
+/// Slots for one type of asset, plus a lookup from the path it was loaded
+/// from so loading the same file twice hands back the same handle. The path
+/// can come with whatever else changes how the file was loaded.
+#[derive(Debug)]
+struct Storage<T, K = PathBuf> {
+    entries: Vec<Option<Entry<T, K>>>,
+    free: Vec<usize>,
+    paths: HashMap<K, usize>,
+}
+
+/// Owns every mesh, texture, material and model the renderer draws.
+#[derive(Debug)]
+pub struct Assets {
+    source: AssetSource,
+    /// By path and whether they're linear, since the same file can be used
+    /// as color by one material and as data by another.
+    textures: Storage<Texture, (PathBuf, bool)>,
+    materials: Storage<Material>,
+    meshes: Storage<Mesh>,
+    models: Storage<Model>,
+    /// 1x1 textures by color and whether they're linear. These are tiny
+    /// and used as defaults all over, so they're never freed.
+    solids: HashMap<([u8; 4], bool), Handle<Texture>>,
+    /// Used for every texture loaded from here on.
+    sampler: SamplerSettings,
+    /// Made on the first upload, since that's when we have a device.
+    mipmaps: Option<MipmapGenerator>,
+    /// Used for every mesh uploaded from here on.
+    vertex_encoding: VertexEncoding,
+    /// Only there for the compact encodings.
+    mesh_layout: Option<wgpu::BindGroupLayout>,
+}
+
+impl Assets {
+    pub fn new(source: AssetSource) -> Self {
+        Self {
+            source,
+            textures: Storage::default(),
+            materials: Storage::default(),
+            meshes: Storage::default(),
+            models: Storage::default(),
+            solids: HashMap::new(),
+            sampler: SamplerSettings::default(),
+            mipmaps: None,
+            vertex_encoding: VertexEncoding::Full,
+            mesh_layout: None,
+        }
+    }
+
+    /// Where the paths given to the `load_*` functions are looked up.
+    pub fn source(&self) -> &AssetSource {
+        &self.source
+    }
+
+    /// Loads a texture, or hands back the one already loaded from `path`.
+    pub fn load_texture(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: impl AsRef<Path>,
+        usage: TextureUsage,
+    ) -> anyhow::Result<Handle<Texture>> {
+        if let Some(handle) = self.find_texture(&path, usage) {
+            return Ok(handle);
+        }
+
+        let bytes = self.source.read(&path)?;
+        let image = ImageData::from_bytes(&bytes)?;
+        let label = path.as_ref().to_string_lossy();
+        let texture = self.create_texture(device, queue, &image, &label, usage)?;
+        Ok(self.add_texture_at(path, usage, texture))
+    }
+
+    /// Uploads an image with the current sampler settings and a mip chain,
+    /// without adding it to the assets.
+    pub fn create_texture(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        image: &ImageData,
+        label: &str,
+        usage: TextureUsage,
+    ) -> anyhow::Result<Texture> {
+        let mipmaps = self
+            .mipmaps
+            .get_or_insert_with(|| MipmapGenerator::new(device));
+        Texture::from_data(
+            device,
+            queue,
+            image,
+            Some(label),
+            usage.is_linear(),
+            &self.sampler,
+            Some(mipmaps),
+        )
+    }
+
+    pub fn sampler_settings(&self) -> SamplerSettings {
+        self.sampler
+    }
+
+    /// Changes how every texture gets sampled, the ones already loaded
+    /// included. Their pixels stay as they are, only their samplers and the
+    /// bind groups of the materials using them get rebuilt.
+    pub fn set_sampler_settings(
+        &mut self,
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        sampler: SamplerSettings,
+    ) {
+        if sampler == self.sampler {
+            return;
+        }
+        self.sampler = sampler;
+
+        for texture in self.textures.values_mut() {
+            texture.sampler = sampler.create_sampler(device, None);
+        }
+        self.rebuild_bind_groups(device, layout, |_| true);
+    }
+
+    /// Uploads a mesh in the current vertex encoding, without adding it to
+    /// the assets.
+    pub fn create_mesh(&self, device: &wgpu::Device, label: &str, mesh: MeshData) -> Mesh {
+        // The skinning pipelines' last bind group holds the joints, which
+        // leaves nowhere for the compact encodings' dequantize uniform
+        if mesh.skin.is_some() {
+            return Mesh::from_data(device, label, mesh, VertexEncoding::Full, None);
+        }
+        Mesh::from_data(
+            device,
+            label,
+            mesh,
+            self.vertex_encoding,
+            self.mesh_layout.as_ref(),
+        )
+    }
+
+    pub fn vertex_encoding(&self) -> VertexEncoding {
+        self.vertex_encoding
+    }
+
+    /// Changes how meshes uploaded after this are packed. This has to match
+    /// the pipelines that draw them, so it's meant to be set once before
+    /// anything is loaded.
+    pub fn set_vertex_encoding(&mut self, device: &wgpu::Device, encoding: VertexEncoding) {
+        self.vertex_encoding = encoding;
+        if encoding.is_compact() && self.mesh_layout.is_none() {
+            self.mesh_layout = Some(Mesh::create_bind_group_layout(device));
+        }
+    }
+
+    /// What compact meshes need bound alongside them, `None` when meshes
+    /// are stored in full.
+    pub fn mesh_bind_group_layout(&self) -> Option<&wgpu::BindGroupLayout> {
+        self.mesh_layout
+            .as_ref()
+            .filter(|_| self.vertex_encoding.is_compact())
+    }
+
+    /// A 1x1 texture of a single color, shared with everything else that
+    /// asks for the same one.
+    pub fn solid_texture(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        color: [u8; 4],
+        is_linear: bool,
+    ) -> Handle<Texture> {
+        if let Some(handle) = self.solids.get(&(color, is_linear)) {
+            return handle.clone();
+        }
+
+        let texture = Texture::solid(device, queue, color, "solid", is_linear);
+        let handle = self.add_texture(texture);
+        self.solids.insert((color, is_linear), handle.clone());
+        handle
+    }
+
+    /// Loads a model along with everything it references, or hands back the
+    /// one already loaded from `path`.
+    pub fn load_model(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        path: impl AsRef<Path>,
+    ) -> anyhow::Result<Handle<Model>> {
+        if let Some(handle) = self.find_model(&path) {
+            return Ok(handle);
+        }
+
+        let model = Model::load(device, queue, layout, self, &path)?;
+        Ok(self.add_model_at(path, model))
+    }
+
+    pub fn add_texture(&mut self, texture: Texture) -> Handle<Texture> {
+        self.textures.insert(texture, None)
+    }
+
+    /// Adds a texture that was loaded some other way than [`load_texture`],
+    /// cached under `path` and `usage` as if it had been.
+    ///
+    /// [`load_texture`]: Assets::load_texture
+    pub fn add_texture_at(
+        &mut self,
+        path: impl AsRef<Path>,
+        usage: TextureUsage,
+        texture: Texture,
+    ) -> Handle<Texture> {
+        let key = (self.source.cache_key(path.as_ref()), usage.is_linear());
+        self.textures.insert(texture, Some(key))
+    }
+
+    pub fn add_material(&mut self, material: Material) -> Handle<Material> {
+        self.materials.insert(material, None)
+    }
+
+    pub fn add_mesh(&mut self, mesh: Mesh) -> Handle<Mesh> {
+        self.meshes.insert(mesh, None)
+    }
+
+    pub fn add_model(&mut self, model: Model) -> Handle<Model> {
+        self.models.insert(model, None)
+    }
+
+    pub fn add_model_at(&mut self, path: impl AsRef<Path>, model: Model) -> Handle<Model> {
+        self.models
+            .insert(model, Some(self.source.cache_key(path.as_ref())))
+    }
+
+    /// Only finds a texture loaded for a usage that's decoded the same way,
+    /// normal maps and other data share one but colors get their own.
+    pub fn find_texture(
+        &self,
+        path: impl AsRef<Path>,
+        usage: TextureUsage,
+    ) -> Option<Handle<Texture>> {
+        let key = (self.source.cache_key(path.as_ref()), usage.is_linear());
+        self.textures.find(&key)
+    }
+
+    pub fn find_model(&self, path: impl AsRef<Path>) -> Option<Handle<Model>> {
+        self.models.find(&self.source.cache_key(path.as_ref()))
+    }
+
+    /// Every model loaded from a file in `dir`, along with that file.
+    pub fn models_in(&self, dir: impl AsRef<Path>) -> Vec<(Handle<Model>, PathBuf)> {
+        let dir = self.source.cache_key(dir.as_ref());
+        self.models
+            .with_paths()
+            .filter(|(path, _)| path.parent() == Some(dir.as_path()))
+            .map(|(path, handle)| (handle, path.to_path_buf()))
+            .collect()
+    }
+
+    /// Swaps the texture behind `handle`, rebuilding the bind group of every
+    /// material that uses it.
+    pub fn replace_texture(
+        &mut self,
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        handle: &Handle<Texture>,
+        texture: Texture,
+    ) {
+        self.textures.replace(handle, texture);
+        self.rebuild_bind_groups(device, layout, |texture| texture == handle);
+    }
+
+    /// Rebuilds the bind group of every material using a texture that
+    /// `uses` picks out.
+    fn rebuild_bind_groups(
+        &mut self,
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        uses: impl Fn(&Handle<Texture>) -> bool,
+    ) {
+        let textures = &self.textures;
+        for material in self.materials.values_mut() {
+            if material.textures.iter().any(|(texture, _)| uses(texture)) {
+                material.bind_group = Material::create_bind_group(
+                    device,
+                    layout,
+                    &material.name,
+                    material
+                        .textures
+                        .as_ref()
+                        .map(|handle, _| textures.get(handle)),
+                    &material.factors_buffer,
+                );
+            }
+        }
+    }
+
+    /// Swaps the model behind `handle`, anything drawing it picks up the new
+    /// one on the next frame.
+    pub fn replace_model(&mut self, handle: &Handle<Model>, model: Model) {
+        self.models.replace(handle, model);
+    }
+
+    pub fn texture(&self, handle: &Handle<Texture>) -> &Texture {
+        self.textures.get(handle)
+    }
+
+    pub fn material(&self, handle: &Handle<Material>) -> &Material {
+        self.materials.get(handle)
+    }
+
+    pub fn mesh(&self, handle: &Handle<Mesh>) -> &Mesh {
+        self.meshes.get(handle)
+    }
+
+    pub fn model(&self, handle: &Handle<Model>) -> &Model {
+        self.models.get(handle)
+    }
+
+    /// Frees everything nothing holds a handle to anymore. Models go first
+    /// since dropping them releases their meshes and materials, which in
+    /// turn release their textures.
+    pub fn collect_garbage(&mut self) {
+        let freed = self.models.collect_garbage()
+            + self.materials.collect_garbage()
+            + self.meshes.collect_garbage()
+            + self.textures.collect_garbage();
+
+        if freed > 0 {
+            log::debug!("Freed {} unused assets", freed);
+        }
+    }
+}
+
+impl<T, K: Clone + Eq + Hash> Storage<T, K> {
+    fn insert(&mut self, value: T, path: Option<K>) -> Handle<T> {
+        let count = Arc::new(());
+        let entry = Entry {
+            value,
+            count: count.clone(),
+            path: path.clone(),
+        };
+
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.entries[index] = Some(entry);
+                index
+            }
+            None => {
+                self.entries.push(Some(entry));
+                self.entries.len() - 1
+            }
+        };
+
+        if let Some(path) = path {
+            self.paths.insert(path, index);
+        }
+
+        Handle {
+            index,
+            count,
+            _marker: PhantomData,
+        }
+    }
+
+    fn find(&self, path: &K) -> Option<Handle<T>> {
+        let index = *self.paths.get(path)?;
+        let entry = self.entries[index].as_ref()?;
+
+        Some(Handle {
+            index,
+            count: entry.count.clone(),
+            _marker: PhantomData,
+        })
+    }
+
+    fn get(&self, handle: &Handle<T>) -> &T {
+        // A live handle keeps its slot from being reused, so this can only
+        // fail for a handle from a different `Assets`
+        &self.entries[handle.index]
+            .as_ref()
+            .expect("Handle doesn't belong to these assets")
+            .value
+    }
+
+    fn replace(&mut self, handle: &Handle<T>, value: T) -> T {
+        let entry = self.entries[handle.index]
+            .as_mut()
+            .expect("Handle doesn't belong to these assets");
+        std::mem::replace(&mut entry.value, value)
+    }
+
+    fn with_paths(&self) -> impl Iterator<Item = (&K, Handle<T>)> {
+        self.paths.iter().filter_map(move |(path, &index)| {
+            let entry = self.entries[index].as_ref()?;
+            Some((
+                path,
+                Handle {
+                    index,
+                    count: entry.count.clone(),
+                    _marker: PhantomData,
+                },
+            ))
+        })
+    }
+
+    fn values_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.entries
+            .iter_mut()
+            .flatten()
+            .map(|entry| &mut entry.value)
+    }
+
+    fn collect_garbage(&mut self) -> usize {
+        let mut freed = 0;
+        for (index, slot) in self.entries.iter_mut().enumerate() {
+            let unused = matches!(slot, Some(entry) if Arc::strong_count(&entry.count) == 1);
+            if unused {
+                if let Some(path) = slot.take().and_then(|entry| entry.path) {
+                    self.paths.remove(&path);
+                }
+                self.free.push(index);
+                freed += 1;
+            }
+        }
+        freed
+    }
+}
+
+impl<T, K> Default for Storage<T, K> {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+            free: Vec::new(),
+            paths: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            index: self.index,
+            count: self.count.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> PartialEq for Handle<T> {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.count, &other.count)
+    }
+}
+
+impl<T> Eq for Handle<T> {}
+
+impl<T> Hash for Handle<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.count).hash(state);
+    }
+}
+
+impl<T> fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Handle").field(&self.index).finish()
+    }
+}
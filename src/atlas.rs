@@ -0,0 +1,236 @@
+use crate::{model::ModelVertex, texture::mip_level_count};
+use glam::Vec2;
+use image::RgbaImage;
+
+/// Pixels of each image's edge repeated around it, so the lower mips don't
+/// bleed the neighbours in. Each mip halves it, so the atlas only gets as
+/// many as keep at least one pixel of it.
+const PADDING: u32 = 4;
+
+/// Lots of small images packed into one, so everything using them can share
+/// a single texture and bind group.
+///
+/// UVs have to be remapped into each image's region with
+/// [`AtlasRegion::remap`], which also means tiling doesn't survive being
+/// packed.
+#[derive(Debug)]
+pub struct TextureAtlas {
+    /// The atlas and its mips, largest first. Only as many as the padding
+    /// lasts for, since a full chain would blur the images into each other.
+    pub levels: Vec<RgbaImage>,
+    /// One for each image, in the order they were given.
+    pub regions: Vec<AtlasRegion>,
+}
+
+/// Where one image ended up in a [`TextureAtlas`], in UVs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AtlasRegion {
+    pub offset: Vec2,
+    pub scale: Vec2,
+}
+
+impl AtlasRegion {
+    /// Takes a UV for the original image to the same spot in the atlas.
+    pub fn remap(&self, uv: Vec2) -> Vec2 {
+        self.offset + uv.clamp(Vec2::ZERO, Vec2::ONE) * self.scale
+    }
+}
+
+impl TextureAtlas {
+    /// Packs `images`, or `None` if there aren't any, one of them is empty or
+    /// they don't fit in `max_size` on a side.
+    pub fn new(images: &[RgbaImage], max_size: u32) -> Option<Self> {
+        if images
+            .iter()
+            .any(|image| image.width() == 0 || image.height() == 0)
+        {
+            return None;
+        }
+
+        // Rounding up to a multiple of the padding keeps every image on the
+        // same pixel grid in each mip
+        let align = |size: u32| (size + PADDING * 2 + PADDING - 1) / PADDING * PADDING;
+        let sizes = images
+            .iter()
+            .map(|image| (align(image.width()), align(image.height())))
+            .collect::<Vec<_>>();
+        let (width, height, positions) = pack(&sizes)?;
+        if width > max_size || height > max_size {
+            return None;
+        }
+
+        let mut atlas = RgbaImage::new(width, height);
+        for ((image, &(x, y)), &size) in images.iter().zip(&positions).zip(&sizes) {
+            blit_padded(&mut atlas, image, (x, y), size);
+        }
+
+        let atlas_size = Vec2::new(width as f32, height as f32);
+        let regions = images
+            .iter()
+            .zip(&positions)
+            .map(|(image, &(x, y))| AtlasRegion {
+                offset: Vec2::new((x + PADDING) as f32, (y + PADDING) as f32) / atlas_size,
+                scale: Vec2::new(image.width() as f32, image.height() as f32) / atlas_size,
+            })
+            .collect();
+
+        // Averaging sRGB values directly comes out a little dark, but these
+        // are only the small mips
+        let mip_level_count = (PADDING.trailing_zeros() + 1).min(mip_level_count(width, height));
+        let mut levels = vec![atlas];
+        for level in 1..mip_level_count {
+            let next = image::imageops::resize(
+                &levels[level as usize - 1],
+                (width >> level).max(1),
+                (height >> level).max(1),
+                image::imageops::FilterType::Triangle,
+            );
+            levels.push(next);
+        }
+
+        Some(Self { levels, regions })
+    }
+
+    /// Moves the UVs of a mesh textured with image `region` into the atlas.
+    pub fn remap_vertices(&self, region: usize, vertices: &mut [ModelVertex]) {
+        let region = self.regions[region];
+        for vertex in vertices {
+            vertex.tex_coords = region.remap(vertex.tex_coords);
+        }
+    }
+}
+
+/// Shelf packs `sizes`, tallest first, into a power of two wide texture.
+/// Returns its size and where each one went.
+fn pack(sizes: &[(u32, u32)]) -> Option<(u32, u32, Vec<(u32, u32)>)> {
+    let widest = sizes.iter().map(|&(width, _)| width).max()?;
+    let area = sizes
+        .iter()
+        .map(|&(width, height)| width as u64 * height as u64)
+        .sum::<u64>();
+    let width = ((area as f64).sqrt() as u32)
+        .max(widest)
+        .next_power_of_two();
+
+    let mut order = (0..sizes.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| std::cmp::Reverse(sizes[i].1));
+
+    let mut positions = vec![(0, 0); sizes.len()];
+    let (mut x, mut shelf_y, mut shelf_height) = (0, 0, 0);
+    for i in order {
+        let (item_width, item_height) = sizes[i];
+        if x + item_width > width {
+            x = 0;
+            shelf_y += shelf_height;
+            shelf_height = 0;
+        }
+        positions[i] = (x, shelf_y);
+        x += item_width;
+        shelf_height = shelf_height.max(item_height);
+    }
+
+    let height = (shelf_y + shelf_height).next_power_of_two();
+    Some((width, height, positions))
+}
+
+/// Copies `image` to `position` plus [`PADDING`], stretching its edge
+/// pixels out over the rest of its `size` cell.
+fn blit_padded(atlas: &mut RgbaImage, image: &RgbaImage, position: (u32, u32), size: (u32, u32)) {
+    let (width, height) = image.dimensions();
+    for dy in 0..size.1 {
+        for dx in 0..size.0 {
+            let source_x = dx.saturating_sub(PADDING).min(width - 1);
+            let source_y = dy.saturating_sub(PADDING).min(height - 1);
+            let pixel = *image.get_pixel(source_x, source_y);
+            atlas.put_pixel(position.0 + dx, position.1 + dy, pixel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overlaps(a: ((u32, u32), (u32, u32)), b: ((u32, u32), (u32, u32))) -> bool {
+        let (((ax, ay), (aw, ah)), ((bx, by), (bw, bh))) = (a, b);
+        ax < bx + bw && bx < ax + aw && ay < by + bh && by < ay + ah
+    }
+
+    #[test]
+    fn pack_keeps_everything_apart_and_in_bounds() {
+        let sizes = [
+            (40, 40),
+            (16, 72),
+            (128, 8),
+            (24, 24),
+            (24, 24),
+            (8, 8),
+            (100, 36),
+            (12, 60),
+        ];
+        let (width, height, positions) = pack(&sizes).unwrap();
+        assert!(width.is_power_of_two() && height.is_power_of_two());
+
+        let rects = positions
+            .into_iter()
+            .zip(sizes.iter().copied())
+            .collect::<Vec<_>>();
+        for (i, &((x, y), (w, h))) in rects.iter().enumerate() {
+            assert!(x + w <= width && y + h <= height, "{} is out of bounds", i);
+            for (j, &other) in rects.iter().enumerate().skip(i + 1) {
+                assert!(!overlaps(rects[i], other), "{} overlaps {}", i, j);
+            }
+        }
+    }
+
+    #[test]
+    fn pack_takes_nothing() {
+        assert!(pack(&[]).is_none());
+    }
+
+    #[test]
+    fn remap_maps_corners_onto_regions() {
+        let images = [
+            RgbaImage::new(32, 16),
+            RgbaImage::new(8, 8),
+            RgbaImage::new(20, 44),
+        ];
+        let atlas = TextureAtlas::new(&images, 1024).unwrap();
+        let (width, height) = atlas.levels[0].dimensions();
+        let atlas_size = Vec2::new(width as f32, height as f32);
+
+        for (image, region) in images.iter().zip(&atlas.regions) {
+            let top_left = region.remap(Vec2::ZERO) * atlas_size;
+            let bottom_right = region.remap(Vec2::ONE) * atlas_size;
+            assert_eq!(
+                bottom_right - top_left,
+                Vec2::new(image.width() as f32, image.height() as f32)
+            );
+            assert!(top_left.cmpge(Vec2::ZERO).all());
+            assert!(bottom_right.cmple(atlas_size).all());
+            assert_eq!(region.remap(Vec2::new(2.0, -1.0)), region.remap(Vec2::X));
+        }
+
+        for (i, a) in atlas.regions.iter().enumerate() {
+            for b in &atlas.regions[i + 1..] {
+                let rect = |region: &AtlasRegion| {
+                    let min = region.remap(Vec2::ZERO) * atlas_size;
+                    let size = region.scale * atlas_size;
+                    ((min.x as u32, min.y as u32), (size.x as u32, size.y as u32))
+                };
+                assert!(!overlaps(rect(a), rect(b)));
+            }
+        }
+    }
+
+    #[test]
+    fn mips_stop_before_the_padding_runs_out() {
+        let atlas =
+            TextureAtlas::new(&[RgbaImage::new(64, 64), RgbaImage::new(64, 64)], 1024).unwrap();
+        assert_eq!(atlas.levels.len() as u32, PADDING.trailing_zeros() + 1);
+        for pair in atlas.levels.windows(2) {
+            assert_eq!(pair[1].width(), pair[0].width() / 2);
+            assert_eq!(pair[1].height(), pair[0].height() / 2);
+        }
+    }
+}
@@ -1,7 +1,9 @@
-use glam::{vec3, vec4, Mat4, Vec3, Vec4};
+use glam::{vec3, vec4, Mat4, Quat, Vec3, Vec4};
 use wgpu::SurfaceConfiguration;
 use winit::event::WindowEvent;
 
+use crate::animation::Animatable;
+
 #[derive(Debug)]
 pub struct Camera {
     pub position: Vec3,
@@ -32,12 +34,26 @@ impl Camera {
     }
 }
 
+impl Animatable for Camera {
+    fn set_position(&mut self, position: Vec3) {
+        self.position = position;
+    }
+
+    /// Unrotated, the camera looks down +X. It can't roll, so any roll in
+    /// `rotation` is lost.
+    fn set_rotation(&mut self, rotation: Quat) {
+        let forward = rotation * Vec3::X;
+        self.yaw = forward.z.atan2(forward.x);
+        self.pitch = forward.y.clamp(-1.0, 1.0).asin();
+    }
+}
+
 #[derive(Debug)]
 pub struct Projection {
     aspect: f32,
-    fov: f32,
-    znear: f32,
-    zfar: f32,
+    pub fov: f32,
+    pub znear: f32,
+    pub zfar: f32,
 }
 
 impl Projection {
@@ -68,12 +84,15 @@ pub struct CameraUniform {
     // We can't use cgmath with bytemuck directly so we'll have
     // to convert the Matrix4 into a 4x4 f32 array
     view_proj: Mat4,
+    // Used to get back to world space from the depth buffer
+    inv_view_proj: Mat4,
 }
 
 impl CameraUniform {
     pub fn update_view_proj(&mut self, camera: &Camera, projection: &Projection) {
         self.view_position = vec4(camera.position.x, camera.position.y, camera.position.z, 1.0);
         self.view_proj = projection.calc_matrix() * camera.calc_matrix();
+        self.inv_view_proj = self.view_proj.inverse();
 
         println!("view_pos: {:?}", self.view_position);
     }
@@ -0,0 +1,420 @@
+use anyhow::{anyhow, bail, Context};
+use ddsfile::{D3DFormat, Dds, DxgiFormat};
+use wgpu::TextureFormat;
+
+const KTX2_MAGIC: [u8; 12] = [
+    0xAB, b'K', b'T', b'X', b' ', b'2', b'0', 0xBB, b'\r', b'\n', 0x1A, b'\n',
+];
+const DDS_MAGIC: &[u8] = b"DDS ";
+
+/// How `texture2ddecoder` decodes a whole level: the blocks, then its width
+/// and height, into one BGRA8 texel per `u32`.
+type Decode = fn(&[u8], usize, usize, &mut [u32]) -> Result<(), &'static str>;
+
+/// Formats that come in both flavours, linear first.
+const SRGB_PAIRS: &[(TextureFormat, TextureFormat)] = &[
+    (TextureFormat::Rgba8Unorm, TextureFormat::Rgba8UnormSrgb),
+    (TextureFormat::Bgra8Unorm, TextureFormat::Bgra8UnormSrgb),
+    (TextureFormat::Bc1RgbaUnorm, TextureFormat::Bc1RgbaUnormSrgb),
+    (TextureFormat::Bc2RgbaUnorm, TextureFormat::Bc2RgbaUnormSrgb),
+    (TextureFormat::Bc3RgbaUnorm, TextureFormat::Bc3RgbaUnormSrgb),
+    (TextureFormat::Bc7RgbaUnorm, TextureFormat::Bc7RgbaUnormSrgb),
+    (TextureFormat::Etc2RgbUnorm, TextureFormat::Etc2RgbUnormSrgb),
+    (
+        TextureFormat::Etc2RgbA1Unorm,
+        TextureFormat::Etc2RgbA1UnormSrgb,
+    ),
+    (
+        TextureFormat::Astc4x4RgbaUnorm,
+        TextureFormat::Astc4x4RgbaUnormSrgb,
+    ),
+    (
+        TextureFormat::Astc5x4RgbaUnorm,
+        TextureFormat::Astc5x4RgbaUnormSrgb,
+    ),
+    (
+        TextureFormat::Astc5x5RgbaUnorm,
+        TextureFormat::Astc5x5RgbaUnormSrgb,
+    ),
+    (
+        TextureFormat::Astc6x5RgbaUnorm,
+        TextureFormat::Astc6x5RgbaUnormSrgb,
+    ),
+    (
+        TextureFormat::Astc6x6RgbaUnorm,
+        TextureFormat::Astc6x6RgbaUnormSrgb,
+    ),
+    (
+        TextureFormat::Astc8x5RgbaUnorm,
+        TextureFormat::Astc8x5RgbaUnormSrgb,
+    ),
+    (
+        TextureFormat::Astc8x6RgbaUnorm,
+        TextureFormat::Astc8x6RgbaUnormSrgb,
+    ),
+    (
+        TextureFormat::Astc8x8RgbaUnorm,
+        TextureFormat::Astc8x8RgbaUnormSrgb,
+    ),
+    (
+        TextureFormat::Astc10x5RgbaUnorm,
+        TextureFormat::Astc10x5RgbaUnormSrgb,
+    ),
+    (
+        TextureFormat::Astc10x6RgbaUnorm,
+        TextureFormat::Astc10x6RgbaUnormSrgb,
+    ),
+    (
+        TextureFormat::Astc10x8RgbaUnorm,
+        TextureFormat::Astc10x8RgbaUnormSrgb,
+    ),
+    (
+        TextureFormat::Astc10x10RgbaUnorm,
+        TextureFormat::Astc10x10RgbaUnormSrgb,
+    ),
+    (
+        TextureFormat::Astc12x10RgbaUnorm,
+        TextureFormat::Astc12x10RgbaUnormSrgb,
+    ),
+    (
+        TextureFormat::Astc12x12RgbaUnorm,
+        TextureFormat::Astc12x12RgbaUnormSrgb,
+    ),
+];
+
+/// An image read from a KTX2 or DDS file, still in the format the GPU
+/// samples it in, along with whatever mips the file came with.
+#[derive(Debug, Clone)]
+pub struct CompressedImage {
+    pub width: u32,
+    pub height: u32,
+    pub format: TextureFormat,
+    /// Largest first, each one rows of blocks packed tightly together.
+    pub levels: Vec<Vec<u8>>,
+}
+
+/// Whether `bytes` look like a KTX2 or DDS file rather than something
+/// `image` can decode.
+pub fn is_compressed(bytes: &[u8]) -> bool {
+    bytes.starts_with(&KTX2_MAGIC) || bytes.starts_with(DDS_MAGIC)
+}
+
+/// The sRGB version of `format` if `srgb`, otherwise the linear one. Formats
+/// that only come in one flavour are left alone.
+pub fn with_srgb(format: TextureFormat, srgb: bool) -> TextureFormat {
+    SRGB_PAIRS
+        .iter()
+        .find(|pair| format == pair.0 || format == pair.1)
+        .map(|&(linear, srgb_format)| if srgb { srgb_format } else { linear })
+        .unwrap_or(format)
+}
+
+/// How many bytes one mip level of `format` takes up.
+pub fn level_size(format: TextureFormat, width: u32, height: u32) -> usize {
+    let info = format.describe();
+    let (block_width, block_height) = info.block_dimensions;
+    let blocks_wide = (width.max(1) + block_width as u32 - 1) / block_width as u32;
+    let blocks_high = (height.max(1) + block_height as u32 - 1) / block_height as u32;
+    (blocks_wide * blocks_high * info.block_size as u32) as usize
+}
+
+impl CompressedImage {
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        if bytes.starts_with(&KTX2_MAGIC) {
+            Self::from_ktx2(bytes)
+        } else if bytes.starts_with(DDS_MAGIC) {
+            Self::from_dds(bytes)
+        } else {
+            bail!("Not a KTX2 or DDS file")
+        }
+    }
+
+    fn from_ktx2(bytes: &[u8]) -> anyhow::Result<Self> {
+        let reader = ktx2::Reader::new(bytes).context("Invalid KTX2 file")?;
+        let header = reader.header();
+        if header.supercompression_scheme.is_some() {
+            bail!("Supercompressed KTX2 files aren't supported");
+        }
+        if header.pixel_depth > 1 || header.layer_count > 1 || header.face_count > 1 {
+            bail!("Only 2D KTX2 textures are supported");
+        }
+
+        let ktx2_format = header
+            .format
+            .context("KTX2 file has no format, Basis Universal isn't supported")?;
+        let format = ktx2_to_wgpu(ktx2_format)
+            .with_context(|| format!("Unsupported KTX2 format {:?}", ktx2_format))?;
+
+        Self::new(
+            header.pixel_width,
+            header.pixel_height,
+            format,
+            reader.levels().map(<[u8]>::to_vec).collect(),
+        )
+    }
+
+    fn from_dds(bytes: &[u8]) -> anyhow::Result<Self> {
+        let dds = Dds::read(bytes).context("Invalid DDS file")?;
+        if dds.get_depth() > 1 || dds.get_num_array_layers() > 1 {
+            bail!("Only 2D DDS textures are supported");
+        }
+
+        let format = match (dds.get_dxgi_format(), dds.get_d3d_format()) {
+            (Some(format), _) => dxgi_to_wgpu(format),
+            (None, Some(format)) => d3d_to_wgpu(format),
+            (None, None) => None,
+        }
+        .context("Unsupported DDS format")?;
+
+        // The levels are packed one after the other
+        let data = dds.get_data(0)?;
+        let (width, height) = (dds.get_width(), dds.get_height());
+        let mut levels = Vec::new();
+        let mut offset = 0;
+        for level in 0..dds.get_num_mipmap_levels().max(1) {
+            let size = level_size(format, width >> level, height >> level);
+            let bytes = data
+                .get(offset..offset + size)
+                .context("DDS file is truncated")?;
+            levels.push(bytes.to_vec());
+            offset += size;
+        }
+
+        Self::new(width, height, format, levels)
+    }
+
+    fn new(
+        width: u32,
+        height: u32,
+        format: TextureFormat,
+        levels: Vec<Vec<u8>>,
+    ) -> anyhow::Result<Self> {
+        let height = height.max(1);
+        if levels.is_empty() {
+            bail!("Image has no mip levels");
+        }
+        for (i, level) in levels.iter().enumerate() {
+            if level.len() < level_size(format, width >> i, height >> i) {
+                bail!("Mip level {} is truncated", i);
+            }
+        }
+
+        Ok(Self {
+            width,
+            height,
+            format,
+            levels,
+        })
+    }
+
+    /// Decodes on the CPU into BGRA8, for adapters that can't sample the
+    /// format directly. BC6H gets clamped to 0..1 on the way. Signed formats
+    /// and BC2 aren't supported.
+    pub fn decompress(&self) -> anyhow::Result<Self> {
+        use texture2ddecoder as decoder;
+        use TextureFormat as F;
+        let srgb = SRGB_PAIRS.iter().any(|&(_, srgb)| self.format == srgb);
+        let bgra8 = with_srgb(F::Bgra8Unorm, srgb);
+
+        let decode: Decode = match self.format {
+            F::Rgba8Unorm | F::Rgba8UnormSrgb | F::Bgra8Unorm | F::Bgra8UnormSrgb => {
+                return Ok(self.clone())
+            }
+            F::Bc1RgbaUnorm | F::Bc1RgbaUnormSrgb => decoder::decode_bc1,
+            F::Bc3RgbaUnorm | F::Bc3RgbaUnormSrgb => decoder::decode_bc3,
+            F::Bc4RUnorm => decoder::decode_bc4,
+            F::Bc5RgUnorm => decoder::decode_bc5,
+            F::Bc6hRgbUfloat => decoder::decode_bc6_unsigned,
+            F::Bc7RgbaUnorm | F::Bc7RgbaUnormSrgb => decoder::decode_bc7,
+            F::Etc2RgbUnorm | F::Etc2RgbUnormSrgb => decoder::decode_etc2_rgb,
+            F::Etc2RgbA1Unorm | F::Etc2RgbA1UnormSrgb => decoder::decode_etc2_rgba1,
+            F::EacRUnorm => decoder::decode_eacr,
+            F::EacRgUnorm => decoder::decode_eacrg,
+            format
+                if format
+                    .describe()
+                    .required_features
+                    .contains(wgpu::Features::TEXTURE_COMPRESSION_ASTC_LDR) =>
+            {
+                let (width, height) = format.describe().block_dimensions;
+                let (block_width, block_height) = (width as usize, height as usize);
+                return self.decode_levels(bgra8, |data, width, height, image| {
+                    decoder::decode_astc(data, width, height, block_width, block_height, image)
+                });
+            }
+            format => bail!("{:?} can't be decoded on the CPU", format),
+        };
+        self.decode_levels(bgra8, decode)
+    }
+
+    /// Runs `decode` over every level, which fills in a level's worth of
+    /// BGRA8 texels.
+    fn decode_levels(
+        &self,
+        format: TextureFormat,
+        decode: impl Fn(&[u8], usize, usize, &mut [u32]) -> Result<(), &'static str>,
+    ) -> anyhow::Result<Self> {
+        let levels = self
+            .levels
+            .iter()
+            .enumerate()
+            .map(|(i, level)| {
+                let width = (self.width >> i).max(1) as usize;
+                let height = (self.height >> i).max(1) as usize;
+                let mut pixels = vec![0; width * height];
+                decode(level, width, height, &mut pixels)
+                    .map_err(|e| anyhow!("Failed to decode mip level {}: {}", i, e))?;
+                Ok(bytemuck::cast_slice(&pixels).to_vec())
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            width: self.width,
+            height: self.height,
+            format,
+            levels,
+        })
+    }
+}
+
+fn ktx2_to_wgpu(format: ktx2::Format) -> Option<TextureFormat> {
+    use ktx2::Format as K;
+    Some(match format {
+        K::R8G8B8A8_UNORM => TextureFormat::Rgba8Unorm,
+        K::R8G8B8A8_SRGB => TextureFormat::Rgba8UnormSrgb,
+        K::B8G8R8A8_UNORM => TextureFormat::Bgra8Unorm,
+        K::B8G8R8A8_SRGB => TextureFormat::Bgra8UnormSrgb,
+        K::BC1_RGB_UNORM_BLOCK | K::BC1_RGBA_UNORM_BLOCK => TextureFormat::Bc1RgbaUnorm,
+        K::BC1_RGB_SRGB_BLOCK | K::BC1_RGBA_SRGB_BLOCK => TextureFormat::Bc1RgbaUnormSrgb,
+        K::BC2_UNORM_BLOCK => TextureFormat::Bc2RgbaUnorm,
+        K::BC2_SRGB_BLOCK => TextureFormat::Bc2RgbaUnormSrgb,
+        K::BC3_UNORM_BLOCK => TextureFormat::Bc3RgbaUnorm,
+        K::BC3_SRGB_BLOCK => TextureFormat::Bc3RgbaUnormSrgb,
+        K::BC4_UNORM_BLOCK => TextureFormat::Bc4RUnorm,
+        K::BC4_SNORM_BLOCK => TextureFormat::Bc4RSnorm,
+        K::BC5_UNORM_BLOCK => TextureFormat::Bc5RgUnorm,
+        K::BC5_SNORM_BLOCK => TextureFormat::Bc5RgSnorm,
+        K::BC6H_UFLOAT_BLOCK => TextureFormat::Bc6hRgbUfloat,
+        K::BC6H_SFLOAT_BLOCK => TextureFormat::Bc6hRgbSfloat,
+        K::BC7_UNORM_BLOCK => TextureFormat::Bc7RgbaUnorm,
+        K::BC7_SRGB_BLOCK => TextureFormat::Bc7RgbaUnormSrgb,
+        K::ETC2_R8G8B8_UNORM_BLOCK => TextureFormat::Etc2RgbUnorm,
+        K::ETC2_R8G8B8_SRGB_BLOCK => TextureFormat::Etc2RgbUnormSrgb,
+        K::ETC2_R8G8B8A1_UNORM_BLOCK => TextureFormat::Etc2RgbA1Unorm,
+        K::ETC2_R8G8B8A1_SRGB_BLOCK => TextureFormat::Etc2RgbA1UnormSrgb,
+        K::EAC_R11_UNORM_BLOCK => TextureFormat::EacRUnorm,
+        K::EAC_R11_SNORM_BLOCK => TextureFormat::EacRSnorm,
+        K::EAC_R11G11_UNORM_BLOCK => TextureFormat::EacRgUnorm,
+        K::EAC_R11G11_SNORM_BLOCK => TextureFormat::EacRgSnorm,
+        K::ASTC_4x4_UNORM_BLOCK => TextureFormat::Astc4x4RgbaUnorm,
+        K::ASTC_4x4_SRGB_BLOCK => TextureFormat::Astc4x4RgbaUnormSrgb,
+        K::ASTC_5x4_UNORM_BLOCK => TextureFormat::Astc5x4RgbaUnorm,
+        K::ASTC_5x4_SRGB_BLOCK => TextureFormat::Astc5x4RgbaUnormSrgb,
+        K::ASTC_5x5_UNORM_BLOCK => TextureFormat::Astc5x5RgbaUnorm,
+        K::ASTC_5x5_SRGB_BLOCK => TextureFormat::Astc5x5RgbaUnormSrgb,
+        K::ASTC_6x5_UNORM_BLOCK => TextureFormat::Astc6x5RgbaUnorm,
+        K::ASTC_6x5_SRGB_BLOCK => TextureFormat::Astc6x5RgbaUnormSrgb,
+        K::ASTC_6x6_UNORM_BLOCK => TextureFormat::Astc6x6RgbaUnorm,
+        K::ASTC_6x6_SRGB_BLOCK => TextureFormat::Astc6x6RgbaUnormSrgb,
+        K::ASTC_8x5_UNORM_BLOCK => TextureFormat::Astc8x5RgbaUnorm,
+        K::ASTC_8x5_SRGB_BLOCK => TextureFormat::Astc8x5RgbaUnormSrgb,
+        K::ASTC_8x6_UNORM_BLOCK => TextureFormat::Astc8x6RgbaUnorm,
+        K::ASTC_8x6_SRGB_BLOCK => TextureFormat::Astc8x6RgbaUnormSrgb,
+        K::ASTC_8x8_UNORM_BLOCK => TextureFormat::Astc8x8RgbaUnorm,
+        K::ASTC_8x8_SRGB_BLOCK => TextureFormat::Astc8x8RgbaUnormSrgb,
+        K::ASTC_10x5_UNORM_BLOCK => TextureFormat::Astc10x5RgbaUnorm,
+        K::ASTC_10x5_SRGB_BLOCK => TextureFormat::Astc10x5RgbaUnormSrgb,
+        K::ASTC_10x6_UNORM_BLOCK => TextureFormat::Astc10x6RgbaUnorm,
+        K::ASTC_10x6_SRGB_BLOCK => TextureFormat::Astc10x6RgbaUnormSrgb,
+        K::ASTC_10x8_UNORM_BLOCK => TextureFormat::Astc10x8RgbaUnorm,
+        K::ASTC_10x8_SRGB_BLOCK => TextureFormat::Astc10x8RgbaUnormSrgb,
+        K::ASTC_10x10_UNORM_BLOCK => TextureFormat::Astc10x10RgbaUnorm,
+        K::ASTC_10x10_SRGB_BLOCK => TextureFormat::Astc10x10RgbaUnormSrgb,
+        K::ASTC_12x10_UNORM_BLOCK => TextureFormat::Astc12x10RgbaUnorm,
+        K::ASTC_12x10_SRGB_BLOCK => TextureFormat::Astc12x10RgbaUnormSrgb,
+        K::ASTC_12x12_UNORM_BLOCK => TextureFormat::Astc12x12RgbaUnorm,
+        K::ASTC_12x12_SRGB_BLOCK => TextureFormat::Astc12x12RgbaUnormSrgb,
+        _ => return None,
+    })
+}
+
+fn dxgi_to_wgpu(format: DxgiFormat) -> Option<TextureFormat> {
+    Some(match format {
+        DxgiFormat::R8G8B8A8_UNorm => TextureFormat::Rgba8Unorm,
+        DxgiFormat::R8G8B8A8_UNorm_sRGB => TextureFormat::Rgba8UnormSrgb,
+        DxgiFormat::B8G8R8A8_UNorm => TextureFormat::Bgra8Unorm,
+        DxgiFormat::B8G8R8A8_UNorm_sRGB => TextureFormat::Bgra8UnormSrgb,
+        DxgiFormat::BC1_UNorm => TextureFormat::Bc1RgbaUnorm,
+        DxgiFormat::BC1_UNorm_sRGB => TextureFormat::Bc1RgbaUnormSrgb,
+        DxgiFormat::BC2_UNorm => TextureFormat::Bc2RgbaUnorm,
+        DxgiFormat::BC2_UNorm_sRGB => TextureFormat::Bc2RgbaUnormSrgb,
+        DxgiFormat::BC3_UNorm => TextureFormat::Bc3RgbaUnorm,
+        DxgiFormat::BC3_UNorm_sRGB => TextureFormat::Bc3RgbaUnormSrgb,
+        DxgiFormat::BC4_UNorm => TextureFormat::Bc4RUnorm,
+        DxgiFormat::BC4_SNorm => TextureFormat::Bc4RSnorm,
+        DxgiFormat::BC5_UNorm => TextureFormat::Bc5RgUnorm,
+        DxgiFormat::BC5_SNorm => TextureFormat::Bc5RgSnorm,
+        DxgiFormat::BC6H_UF16 => TextureFormat::Bc6hRgbUfloat,
+        DxgiFormat::BC6H_SF16 => TextureFormat::Bc6hRgbSfloat,
+        DxgiFormat::BC7_UNorm => TextureFormat::Bc7RgbaUnorm,
+        DxgiFormat::BC7_UNorm_sRGB => TextureFormat::Bc7RgbaUnormSrgb,
+        _ => return None,
+    })
+}
+
+fn d3d_to_wgpu(format: D3DFormat) -> Option<TextureFormat> {
+    Some(match format {
+        D3DFormat::A8B8G8R8 => TextureFormat::Rgba8Unorm,
+        D3DFormat::A8R8G8B8 => TextureFormat::Bgra8Unorm,
+        D3DFormat::DXT1 => TextureFormat::Bc1RgbaUnorm,
+        // DXT2 and DXT4 are premultiplied, which we don't do anything about
+        D3DFormat::DXT2 | D3DFormat::DXT3 => TextureFormat::Bc2RgbaUnorm,
+        D3DFormat::DXT4 | D3DFormat::DXT5 => TextureFormat::Bc3RgbaUnorm,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid(format: TextureFormat, block: &[u8]) -> CompressedImage {
+        CompressedImage::new(4, 4, format, vec![block.to_vec()]).unwrap()
+    }
+
+    #[test]
+    fn decompresses_into_bgra() {
+        // Both endpoints pure red, every index picking the first
+        let bc1 = [0x00, 0xF8, 0x00, 0xF8, 0, 0, 0, 0];
+        let image = solid(TextureFormat::Bc1RgbaUnormSrgb, &bc1)
+            .decompress()
+            .unwrap();
+        assert_eq!(image.format, TextureFormat::Bgra8UnormSrgb);
+        assert_eq!(image.levels[0].len(), 4 * 4 * 4);
+        for texel in image.levels[0].chunks_exact(4) {
+            assert_eq!(texel, [0, 0, 255, 255]);
+        }
+    }
+
+    #[test]
+    fn decompresses_astc() {
+        // A void extent block, which is one color all over
+        let astc = [
+            0xFC, 0xFD, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0xFF, 0xFF, 0x00, 0x00,
+            0xFF, 0xFF,
+        ];
+        let image = solid(TextureFormat::Astc4x4RgbaUnorm, &astc)
+            .decompress()
+            .unwrap();
+        assert_eq!(image.format, TextureFormat::Bgra8Unorm);
+        for texel in image.levels[0].chunks_exact(4) {
+            assert_eq!(texel, [0, 255, 0, 255]);
+        }
+    }
+
+    #[test]
+    fn signed_formats_are_rejected() {
+        let image = solid(TextureFormat::Bc4RSnorm, &[0; 8]);
+        assert!(image.decompress().is_err());
+    }
+}
@@ -42,6 +42,14 @@ impl CameraController {
         }
     }
 
+    pub fn speed_mut(&mut self) -> &mut f32 {
+        &mut self.speed
+    }
+
+    pub fn sensitivity_mut(&mut self) -> &mut f32 {
+        &mut self.sensitivity
+    }
+
     pub fn process_keyboard(&mut self, key: VirtualKeyCode, state: ElementState) -> bool {
         let amount = if state == ElementState::Pressed {
             1.0
@@ -0,0 +1,462 @@
+use std::ops::Range;
+
+use wgpu::{
+    BindGroup, BindGroupLayout, Buffer, CommandEncoder, Device, Queue, RenderPipeline,
+    SurfaceConfiguration, TextureFormat, TextureView,
+};
+
+use crate::{
+    assets::Assets,
+    model::{Model, ModelVertex, SkinVertex, Vertex, VertexEncoding},
+    post::HDR_FORMAT,
+    render::{create_render_pipeline, draw_posed_model, AnimatedModel, InstanceRaw, LightUniform},
+    skin::Skin,
+    texture::Texture,
+};
+
+/// How many lights the lighting pass will look at, anything past this is
+/// dropped when the light list gets uploaded.
+pub const MAX_LIGHTS: usize = 64;
+
+const ALBEDO_FORMAT: TextureFormat = TextureFormat::Rgba8UnormSrgb;
+const NORMAL_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+const MATERIAL_FORMAT: TextureFormat = TextureFormat::Rgba8Unorm;
+// Emission isn't limited to 0..1
+const EMISSION_FORMAT: TextureFormat = HDR_FORMAT;
+
+/// Matches the header of the `Lights` storage buffer in deferred_lighting.wgsl,
+/// the light array starts right after it.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct LightsHeader {
+    count: u32,
+    _padding: [u32; 3],
+}
+
+/// The screen sized targets the geometry pass writes into. Depth lives in
+/// `Render` since the forward path wants it too.
+#[derive(Debug)]
+pub struct GBuffer {
+    pub albedo: Texture,
+    pub normal: Texture,
+    pub material: Texture,
+    pub emission: Texture,
+}
+
+#[derive(Debug)]
+pub struct DeferredRenderer {
+    gbuffer: GBuffer,
+    gbuffer_pipeline: RenderPipeline,
+    skinned_gbuffer_pipeline: RenderPipeline,
+
+    lighting_pipeline: RenderPipeline,
+    gbuffer_bind_group_layout: BindGroupLayout,
+    gbuffer_bind_group: BindGroup,
+
+    lights_buffer: Buffer,
+    lights_bind_group: BindGroup,
+    /// How many lights the last update had to leave out, so we only warn
+    /// when it changes rather than every frame.
+    dropped_lights: usize,
+}
+
+impl GBuffer {
+    pub fn new(device: &Device, config: &SurfaceConfiguration) -> Self {
+        let (width, height) = (config.width, config.height);
+        Self {
+            albedo: Texture::create_render_target(
+                device,
+                width,
+                height,
+                ALBEDO_FORMAT,
+                "gbuffer_albedo",
+            ),
+            normal: Texture::create_render_target(
+                device,
+                width,
+                height,
+                NORMAL_FORMAT,
+                "gbuffer_normal",
+            ),
+            material: Texture::create_render_target(
+                device,
+                width,
+                height,
+                MATERIAL_FORMAT,
+                "gbuffer_material",
+            ),
+            emission: Texture::create_render_target(
+                device,
+                width,
+                height,
+                EMISSION_FORMAT,
+                "gbuffer_emission",
+            ),
+        }
+    }
+}
+
+impl DeferredRenderer {
+    pub fn new(
+        device: &Device,
+        config: &SurfaceConfiguration,
+        texture_bind_group_layout: &BindGroupLayout,
+        camera_bind_group_layout: &BindGroupLayout,
+        light_bind_group_layout: &BindGroupLayout,
+        mesh_bind_group_layout: Option<&BindGroupLayout>,
+        skin_bind_group_layout: &BindGroupLayout,
+        vertices: VertexEncoding,
+        depth_texture: &Texture,
+    ) -> Self {
+        let gbuffer = GBuffer::new(device, config);
+
+        let gbuffer_pipeline = {
+            let mut bind_group_layouts = vec![
+                texture_bind_group_layout,
+                camera_bind_group_layout,
+                light_bind_group_layout,
+            ];
+            bind_group_layouts.extend(mesh_bind_group_layout);
+            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("GBuffer Pipeline Layout"),
+                bind_group_layouts: &bind_group_layouts,
+                push_constant_ranges: &[],
+            });
+
+            create_render_pipeline(
+                "gbuffer_pipeline",
+                device,
+                &layout,
+                &[
+                    ALBEDO_FORMAT,
+                    NORMAL_FORMAT,
+                    MATERIAL_FORMAT,
+                    EMISSION_FORMAT,
+                ],
+                Some(Texture::DEPTH_FORMAT),
+                &[vertices.desc(), InstanceRaw::desc()],
+                vertices.entry_point(),
+                "main",
+                wgpu::include_wgsl!("../shaders/wgsl/gbuffer.wgsl"),
+                None,
+            )
+        };
+
+        let skinned_gbuffer_pipeline = {
+            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Skinned GBuffer Pipeline Layout"),
+                bind_group_layouts: &[
+                    texture_bind_group_layout,
+                    camera_bind_group_layout,
+                    light_bind_group_layout,
+                    skin_bind_group_layout,
+                ],
+                push_constant_ranges: &[],
+            });
+
+            create_render_pipeline(
+                "skinned_gbuffer_pipeline",
+                device,
+                &layout,
+                &[
+                    ALBEDO_FORMAT,
+                    NORMAL_FORMAT,
+                    MATERIAL_FORMAT,
+                    EMISSION_FORMAT,
+                ],
+                Some(Texture::DEPTH_FORMAT),
+                &[ModelVertex::desc(), InstanceRaw::desc(), SkinVertex::desc()],
+                "main_skinned",
+                "main",
+                wgpu::include_wgsl!("../shaders/wgsl/gbuffer.wgsl"),
+                None,
+            )
+        };
+
+        let gbuffer_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    gbuffer_texture_entry(0, wgpu::TextureSampleType::Float { filterable: false }),
+                    gbuffer_texture_entry(1, wgpu::TextureSampleType::Float { filterable: false }),
+                    gbuffer_texture_entry(2, wgpu::TextureSampleType::Float { filterable: false }),
+                    gbuffer_texture_entry(3, wgpu::TextureSampleType::Depth),
+                    gbuffer_texture_entry(4, wgpu::TextureSampleType::Float { filterable: false }),
+                ],
+                label: Some("gbuffer_bind_group_layout"),
+            });
+        let gbuffer_bind_group =
+            create_gbuffer_bind_group(device, &gbuffer_bind_group_layout, &gbuffer, depth_texture);
+
+        let lights_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Lights Buffer"),
+            size: (std::mem::size_of::<LightsHeader>()
+                + MAX_LIGHTS * std::mem::size_of::<LightUniform>())
+                as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let lights_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+                label: Some("lights_bind_group_layout"),
+            });
+        let lights_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &lights_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: lights_buffer.as_entire_binding(),
+            }],
+            label: Some("lights_bind_group"),
+        });
+
+        let lighting_pipeline = {
+            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Lighting Pipeline Layout"),
+                bind_group_layouts: &[
+                    &gbuffer_bind_group_layout,
+                    camera_bind_group_layout,
+                    &lights_bind_group_layout,
+                ],
+                push_constant_ranges: &[],
+            });
+
+            create_render_pipeline(
+                "lighting_pipeline",
+                device,
+                &layout,
+                &[HDR_FORMAT],
+                None,
+                &[],
+                "main",
+                "main",
+                wgpu::include_wgsl!("../shaders/wgsl/deferred_lighting.wgsl"),
+                None,
+            )
+        };
+
+        Self {
+            gbuffer,
+            gbuffer_pipeline,
+            skinned_gbuffer_pipeline,
+            lighting_pipeline,
+            gbuffer_bind_group_layout,
+            gbuffer_bind_group,
+            lights_buffer,
+            lights_bind_group,
+            dropped_lights: 0,
+        }
+    }
+
+    pub fn resize(&mut self, device: &Device, config: &SurfaceConfiguration, depth: &Texture) {
+        self.gbuffer = GBuffer::new(device, config);
+        self.gbuffer_bind_group = create_gbuffer_bind_group(
+            device,
+            &self.gbuffer_bind_group_layout,
+            &self.gbuffer,
+            depth,
+        );
+    }
+
+    /// Uploads the lights for the lighting pass. Only the first
+    /// [`MAX_LIGHTS`] fit in the buffer, the rest are left out.
+    pub fn update_lights<'a>(
+        &mut self,
+        queue: &Queue,
+        lights: impl IntoIterator<Item = &'a LightUniform>,
+    ) {
+        let mut lights = lights.into_iter().copied().collect::<Vec<_>>();
+        let dropped = lights.len().saturating_sub(MAX_LIGHTS);
+        if dropped != self.dropped_lights && dropped > 0 {
+            log::warn!(
+                "{} lights is more than the {} the deferred renderer can shade, leaving out {}",
+                lights.len(),
+                MAX_LIGHTS,
+                dropped
+            );
+        }
+        self.dropped_lights = dropped;
+        lights.truncate(MAX_LIGHTS);
+
+        let header = LightsHeader {
+            count: lights.len() as u32,
+            _padding: [0; 3],
+        };
+
+        queue.write_buffer(&self.lights_buffer, 0, bytemuck::cast_slice(&[header]));
+        queue.write_buffer(
+            &self.lights_buffer,
+            std::mem::size_of::<LightsHeader>() as wgpu::BufferAddress,
+            bytemuck::cast_slice(&lights),
+        );
+    }
+
+    /// Fills the G-buffer and the depth texture with the scene geometry.
+    pub fn geometry_pass(
+        &self,
+        encoder: &mut CommandEncoder,
+        depth: &TextureView,
+        model: &Model,
+        skin: Option<&Skin>,
+        assets: &Assets,
+        instance_buffer: &Buffer,
+        instance_lods: &[Range<u32>],
+        animated_models: &[AnimatedModel],
+        camera: &BindGroup,
+        light: &BindGroup,
+    ) {
+        let clear = wgpu::Operations {
+            load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+            store: true,
+        };
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("GBuffer Pass"),
+            color_attachments: &[
+                wgpu::RenderPassColorAttachment {
+                    view: &self.gbuffer.albedo.view,
+                    resolve_target: None,
+                    ops: clear,
+                },
+                wgpu::RenderPassColorAttachment {
+                    view: &self.gbuffer.normal.view,
+                    resolve_target: None,
+                    ops: clear,
+                },
+                wgpu::RenderPassColorAttachment {
+                    view: &self.gbuffer.material.view,
+                    resolve_target: None,
+                    ops: clear,
+                },
+                wgpu::RenderPassColorAttachment {
+                    view: &self.gbuffer.emission.view,
+                    resolve_target: None,
+                    ops: clear,
+                },
+            ],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+
+        for translucent in [false, true] {
+            render_pass.set_vertex_buffer(1, instance_buffer.slice(..));
+            draw_posed_model(
+                &mut render_pass,
+                model,
+                skin,
+                instance_lods,
+                &self.gbuffer_pipeline,
+                &self.skinned_gbuffer_pipeline,
+                translucent,
+                assets,
+                camera,
+                light,
+            );
+
+            for animated in animated_models {
+                animated.draw(
+                    &mut render_pass,
+                    &self.gbuffer_pipeline,
+                    &self.skinned_gbuffer_pipeline,
+                    translucent,
+                    assets,
+                    camera,
+                    light,
+                );
+            }
+        }
+    }
+
+    /// Shades every covered pixel of `view` using the G-buffer and the light list.
+    pub fn lighting_pass(
+        &self,
+        encoder: &mut CommandEncoder,
+        view: &TextureView,
+        clear_color: wgpu::Color,
+        camera: &BindGroup,
+    ) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Lighting Pass"),
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(clear_color),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        render_pass.set_pipeline(&self.lighting_pipeline);
+        render_pass.set_bind_group(0, &self.gbuffer_bind_group, &[]);
+        render_pass.set_bind_group(1, camera, &[]);
+        render_pass.set_bind_group(2, &self.lights_bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+fn gbuffer_texture_entry(
+    binding: u32,
+    sample_type: wgpu::TextureSampleType,
+) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Texture {
+            multisampled: false,
+            view_dimension: wgpu::TextureViewDimension::D2,
+            sample_type,
+        },
+        count: None,
+    }
+}
+
+fn create_gbuffer_bind_group(
+    device: &Device,
+    layout: &BindGroupLayout,
+    gbuffer: &GBuffer,
+    depth: &Texture,
+) -> BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&gbuffer.albedo.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(&gbuffer.normal.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(&gbuffer.material.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::TextureView(&depth.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: wgpu::BindingResource::TextureView(&gbuffer.emission.view),
+            },
+        ],
+        label: Some("gbuffer_bind_group"),
+    })
+}
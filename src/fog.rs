@@ -0,0 +1,99 @@
+use glam::Vec3;
+
+/// How fog builds up with distance from the camera.
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FogMode {
+    Off = 0,
+    /// Ramps from nothing at `start` to full at `end`.
+    Linear = 1,
+    Exponential = 2,
+    ExponentialSquared = 3,
+}
+
+/// Runtime knobs for the fog, tweakable from the UI.
+#[derive(Debug, Clone)]
+pub struct FogSettings {
+    pub mode: FogMode,
+    pub color: Vec3,
+    /// Clear to the fog color so far away geometry fades into the background.
+    pub use_as_clear_color: bool,
+
+    pub start: f32,
+    pub end: f32,
+    /// Used by the exponential modes.
+    pub density: f32,
+
+    /// Fog that pools below `height` and thins out above it, on top of
+    /// whatever `mode` adds.
+    pub height_fog: bool,
+    pub height: f32,
+    pub height_density: f32,
+    pub height_falloff: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct FogUniform {
+    color: Vec3,
+    mode: u32,
+    start: f32,
+    end: f32,
+    density: f32,
+    height: f32,
+    height_density: f32,
+    height_falloff: f32,
+    _padding: [u32; 2],
+}
+
+impl Default for FogSettings {
+    fn default() -> Self {
+        Self {
+            mode: FogMode::Off,
+            color: Vec3::new(0.1, 0.2, 0.3),
+            use_as_clear_color: true,
+            start: 20.0,
+            end: 90.0,
+            density: 0.02,
+            height_fog: false,
+            height: 0.0,
+            height_density: 0.05,
+            height_falloff: 0.5,
+        }
+    }
+}
+
+impl FogSettings {
+    pub(crate) fn to_uniform(&self) -> FogUniform {
+        FogUniform {
+            color: self.color,
+            mode: self.mode as u32,
+            start: self.start,
+            // Keep the linear ramp from dividing by zero
+            end: self.end.max(self.start + 0.001),
+            density: self.density,
+            height: self.height,
+            height_density: if self.height_fog {
+                self.height_density
+            } else {
+                0.0
+            },
+            height_falloff: self.height_falloff.max(0.001),
+            _padding: [0; 2],
+        }
+    }
+
+    /// The fog color as a clear color, when the fog should drive it.
+    pub fn clear_color(&self) -> Option<wgpu::Color> {
+        if !self.use_as_clear_color || (self.mode == FogMode::Off && !self.height_fog) {
+            return None;
+        }
+
+        Some(wgpu::Color {
+            r: self.color.x as f64,
+            g: self.color.y as f64,
+            b: self.color.z as f64,
+            a: 1.0,
+        })
+    }
+}
@@ -0,0 +1,628 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use anyhow::{bail, Context};
+use glam::{Mat3, Mat4, Quat, Vec2, Vec3};
+use gltf::{
+    animation::util::ReadOutputs, buffer, material::AlphaMode, mesh::Mode, Document, Gltf, Node,
+};
+
+use crate::{
+    animation::{Interpolate, Interpolation, Keyframes, Transform},
+    model::{
+        compute_tangents, generate_normals, MaterialData, MaterialFactors, MaterialTextures,
+        MeshData, ModelData, ModelVertex, SkinVertex, TextureData, TextureUsage,
+    },
+    skin::{Joint, JointChannel, JointProperty, SkeletalAnimation, Skeleton},
+    source::AssetSource,
+    texture::ImageData,
+};
+
+/// Loads a `.gltf` or `.glb`.
+///
+/// `Model` has no hierarchy, so the node tree gets flattened: every
+/// primitive becomes its own mesh with its node's transform baked into the
+/// vertices. The exception is skinned primitives, which the skeleton
+/// places instead.
+///
+/// Only the first skin is loaded, along with the animations that move its
+/// joints. Meshes bound to any other skin come out unskinned.
+pub fn load(source: &AssetSource, path: &Path) -> anyhow::Result<ModelData> {
+    // External buffers and images are relative to the glTF file
+    let containing_folder = path.parent().context("Directory has no parent")?;
+
+    let bytes = source.read(path)?;
+    let mut gltf = Gltf::from_slice(&bytes)?;
+
+    let mut sources = vec![path.to_path_buf()];
+    sources.extend(gltf.buffers().filter_map(|buffer| match buffer.source() {
+        buffer::Source::Uri(uri) if !uri.starts_with("data:") => {
+            Some(containing_folder.join(percent_decode(uri)))
+        }
+        _ => None,
+    }));
+
+    let mut blob = gltf.blob.take();
+    let buffers = gltf
+        .buffers()
+        .map(|buffer| match buffer.source() {
+            buffer::Source::Bin => blob.take().context("GLB is missing its binary chunk"),
+            buffer::Source::Uri(uri) => read_uri(source, containing_folder, uri),
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut materials = gltf
+        .materials()
+        .map(|material| {
+            let texture = |texture: Option<gltf::Texture>, usage: TextureUsage| match texture {
+                Some(texture) => texture_data(source, containing_folder, &buffers, texture),
+                None => Ok(TextureData::Solid(usage.fallback())),
+            };
+
+            // The metallic/roughness and occlusion maps don't have anywhere
+            // to go in our materials
+            let pbr = material.pbr_metallic_roughness();
+            let textures = MaterialTextures {
+                diffuse: texture(
+                    pbr.base_color_texture().map(|info| info.texture()),
+                    TextureUsage::Color,
+                )?,
+                normal: texture(
+                    material.normal_texture().map(|normal| normal.texture()),
+                    TextureUsage::Normal,
+                )?,
+                emissive: texture(
+                    material.emissive_texture().map(|info| info.texture()),
+                    TextureUsage::Color,
+                )?,
+                ..MaterialTextures::solid()
+            };
+
+            Ok(MaterialData {
+                name: material.name().unwrap_or("glTF material").to_string(),
+                textures,
+                factors: factors(&material),
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    // Only added if some primitive doesn't have a material
+    let mut default_material = None;
+
+    let skin = gltf.skins().next();
+    let (skeleton, joint_for_node) = match &skin {
+        Some(skin) => {
+            let (skeleton, joint_for_node) = load_skeleton(&gltf, skin, &buffers)?;
+            (Some(skeleton), joint_for_node)
+        }
+        None => (None, HashMap::new()),
+    };
+    // Skins list their joints in any order, the skeleton has parents first
+    let joint_remap = skin
+        .iter()
+        .flat_map(|skin| skin.joints())
+        .map(|node| joint_for_node[&node.index()] as u16)
+        .collect::<Vec<_>>();
+
+    let mut animations = Vec::new();
+    for animation in gltf.animations() {
+        let animation = load_animation(animation, &buffers, &joint_for_node)?;
+        // Ones that only move other nodes don't have anything to animate here
+        if !animation.channels.is_empty() {
+            animations.push(animation);
+        }
+    }
+
+    let scene = gltf
+        .default_scene()
+        .or_else(|| gltf.scenes().next())
+        .context("glTF file has no scenes")?;
+
+    let mut meshes = Vec::new();
+    let mut nodes = scene
+        .nodes()
+        .map(|node| (node, Mat4::IDENTITY))
+        .collect::<Vec<(Node, Mat4)>>();
+    while let Some((node, parent_transform)) = nodes.pop() {
+        let transform = parent_transform * Mat4::from_cols_array_2d(&node.transform().matrix());
+        nodes.extend(node.children().map(|child| (child, transform)));
+
+        let mesh = match node.mesh() {
+            Some(mesh) => mesh,
+            None => continue,
+        };
+
+        let skinned = match node.skin() {
+            Some(skin) if skin.index() == 0 => true,
+            Some(_) => {
+                log::warn!(
+                    "Only the first skin is supported, {} won't be animated",
+                    mesh.name().unwrap_or("glTF mesh")
+                );
+                false
+            }
+            None => false,
+        };
+        // The joints put skinned meshes in place, their node doesn't move
+        // them at all
+        let transform = if skinned { Mat4::IDENTITY } else { transform };
+
+        let normal_matrix = Mat3::from_mat4(transform).inverse().transpose();
+        let tangent_matrix = Mat3::from_mat4(transform);
+
+        for (i, primitive) in mesh.primitives().enumerate() {
+            let name = format!("{}.{}", mesh.name().unwrap_or("glTF mesh"), i);
+            if primitive.mode() != Mode::Triangles {
+                log::warn!("Skipping {}, only triangles are supported", name);
+                continue;
+            }
+
+            let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+
+            let mut vertices = reader
+                .read_positions()
+                .with_context(|| format!("{} has no positions", name))?
+                .map(|position| ModelVertex {
+                    position: transform.transform_point3(position.into()),
+                    tex_coords: Vec2::ZERO,
+                    normal: Vec3::ZERO,
+                    tangent: [0.0; 4],
+                    layer: 0,
+                })
+                .collect::<Vec<_>>();
+
+            let has_normals = match reader.read_normals() {
+                Some(normals) => {
+                    for (vertex, normal) in vertices.iter_mut().zip(normals) {
+                        vertex.normal = (normal_matrix * Vec3::from(normal)).normalize();
+                    }
+                    true
+                }
+                None => false,
+            };
+
+            if let Some(tex_coords) = reader.read_tex_coords(0) {
+                for (vertex, tex_coords) in vertices.iter_mut().zip(tex_coords.into_f32()) {
+                    vertex.tex_coords = tex_coords.into();
+                }
+            }
+
+            let mut skin = if skinned {
+                let joints = reader
+                    .read_joints(0)
+                    .with_context(|| format!("{} is skinned but has no joints", name))?;
+                let weights = reader
+                    .read_weights(0)
+                    .with_context(|| format!("{} is skinned but has no weights", name))?;
+                let skin = joints
+                    .into_u16()
+                    .zip(weights.into_f32())
+                    .map(|(joints, weights)| skin_vertex(joints, weights, &joint_remap))
+                    .collect::<anyhow::Result<Vec<_>>>()
+                    .with_context(|| format!("{} has a bad skin", name))?;
+                if skin.len() != vertices.len() {
+                    bail!(
+                        "{} has {} joints and weights for {} vertices",
+                        name,
+                        skin.len(),
+                        vertices.len()
+                    );
+                }
+                Some(skin)
+            } else {
+                None
+            };
+
+            let mut indices = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect::<Vec<_>>(),
+                None => (0..vertices.len() as u32).collect(),
+            };
+            if indices.len() % 3 != 0 {
+                bail!(
+                    "{} has {} indices, which isn't whole triangles",
+                    name,
+                    indices.len()
+                );
+            }
+            if let Some(index) = indices
+                .iter()
+                .find(|&&index| index as usize >= vertices.len())
+            {
+                bail!(
+                    "{} has index {} but only {} vertices",
+                    name,
+                    index,
+                    vertices.len()
+                );
+            }
+
+            // A mirrored transform flips the winding, which would get the
+            // wrong faces culled
+            if transform.determinant() < 0.0 {
+                for triangle in indices.chunks_mut(3) {
+                    triangle.swap(1, 2);
+                }
+            }
+
+            // The spec says to ignore the tangents when the normals are
+            // missing, and generating normals reorders the vertices anyway
+            if !has_normals {
+                generate_normals(&mut vertices, &mut indices, skin.as_mut());
+            }
+
+            match reader.read_tangents().filter(|_| has_normals) {
+                Some(tangents) => {
+                    for (vertex, tangent) in vertices.iter_mut().zip(tangents) {
+                        let direction = (tangent_matrix
+                            * Vec3::new(tangent[0], tangent[1], tangent[2]))
+                        .normalize();
+                        // Mirroring flips which way the bitangent points
+                        let handedness = tangent[3] * transform.determinant().signum();
+                        vertex.tangent = [direction.x, direction.y, direction.z, handedness];
+                    }
+                }
+                None => compute_tangents(&mut vertices, &mut indices, skin.as_mut()),
+            }
+
+            let material = match primitive.material().index() {
+                Some(index) => index,
+                None => *default_material.get_or_insert_with(|| {
+                    materials.push(MaterialData::untextured("glTF default material"));
+                    materials.len() - 1
+                }),
+            };
+
+            meshes.push(MeshData {
+                name,
+                vertices,
+                indices,
+                lods: Vec::new(),
+                material,
+                skin,
+            });
+        }
+    }
+
+    Ok(ModelData {
+        path: path.to_path_buf(),
+        sources,
+        meshes,
+        materials,
+        skeleton,
+        animations,
+    })
+}
+
+/// Builds the skeleton for `skin`, along with which of its joints each of
+/// the skin's nodes became.
+///
+/// Nodes in between joints that aren't joints themselves go in as joints
+/// that nothing is weighted to, so their transforms and animations still
+/// carry through to the joints below them.
+fn load_skeleton(
+    document: &Document,
+    skin: &gltf::Skin,
+    buffers: &[Vec<u8>],
+) -> anyhow::Result<(Skeleton, HashMap<usize, usize>)> {
+    let nodes = document.nodes().collect::<Vec<_>>();
+    let mut parents = HashMap::new();
+    for node in &nodes {
+        for child in node.children() {
+            parents.insert(child.index(), node.index());
+        }
+    }
+
+    let skin_joints = skin.joints().collect::<Vec<_>>();
+    let reader = skin.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+    let inverse_binds = match reader.read_inverse_bind_matrices() {
+        Some(matrices) => matrices
+            .map(|matrix| Mat4::from_cols_array_2d(&matrix))
+            .collect::<Vec<_>>(),
+        None => vec![Mat4::IDENTITY; skin_joints.len()],
+    };
+    if inverse_binds.len() < skin_joints.len() {
+        bail!(
+            "Skin has {} inverse bind matrices for {} joints",
+            inverse_binds.len(),
+            skin_joints.len()
+        );
+    }
+    let inverse_bind_for_node = skin_joints
+        .iter()
+        .map(|node| node.index())
+        .zip(inverse_binds)
+        .collect::<HashMap<_, _>>();
+
+    // Walking up from each joint to the next one finds the nodes in between,
+    // whatever is above a root joint only places it
+    let mut in_skeleton = inverse_bind_for_node
+        .keys()
+        .copied()
+        .collect::<HashSet<_>>();
+    for node in skin_joints.iter().map(|node| node.index()) {
+        let mut between = Vec::new();
+        let mut ancestor = node;
+        while let Some(&parent) = parents.get(&ancestor) {
+            if inverse_bind_for_node.contains_key(&parent) {
+                in_skeleton.extend(between.drain(..));
+                break;
+            }
+            between.push(parent);
+            ancestor = parent;
+        }
+    }
+    if in_skeleton.len() > u16::MAX as usize + 1 {
+        bail!(
+            "Skin has {} joints, more than we can index",
+            in_skeleton.len()
+        );
+    }
+
+    // Sorting by depth puts every parent before its children
+    let depth = |mut node: usize| {
+        let mut depth = 0;
+        while let Some(&parent) = parents.get(&node) {
+            depth += 1;
+            node = parent;
+        }
+        depth
+    };
+    let mut order = in_skeleton.iter().copied().collect::<Vec<_>>();
+    order.sort_by_key(|&node| (depth(node), node));
+
+    let mut joint_for_node = HashMap::new();
+    let mut joints = Vec::with_capacity(order.len());
+    for node in order {
+        let parent = parents.get(&node).copied();
+        let parent_joint = parent.and_then(|parent| joint_for_node.get(&parent).copied());
+        joint_for_node.insert(node, joints.len());
+        joints.push(Joint {
+            parent: parent_joint,
+            rest: local_transform(&nodes[node]),
+            inverse_bind: inverse_bind_for_node
+                .get(&node)
+                .copied()
+                .unwrap_or(Mat4::IDENTITY),
+            root: match (parent, parent_joint) {
+                (Some(parent), None) => world_transform(&nodes, &parents, parent),
+                _ => Mat4::IDENTITY,
+            },
+        });
+    }
+
+    Ok((Skeleton { joints }, joint_for_node))
+}
+
+fn local_transform(node: &Node) -> Transform {
+    let (translation, [x, y, z, w], scale) = node.transform().decomposed();
+    Transform {
+        translation: translation.into(),
+        rotation: Quat::from_xyzw(x, y, z, w),
+        scale: scale.into(),
+    }
+}
+
+/// Where node `index` is relative to the scene.
+fn world_transform(nodes: &[Node], parents: &HashMap<usize, usize>, mut index: usize) -> Mat4 {
+    let mut transform = local_transform(&nodes[index]).to_matrix();
+    while let Some(&parent) = parents.get(&index) {
+        transform = local_transform(&nodes[parent]).to_matrix() * transform;
+        index = parent;
+    }
+    transform
+}
+
+/// Points the joints at the skeleton rather than the skin, and makes sure
+/// the weights add up to one, which quantized weights only roughly do.
+fn skin_vertex(
+    joints: [u16; 4],
+    weights: [f32; 4],
+    joint_remap: &[u16],
+) -> anyhow::Result<SkinVertex> {
+    let total = weights.iter().sum::<f32>();
+    let mut vertex = SkinVertex::default();
+    for i in 0..4 {
+        vertex.joints[i] = *joint_remap
+            .get(joints[i] as usize)
+            .with_context(|| format!("Joint {} isn't in the skin", joints[i]))?;
+        vertex.weights[i] = if total > 0.0 { weights[i] / total } else { 0.0 };
+    }
+    // Weightless vertices would collapse to the origin
+    if total <= 0.0 {
+        vertex.weights[0] = 1.0;
+    }
+    Ok(vertex)
+}
+
+/// Keeps the channels that move one of the skeleton's joints.
+fn load_animation(
+    animation: gltf::Animation,
+    buffers: &[Vec<u8>],
+    joint_for_node: &HashMap<usize, usize>,
+) -> anyhow::Result<SkeletalAnimation> {
+    let name = animation
+        .name()
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("glTF animation {}", animation.index()));
+
+    let mut channels = Vec::new();
+    for channel in animation.channels() {
+        let joint = match joint_for_node.get(&channel.target().node().index()) {
+            Some(&joint) => joint,
+            None => continue,
+        };
+        let property = load_channel(&channel, buffers)
+            .with_context(|| format!("{} has a bad channel", name))?;
+        channels.extend(property.map(|property| JointChannel { joint, property }));
+    }
+
+    let duration = channels
+        .iter()
+        .map(|channel| channel.property.duration())
+        .fold(0.0, f32::max);
+    Ok(SkeletalAnimation {
+        name,
+        duration,
+        channels,
+    })
+}
+
+/// `None` for morph target weights, which we don't support.
+fn load_channel(
+    channel: &gltf::animation::Channel,
+    buffers: &[Vec<u8>],
+) -> anyhow::Result<Option<JointProperty>> {
+    let reader = channel.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+    let times = reader
+        .read_inputs()
+        .context("Missing keyframe times")?
+        .collect::<Vec<_>>();
+    let interpolation = channel.sampler().interpolation();
+
+    Ok(Some(
+        match reader.read_outputs().context("Missing keyframe values")? {
+            ReadOutputs::Translations(values) => {
+                JointProperty::Translation(keyframes(times, values.map(Vec3::from), interpolation)?)
+            }
+            ReadOutputs::Rotations(values) => JointProperty::Rotation(keyframes(
+                times,
+                values
+                    .into_f32()
+                    .map(|[x, y, z, w]| Quat::from_xyzw(x, y, z, w).normalize()),
+                interpolation,
+            )?),
+            ReadOutputs::Scales(values) => {
+                JointProperty::Scale(keyframes(times, values.map(Vec3::from), interpolation)?)
+            }
+            ReadOutputs::MorphTargetWeights(_) => return Ok(None),
+        },
+    ))
+}
+
+/// Cubic spline keyframes come as an in tangent, a value and an out
+/// tangent each. We only keep the values and go between them linearly.
+fn keyframes<T: Interpolate>(
+    times: Vec<f32>,
+    values: impl Iterator<Item = T>,
+    interpolation: gltf::animation::Interpolation,
+) -> anyhow::Result<Keyframes<T>> {
+    use gltf::animation::Interpolation as GltfInterpolation;
+
+    let values = values.collect::<Vec<_>>();
+    match interpolation {
+        GltfInterpolation::Step => Keyframes::new(times, values, Interpolation::Step),
+        GltfInterpolation::Linear => Keyframes::new(times, values, Interpolation::Linear),
+        GltfInterpolation::CubicSpline => {
+            let values = values.chunks_exact(3).map(|tangents| tangents[1]).collect();
+            Keyframes::new(times, values, Interpolation::Linear)
+        }
+    }
+}
+
+fn texture_data(
+    source: &AssetSource,
+    containing_folder: &Path,
+    buffers: &[Vec<u8>],
+    texture: gltf::Texture,
+) -> anyhow::Result<TextureData> {
+    let gltf_image = texture.source();
+    let label = gltf_image
+        .name()
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("glTF image {}", gltf_image.index()));
+
+    let bytes = match gltf_image.source() {
+        gltf::image::Source::View { view, .. } => {
+            let range = view.offset()..view.offset() + view.length();
+            buffers
+                .get(view.buffer().index())
+                .and_then(|buffer| buffer.get(range))
+                .with_context(|| format!("{} is outside its buffer", label))?
+                .to_vec()
+        }
+        gltf::image::Source::Uri { uri, .. } if uri.starts_with("data:") => {
+            read_uri(source, containing_folder, uri)?
+        }
+        // Plain files go through the asset cache so they're shared with
+        // everything else that uses them
+        gltf::image::Source::Uri { uri, .. } => {
+            return Ok(TextureData::File(
+                containing_folder.join(percent_decode(uri)),
+            ));
+        }
+    };
+
+    let image =
+        ImageData::from_bytes(&bytes).with_context(|| format!("Failed to decode {}", label))?;
+    Ok(TextureData::Image { label, image })
+}
+
+/// Reads either a base64 `data:` URI or a file next to the glTF.
+fn read_uri(source: &AssetSource, containing_folder: &Path, uri: &str) -> anyhow::Result<Vec<u8>> {
+    if let Some(data) = uri.strip_prefix("data:") {
+        let (_, encoded) = data
+            .split_once(";base64,")
+            .context("Only base64 data URIs are supported")?;
+        return Ok(base64::decode(encoded)?);
+    }
+
+    let bytes = source.read(containing_folder.join(percent_decode(uri)))?;
+    Ok(bytes.into_owned())
+}
+
+/// URIs in glTF files are percent encoded, e.g. spaces come through as `%20`.
+fn percent_decode(uri: &str) -> String {
+    let bytes = uri.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// We shade with Blinn-Phong rather than PBR, so this only approximates the
+/// metallic/roughness model.
+fn factors(material: &gltf::Material) -> MaterialFactors {
+    let pbr = material.pbr_metallic_roughness();
+    let [r, g, b, a] = pbr.base_color_factor();
+    let base_color = Vec3::new(r, g, b);
+    let roughness = pbr.roughness_factor().clamp(0.0, 1.0);
+
+    // Metals tint their highlights, and rough surfaces spread them out
+    // until there's nothing left
+    let specular = Vec3::ONE.lerp(base_color, pbr.metallic_factor()) * (1.0 - roughness);
+    let alpha = (roughness * roughness).max(0.01);
+    let shininess = 2.0 / (alpha * alpha) - 2.0;
+
+    MaterialFactors {
+        diffuse: base_color,
+        specular,
+        shininess,
+        emissive: material.emissive_factor().into(),
+        opacity: match material.alpha_mode() {
+            AlphaMode::Opaque => 1.0,
+            _ => a,
+        },
+        ..MaterialFactors::default()
+    }
+}
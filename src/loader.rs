@@ -0,0 +1,252 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, Sender},
+};
+
+use anyhow::{anyhow, Context};
+
+use crate::{
+    assets::{Assets, Handle},
+    mesh_cache,
+    model::{Model, ModelData, TextureUsage},
+    primitives,
+    source::AssetSource,
+    texture::{ImageData, Texture},
+};
+
+/// Shown in place of a color texture that's still loading. Other textures
+/// use whatever they'd fall back to if they were missing.
+const PLACEHOLDER_COLOR: [u8; 4] = [128, 128, 128, 255];
+
+/// What a worker hands back to the render thread once it's done with the
+/// parts of loading that don't need the GPU.
+enum Loaded {
+    Texture {
+        handle: Handle<Texture>,
+        path: PathBuf,
+        image: ImageData,
+        usage: TextureUsage,
+    },
+    Model {
+        handle: Handle<Model>,
+        data: ModelData,
+    },
+}
+
+/// Loads assets in the background. Parsing and decoding happen on the
+/// runtime's blocking pool, while uploads happen on the render thread in
+/// [`AssetLoader::finish`].
+///
+/// Until then handles point at placeholders: a plain cube, or a 1x1
+/// texture.
+pub struct AssetLoader {
+    runtime: tokio::runtime::Handle,
+    sender: Sender<anyhow::Result<Loaded>>,
+    receiver: Receiver<anyhow::Result<Loaded>>,
+    pending: usize,
+}
+
+impl AssetLoader {
+    pub fn new(runtime: tokio::runtime::Handle) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            runtime,
+            sender,
+            receiver,
+            pending: 0,
+        }
+    }
+
+    /// How many loads haven't been finished yet.
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    pub fn load_texture(
+        &mut self,
+        assets: &mut Assets,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: impl AsRef<Path>,
+        usage: TextureUsage,
+    ) -> Handle<Texture> {
+        if let Some(handle) = assets.find_texture(&path, usage) {
+            return handle;
+        }
+
+        let color = match usage {
+            TextureUsage::Color => PLACEHOLDER_COLOR,
+            _ => usage.fallback(),
+        };
+        let placeholder = Texture::solid(device, queue, color, "placeholder", usage.is_linear());
+        let handle = assets.add_texture_at(&path, usage, placeholder);
+
+        let source = assets.source().clone();
+        self.spawn_texture(source, handle.clone(), path.as_ref().to_path_buf(), usage);
+        handle
+    }
+
+    pub fn load_model(
+        &mut self,
+        assets: &mut Assets,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        path: impl AsRef<Path>,
+    ) -> Handle<Model> {
+        if let Some(handle) = assets.find_model(&path) {
+            return handle;
+        }
+
+        let data = ModelData::from_meshes("placeholder", vec![primitives::cube(1.0)]);
+        // Its only material is a solid color, so there's no texture to fail on
+        let placeholder = Model::from_data(device, queue, layout, assets, data, |_, path, _| {
+            Err(anyhow!("The placeholder has no texture {:?}", path))
+        })
+        .expect("Failed to build the placeholder model");
+        let handle = assets.add_model_at(&path, placeholder);
+
+        let source = assets.source().clone();
+        self.spawn_model(source, handle.clone(), path.as_ref().to_path_buf());
+        handle
+    }
+
+    /// Loads `path` again if anything was loaded from it, swapping the new
+    /// version in behind the existing handles once it's ready.
+    pub fn reload(&mut self, assets: &Assets, path: impl AsRef<Path>) {
+        let path = path.as_ref();
+        let source = assets.source();
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+
+        if matches!(extension.as_deref(), Some("mtl") | Some("bin")) {
+            // We don't keep track of which OBJ pulled in which MTL, or which
+            // glTF pulled in which buffer, so reload every model sitting
+            // next to it
+            if let Some(dir) = path.parent() {
+                for (handle, model_path) in assets.models_in(dir) {
+                    log::info!("Reloading {:?}", model_path);
+                    mesh_cache::invalidate(source, &model_path);
+                    self.spawn_model(source.clone(), handle, model_path);
+                }
+            }
+        } else if let Some(handle) = assets.find_model(path) {
+            log::info!("Reloading {:?}", path);
+            mesh_cache::invalidate(source, path);
+            self.spawn_model(source.clone(), handle, path.to_path_buf());
+        } else {
+            // It might have been loaded both as a color and as data
+            for usage in [TextureUsage::Color, TextureUsage::Data] {
+                if let Some(handle) = assets.find_texture(path, usage) {
+                    log::info!("Reloading {:?}", path);
+                    self.spawn_texture(source.clone(), handle, path.to_path_buf(), usage);
+                }
+            }
+        }
+    }
+
+    /// Uploads everything the workers have finished since the last call.
+    /// Failed loads are logged and keep their placeholder.
+    pub fn finish(
+        &mut self,
+        assets: &mut Assets,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+    ) {
+        while let Ok(result) = self.receiver.try_recv() {
+            self.pending -= 1;
+
+            let result = match result {
+                Ok(Loaded::Texture {
+                    handle,
+                    path,
+                    image,
+                    usage,
+                }) => assets
+                    .create_texture(device, queue, &image, &path.to_string_lossy(), usage)
+                    .map(|texture| assets.replace_texture(device, layout, &handle, texture)),
+                Ok(Loaded::Model { handle, data }) => {
+                    // The model's textures get loaded in the background too,
+                    // its materials get rebuilt as each one comes in
+                    Model::from_data(
+                        device,
+                        queue,
+                        layout,
+                        assets,
+                        data,
+                        |assets, path, usage| {
+                            Ok(self.load_texture(assets, device, queue, path, usage))
+                        },
+                    )
+                    .map(|model| assets.replace_model(&handle, model))
+                }
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = result {
+                log::error!("{:?}", e);
+            }
+        }
+    }
+
+    fn spawn_texture(
+        &mut self,
+        source: AssetSource,
+        handle: Handle<Texture>,
+        path: PathBuf,
+        usage: TextureUsage,
+    ) {
+        self.spawn(move || {
+            let image = source
+                .read(&path)
+                .and_then(|bytes| ImageData::from_bytes(&bytes))
+                .with_context(|| format!("Failed to load texture {:?}", path))?;
+            Ok(Loaded::Texture {
+                handle,
+                path,
+                image,
+                usage,
+            })
+        });
+    }
+
+    fn spawn_model(&mut self, source: AssetSource, handle: Handle<Model>, path: PathBuf) {
+        self.spawn(move || {
+            let data = ModelData::load(&source, &path)
+                .with_context(|| format!("Failed to load model {:?}", path))?;
+            Ok(Loaded::Model { handle, data })
+        });
+    }
+
+    fn spawn(&mut self, load: impl FnOnce() -> anyhow::Result<Loaded> + Send + 'static) {
+        self.pending += 1;
+
+        let reply = Reply(Some(self.sender.clone()));
+        self.runtime.spawn_blocking(move || reply.send(load()));
+    }
+}
+
+/// Sends a worker's result back to the render thread. If the worker panics
+/// before it gets the chance, an error is sent in its place when this is
+/// dropped, so [`AssetLoader::pending`] still counts down.
+struct Reply(Option<Sender<anyhow::Result<Loaded>>>);
+
+impl Reply {
+    fn send(mut self, result: anyhow::Result<Loaded>) {
+        if let Some(sender) = self.0.take() {
+            // If the loader is gone there's nobody left to upload this
+            let _ = sender.send(result);
+        }
+    }
+}
+
+impl Drop for Reply {
+    fn drop(&mut self) {
+        if let Some(sender) = self.0.take() {
+            let _ = sender.send(Err(anyhow!("A loading task panicked")));
+        }
+    }
+}
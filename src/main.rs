@@ -1,7 +1,14 @@
-use std::time::{Duration, Instant};
+use std::{
+    f32::consts::TAU,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
+use animation::{
+    AnimationClip, AnimationPlayer, Interpolation, Keyframes, LoopMode, Property, Target, Track,
+};
 use controller::CameraController;
-use glam::{vec3, Quat};
+use glam::{vec3, Quat, Vec3};
 use tokio::runtime::Runtime;
 use winit::{
     dpi::PhysicalSize,
@@ -10,24 +17,67 @@ use winit::{
     window::{Window, WindowBuilder},
 };
 
+mod animation;
+mod assets;
+mod atlas;
 mod camera;
+mod compressed;
 mod controller;
+mod deferred;
+mod fog;
+mod gltf_model;
+mod loader;
+mod mesh_cache;
 mod model;
+mod post;
+mod primitives;
 mod render;
+mod skin;
+mod source;
+mod ssao;
 mod texture;
+mod ui;
+mod watcher;
 
-use render::Render;
+use model::VertexEncoding;
+use render::{Render, RenderPath};
+use source::AssetSource;
+use ui::Gui;
 
 fn main() -> anyhow::Result<()> {
     let event_loop = EventLoop::new();
     let window = WindowBuilder::new().build(&event_loop).unwrap();
 
-    let mut game = Game::new(&window)?;
+    let mut path = RenderPath::Forward;
+    let mut vertices = VertexEncoding::Full;
+    let mut asset_dir = None;
+    let mut animated = Vec::new();
+    let mut primitives = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--deferred" => path = RenderPath::Deferred,
+            "--compact-vertices" => vertices = VertexEncoding::Compact,
+            "--quantized-vertices" => vertices = VertexEncoding::Quantized,
+            "--assets" => asset_dir = args.next().map(PathBuf::from),
+            "--animated" => animated.extend(args.next().map(PathBuf::from)),
+            "--primitives" => primitives = true,
+            _ => {}
+        }
+    }
+
+    let source = AssetSource::resolve(asset_dir)?;
+    let mut game = Game::new(&window, path, vertices, source, &animated, primitives)?;
     let mut last_render_time = Instant::now();
 
     event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Poll;
 
+        game.gui.handle_event(&event);
+        if game.gui.captures_event(&event) {
+            return;
+        }
+
         match event {
             Event::DeviceEvent { ref event, .. } => {
                 game.input(event);
@@ -74,7 +124,7 @@ fn main() -> anyhow::Result<()> {
 
                 game.update(dt);
 
-                match game.render() {
+                match game.render(&window) {
                     Ok(_) => {}
                     Err(e) => match e.downcast::<wgpu::SurfaceError>() {
                         Ok(wgpu::SurfaceError::Lost) => game.resize(game.size),
@@ -98,17 +148,52 @@ struct Game {
     size: PhysicalSize<u32>,
     render: Render,
     controller: CameraController,
+    gui: Gui,
     mouse_pressed: bool,
     rt: Runtime,
 }
 
 impl Game {
-    fn new(window: &Window) -> anyhow::Result<Self> {
+    fn new(
+        window: &Window,
+        path: RenderPath,
+        vertices: VertexEncoding,
+        source: AssetSource,
+        animated: &[PathBuf],
+        primitives: bool,
+    ) -> anyhow::Result<Self> {
         let rt = tokio::runtime::Builder::new_current_thread().build()?;
 
-        let render = rt.block_on(Render::new(window));
+        let mut render = rt.block_on(Render::new(window, path, vertices, source));
+        // In a row in front of the instance grid
+        for (i, path) in animated.iter().enumerate() {
+            render.add_animated_model(path, vec3(i as f32 * 3.0, 0.0, 20.0));
+        }
+        // Off to the side, where they're clear of the grid and the row above
+        if primitives {
+            render.add_primitives(vec3(-6.0, 0.5, 24.0));
+        }
+
+        // A full turn every 6 seconds
+        let orbit = light_orbit(render.light_mut().position, 6.0)?;
+        let spin = instance_spin(0, 4.0)?;
+        let pulse = light_pulse(3.0)?;
+        // Would fight the camera controller, so it waits to be played from
+        // the tweak panel
+        let mut flyby = AnimationPlayer::new(
+            camera_flyby(vec3(-20.0, 10.0, -20.0), vec3(20.0, 4.0, -20.0), 8.0)?,
+            LoopMode::Once,
+        );
+        flyby.pause();
+        render.animations_mut().extend([
+            AnimationPlayer::new(orbit, LoopMode::Loop),
+            AnimationPlayer::new(spin, LoopMode::Loop),
+            AnimationPlayer::new(pulse, LoopMode::PingPong),
+            flyby,
+        ]);
 
         let controller = CameraController::new(4.0, 0.4);
+        let gui = Gui::new(window);
 
         Ok(Self {
             size: window.inner_size(),
@@ -116,6 +201,7 @@ impl Game {
             rt,
             mouse_pressed: false,
             controller,
+            gui,
         })
     }
 
@@ -136,7 +222,9 @@ impl Game {
                 true
             }
             DeviceEvent::Button { button: 1, state } => {
-                self.mouse_pressed = *state == ElementState::Pressed;
+                // Don't start rotating the camera while dragging a slider
+                self.mouse_pressed =
+                    *state == ElementState::Pressed && !self.gui.wants_pointer_input();
                 true
             }
             DeviceEvent::MouseMotion { delta } => {
@@ -153,16 +241,107 @@ impl Game {
         let camera = self.render.camera_mut();
         self.controller.update_camera(camera, dt);
 
-        let light_uniform = self.render.light_mut();
-        let old_position = light_uniform.position;
-        light_uniform.position =
-            Quat::from_axis_angle(vec3(0.0, 1.0, 0.0), (60.0 * dt.as_secs_f32()).to_radians())
-                * old_position;
-
-        self.render.update();
+        self.render.update(dt);
     }
 
-    fn render(&mut self) -> anyhow::Result<()> {
-        self.render.render()
+    fn render(&mut self, window: &Window) -> anyhow::Result<()> {
+        let render = &mut self.render;
+        let controller = &mut self.controller;
+        let gui = self
+            .gui
+            .frame(window, |ctx| ui::tweaks(ctx, render, controller));
+
+        self.render.render(&gui)
     }
 }
+
+/// Circles the main light around the Y axis once every `period` seconds,
+/// starting from `position`.
+fn light_orbit(position: Vec3, period: f32) -> anyhow::Result<AnimationClip> {
+    // Positions get lerped, so enough keyframes that cutting the corners
+    // between them isn't noticeable
+    const KEYFRAMES: usize = 48;
+    let (times, positions) = (0..=KEYFRAMES)
+        .map(|i| {
+            let turn = i as f32 / KEYFRAMES as f32;
+            (turn * period, Quat::from_rotation_y(turn * TAU) * position)
+        })
+        .unzip();
+    let keyframes = Keyframes::new(times, positions, Interpolation::Linear)?;
+
+    Ok(AnimationClip::new(
+        "Light orbit",
+        vec![Track::new(Target::Light(0), Property::Position(keyframes))],
+    ))
+}
+
+/// Turns instance `index` about the Y axis once every `period` seconds,
+/// swelling to half as big again halfway round.
+fn instance_spin(index: usize, period: f32) -> anyhow::Result<AnimationClip> {
+    // Rotations take the short way between keyframes, so a full turn needs
+    // more than two
+    let turns = [0.0, 1.0 / 3.0, 2.0 / 3.0, 1.0];
+    let rotation = Keyframes::new(
+        turns.iter().map(|turn| turn * period).collect(),
+        turns
+            .iter()
+            .map(|turn| Quat::from_rotation_y(turn * TAU))
+            .collect(),
+        Interpolation::Linear,
+    )?;
+    let scale = Keyframes::new(
+        vec![0.0, period / 2.0, period],
+        vec![Vec3::ONE, Vec3::splat(1.5), Vec3::ONE],
+        Interpolation::Linear,
+    )?;
+
+    Ok(AnimationClip::new(
+        "Instance spin",
+        vec![
+            Track::new(Target::Instance(index), Property::Rotation(rotation)),
+            Track::new(Target::Instance(index), Property::Scale(scale)),
+        ],
+    ))
+}
+
+/// Takes the main light from warm to cool over `period` seconds, getting
+/// brighter as it goes.
+fn light_pulse(period: f32) -> anyhow::Result<AnimationClip> {
+    let times = vec![0.0, period];
+    let color = Keyframes::new(
+        times.clone(),
+        vec![vec3(1.0, 0.8, 0.6), vec3(0.6, 0.8, 1.0)],
+        Interpolation::Linear,
+    )?;
+    let intensity = Keyframes::new(times, vec![1.0, 2.0], Interpolation::Linear)?;
+
+    Ok(AnimationClip::new(
+        "Light pulse",
+        vec![
+            Track::new(Target::Light(0), Property::Color(color)),
+            Track::new(Target::Light(0), Property::Intensity(intensity)),
+        ],
+    ))
+}
+
+/// Flies the camera from `start` to `end` over `duration` seconds, keeping
+/// it pointed at the middle of the instance grid.
+fn camera_flyby(start: Vec3, end: Vec3, duration: f32) -> anyhow::Result<AnimationClip> {
+    let times = vec![0.0, duration];
+    let position = Keyframes::new(times.clone(), vec![start, end], Interpolation::Linear)?;
+    // Unrotated, the camera looks down +X
+    let look_at_grid = |from: Vec3| Quat::from_rotation_arc(Vec3::X, (-from).normalize());
+    let rotation = Keyframes::new(
+        times,
+        vec![look_at_grid(start), look_at_grid(end)],
+        Interpolation::Linear,
+    )?;
+
+    Ok(AnimationClip::new(
+        "Camera flyby",
+        vec![
+            Track::new(Target::Camera, Property::Position(position)),
+            Track::new(Target::Camera, Property::Rotation(rotation)),
+        ],
+    ))
+}
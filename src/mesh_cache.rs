@@ -0,0 +1,536 @@
+//! Caches loaded models in a binary format that's quick to read back, so
+//! parsing, generating tangents and optimizing only happen the first time.
+//!
+//! A cache file remembers a checksum of every file the model was read from
+//! and is ignored once any of them change, after which it gets rewritten.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context};
+use glam::Vec3;
+use memmap2::Mmap;
+
+use crate::{
+    model::{
+        MaterialData, MaterialFactors, MaterialTextures, MeshData, ModelData, ModelVertex,
+        TextureData, TextureUsage,
+    },
+    source::AssetSource,
+};
+
+/// Environment variable that moves the cache somewhere other than the
+/// system's temporary directory.
+const CACHE_DIR_VAR: &str = "CRAFT_MESH_CACHE";
+
+const MAGIC: &[u8; 4] = b"CMSH";
+/// Bump whenever the layout below, or anything that goes into
+/// `ModelData`, changes.
+const VERSION: u32 = 4;
+
+/// Magic, version, payload length and payload checksum.
+const HEADER_LEN: usize = 4 + 4 + 8 + 8;
+
+/// Reads `path` from the cache, if it's there and none of its sources have
+/// changed since it was written.
+pub fn read(source: &AssetSource, path: &Path) -> Option<ModelData> {
+    let cache_path = cache_path(source, path)?;
+    if !cache_path.is_file() {
+        return None;
+    }
+
+    match read_file(source, path, &cache_path) {
+        Ok(data) => data,
+        Err(e) => {
+            log::warn!("Ignoring mesh cache {:?}: {:?}", cache_path, e);
+            None
+        }
+    }
+}
+
+/// Saves `data` to the cache. Models with textures embedded in them aren't
+/// cached, since we no longer have the encoded images to write out, and
+/// neither are skinned ones.
+pub fn write(source: &AssetSource, data: &ModelData) -> anyhow::Result<()> {
+    let cache_path = match cache_path(source, &data.path) {
+        Some(cache_path) => cache_path,
+        None => return Ok(()),
+    };
+
+    let bytes = match encode_file(source, data)? {
+        Some(bytes) => bytes,
+        None => {
+            log::debug!(
+                "Not caching {:?}, it has embedded textures or a skeleton",
+                data.path
+            );
+            return Ok(());
+        }
+    };
+
+    // Written next to it and renamed over, so a reader never sees half a
+    // file and anything that has the old one mapped keeps it
+    let dir = cache_path.parent().context("Cache path has no parent")?;
+    fs::create_dir_all(dir)?;
+    let temp_path = cache_path.with_extension(format!("{}.tmp", std::process::id()));
+    fs::write(&temp_path, &bytes)?;
+    fs::rename(&temp_path, &cache_path)?;
+    Ok(())
+}
+
+/// Deletes the cached copy of `path`, so the next load reads the source.
+pub fn invalidate(source: &AssetSource, path: &Path) {
+    if let Some(cache_path) = cache_path(source, path) {
+        if cache_path.is_file() {
+            if let Err(e) = fs::remove_file(&cache_path) {
+                log::warn!("Failed to remove mesh cache {:?}: {}", cache_path, e);
+            }
+        }
+    }
+}
+
+/// Embedded assets are already in memory, so they don't get cached.
+fn cache_path(source: &AssetSource, path: &Path) -> Option<PathBuf> {
+    source.root()?;
+
+    let dir = env::var_os(CACHE_DIR_VAR)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| env::temp_dir().join("craft-mesh-cache"));
+    // Named after where the model is, so models with the same name in
+    // different directories don't trample each other
+    let key = source.cache_key(path);
+    let name = format!("{:016x}.mesh", checksum(key.to_string_lossy().as_bytes()));
+    Some(dir.join(name))
+}
+
+/// Maps the file rather than reading it, but the vertices and indices are
+/// still copied out of the mapping. `ModelData` owns its buffers: it outlives
+/// the mapping on its way to the render thread, and packing textures rewrites
+/// the UVs and layers in place before it gets there.
+fn read_file(
+    source: &AssetSource,
+    path: &Path,
+    cache_path: &Path,
+) -> anyhow::Result<Option<ModelData>> {
+    let file = fs::File::open(cache_path)?;
+    // Safe as long as nothing truncates the file while it's mapped, we only
+    // ever replace cache files by renaming over them
+    let map = unsafe { Mmap::map(&file)? };
+    decode_file(source, path, &map)
+}
+
+/// The header and payload, or `None` if the model can't be cached.
+fn encode_file(source: &AssetSource, data: &ModelData) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut payload = Writer::default();
+    if !encode(source, data, &mut payload)? {
+        return Ok(None);
+    }
+
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.bytes.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(payload.bytes.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&checksum(&payload.bytes).to_le_bytes());
+    bytes.extend_from_slice(&payload.bytes);
+    Ok(Some(bytes))
+}
+
+fn decode_file(
+    source: &AssetSource,
+    path: &Path,
+    bytes: &[u8],
+) -> anyhow::Result<Option<ModelData>> {
+    let header = bytes.get(..HEADER_LEN).context("Missing header")?;
+    if &header[..4] != MAGIC {
+        bail!("Not a mesh cache file");
+    }
+    let mut reader = Reader {
+        bytes: &header[4..],
+    };
+    if reader.u32()? != VERSION {
+        // Written by an older build, not an error
+        return Ok(None);
+    }
+    let len = reader.u64()? as usize;
+    let expected_checksum = reader.u64()?;
+
+    let payload = bytes[HEADER_LEN..]
+        .get(..len)
+        .context("File is truncated")?;
+    if checksum(payload) != expected_checksum {
+        bail!("Checksum doesn't match");
+    }
+
+    decode(source, path, &mut Reader { bytes: payload })
+}
+
+fn encode(source: &AssetSource, data: &ModelData, out: &mut Writer) -> anyhow::Result<bool> {
+    if data.skeleton.is_some() {
+        return Ok(false);
+    }
+
+    out.u32(data.sources.len() as u32);
+    for path in &data.sources {
+        out.str(&path.to_string_lossy());
+        out.u64(checksum(&source.read(path)?));
+    }
+
+    out.u32(data.materials.len() as u32);
+    for material in &data.materials {
+        out.str(&material.name);
+        for (texture, _) in material.textures.iter() {
+            match texture {
+                TextureData::File(path) => {
+                    out.u32(0);
+                    out.str(&path.to_string_lossy());
+                }
+                TextureData::Solid(color) => {
+                    out.u32(1);
+                    out.bytes(color);
+                }
+                TextureData::Image { .. } => return Ok(false),
+            }
+        }
+
+        let factors = &material.factors;
+        out.vec3(factors.diffuse);
+        out.vec3(factors.specular);
+        out.f32(factors.shininess);
+        out.vec3(factors.emissive);
+        out.f32(factors.opacity);
+        out.f32(factors.height_scale);
+        out.u32(factors.parallax_layers);
+    }
+
+    out.u32(data.meshes.len() as u32);
+    for mesh in &data.meshes {
+        out.str(&mesh.name);
+        out.u32(mesh.material as u32);
+        out.bytes(bytemuck::cast_slice(&mesh.vertices));
+        out.bytes(bytemuck::cast_slice(&mesh.indices));
+        out.u32(mesh.lods.len() as u32);
+        for lod in &mesh.lods {
+            out.bytes(bytemuck::cast_slice(lod));
+        }
+    }
+
+    Ok(true)
+}
+
+/// `None` if one of the sources has changed.
+fn decode(
+    source: &AssetSource,
+    path: &Path,
+    input: &mut Reader,
+) -> anyhow::Result<Option<ModelData>> {
+    let source_count = input.u32()?;
+    let mut sources = Vec::with_capacity(source_count as usize);
+    for _ in 0..source_count {
+        let source_path = PathBuf::from(input.str()?);
+        let expected_checksum = input.u64()?;
+        let up_to_date = source
+            .read(&source_path)
+            .map(|bytes| checksum(&bytes) == expected_checksum)
+            .unwrap_or(false);
+        if !up_to_date {
+            log::debug!("{:?} changed, reloading {:?}", source_path, path);
+            return Ok(None);
+        }
+        sources.push(source_path);
+    }
+
+    let material_count = input.u32()?;
+    let mut materials = Vec::with_capacity(material_count as usize);
+    for _ in 0..material_count {
+        let name = input.str()?.to_string();
+        let textures = MaterialTextures::<TextureUsage>::usages().try_map(|_, _| {
+            Ok::<_, anyhow::Error>(match input.u32()? {
+                0 => TextureData::File(PathBuf::from(input.str()?)),
+                1 => {
+                    let bytes = input.bytes()?;
+                    if bytes.len() != 4 {
+                        bail!("Solid color isn't four bytes");
+                    }
+                    let mut color = [0; 4];
+                    color.copy_from_slice(bytes);
+                    TextureData::Solid(color)
+                }
+                tag => bail!("Unknown texture kind {}", tag),
+            })
+        })?;
+
+        let factors = MaterialFactors {
+            diffuse: input.vec3()?,
+            specular: input.vec3()?,
+            shininess: input.f32()?,
+            emissive: input.vec3()?,
+            opacity: input.f32()?,
+            height_scale: input.f32()?,
+            parallax_layers: input.u32()?,
+        };
+
+        materials.push(MaterialData {
+            name,
+            textures,
+            factors,
+        });
+    }
+
+    let mesh_count = input.u32()?;
+    let mut meshes = Vec::with_capacity(mesh_count as usize);
+    for _ in 0..mesh_count {
+        let name = input.str()?.to_string();
+        let material = input.u32()? as usize;
+        let vertices = bytemuck::try_cast_slice::<u8, ModelVertex>(input.bytes()?)
+            .map_err(|e| anyhow!("Bad vertex data: {:?}", e))?
+            .to_vec();
+        let indices = read_indices(input)?;
+        let lod_count = input.u32()?;
+        let lods = (0..lod_count)
+            .map(|_| read_indices(input))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let out_of_range = std::iter::once(&indices)
+            .chain(&lods)
+            .flatten()
+            .any(|&i| i as usize >= vertices.len());
+        if material >= materials.len() || out_of_range {
+            bail!("Mesh {} is corrupt", name);
+        }
+        meshes.push(MeshData {
+            name,
+            vertices,
+            indices,
+            lods,
+            material,
+            skin: None,
+        });
+    }
+
+    Ok(Some(ModelData {
+        path: path.to_path_buf(),
+        sources,
+        meshes,
+        materials,
+        skeleton: None,
+        animations: Vec::new(),
+    }))
+}
+
+fn read_indices(input: &mut Reader) -> anyhow::Result<Vec<u32>> {
+    Ok(bytemuck::try_cast_slice::<u8, u32>(input.bytes()?)
+        .map_err(|e| anyhow!("Bad index data: {:?}", e))?
+        .to_vec())
+}
+
+/// 64 bit FNV-1a. Not cryptographic, but it only has to notice edits.
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Everything is little endian, and variable length data is padded to four
+/// bytes so vertices and indices can be cast straight out of the mapping.
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn vec3(&mut self, value: Vec3) {
+        self.f32(value.x);
+        self.f32(value.y);
+        self.f32(value.z);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.bytes.extend_from_slice(bytes);
+        let padding = (4 - bytes.len() % 4) % 4;
+        self.bytes.extend(std::iter::repeat(0).take(padding));
+    }
+
+    fn str(&mut self, value: &str) {
+        self.bytes(value.as_bytes());
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if self.bytes.len() < len {
+            bail!("File is truncated");
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn f32(&mut self) -> anyhow::Result<f32> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    fn vec3(&mut self) -> anyhow::Result<Vec3> {
+        Ok(Vec3::new(self.f32()?, self.f32()?, self.f32()?))
+    }
+
+    fn bytes(&mut self) -> anyhow::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+        self.take((4 - len % 4) % 4)?;
+        Ok(bytes)
+    }
+
+    fn str(&mut self) -> anyhow::Result<&'a str> {
+        Ok(std::str::from_utf8(self.bytes()?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives;
+
+    /// A directory of its own to read sources from, with `model.obj` in it.
+    fn source_dir(name: &str) -> AssetSource {
+        let dir = env::temp_dir().join(format!(
+            "craft-mesh-cache-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("model.obj"), "o cube\n").unwrap();
+        AssetSource::Dir(dir)
+    }
+
+    fn model() -> ModelData {
+        let mut data = ModelData::from_meshes("model.obj", vec![primitives::cube(1.0)]);
+        data.sources.push(PathBuf::from("model.obj"));
+        let material = &mut data.materials[0];
+        material.textures.diffuse = TextureData::File(PathBuf::from("diffuse.png"));
+        material.factors.shininess = 12.0;
+        data
+    }
+
+    #[test]
+    fn round_trip() {
+        let source = source_dir("round-trip");
+        let data = model();
+        let bytes = encode_file(&source, &data).unwrap().unwrap();
+        let decoded = decode_file(&source, &data.path, &bytes).unwrap().unwrap();
+
+        assert_eq!(decoded.path, data.path);
+        assert_eq!(decoded.sources, data.sources);
+
+        assert_eq!(decoded.materials.len(), 1);
+        let (material, expected) = (&decoded.materials[0], &data.materials[0]);
+        assert_eq!(material.name, expected.name);
+        assert_eq!(material.factors, expected.factors);
+        for ((texture, _), (expected, _)) in material.textures.iter().zip(expected.textures.iter())
+        {
+            match (texture, expected) {
+                (TextureData::File(path), TextureData::File(expected)) => {
+                    assert_eq!(path, expected)
+                }
+                (TextureData::Solid(color), TextureData::Solid(expected)) => {
+                    assert_eq!(color, expected)
+                }
+                _ => panic!("{:?} came back as {:?}", expected, texture),
+            }
+        }
+
+        assert_eq!(decoded.meshes.len(), 1);
+        let (mesh, expected) = (&decoded.meshes[0], &data.meshes[0]);
+        assert_eq!(mesh.name, expected.name);
+        assert_eq!(mesh.material, expected.material);
+        assert_eq!(
+            bytemuck::cast_slice::<_, u8>(&mesh.vertices),
+            bytemuck::cast_slice::<_, u8>(&expected.vertices)
+        );
+        assert_eq!(mesh.indices, expected.indices);
+        assert_eq!(mesh.lods, expected.lods);
+    }
+
+    #[test]
+    fn changed_source_is_stale() {
+        let source = source_dir("changed-source");
+        let data = model();
+        let bytes = encode_file(&source, &data).unwrap().unwrap();
+
+        fs::write(source.root().unwrap().join("model.obj"), "o sphere\n").unwrap();
+        assert!(decode_file(&source, &data.path, &bytes).unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_file_is_rejected() {
+        let source = source_dir("truncated");
+        let data = model();
+        let bytes = encode_file(&source, &data).unwrap().unwrap();
+
+        for len in [0, HEADER_LEN - 1, HEADER_LEN, bytes.len() - 1] {
+            assert!(decode_file(&source, &data.path, &bytes[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn corrupt_payload_is_rejected() {
+        let source = source_dir("corrupt");
+        let data = model();
+        let mut bytes = encode_file(&source, &data).unwrap().unwrap();
+
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        assert!(decode_file(&source, &data.path, &bytes).is_err());
+
+        // Past the checksum, the indices are still checked against the
+        // vertices
+        let mut data = model();
+        data.meshes[0].indices[0] = data.meshes[0].vertices.len() as u32;
+        let mut payload = Writer::default();
+        assert!(encode(&source, &data, &mut payload).unwrap());
+        let mut reader = Reader {
+            bytes: &payload.bytes,
+        };
+        assert!(decode(&source, &data.path, &mut reader).is_err());
+    }
+
+    #[test]
+    fn older_version_is_ignored() {
+        let source = source_dir("older-version");
+        let data = model();
+        let mut bytes = encode_file(&source, &data).unwrap().unwrap();
+
+        bytes[4..8].copy_from_slice(&(VERSION - 1).to_le_bytes());
+        assert!(decode_file(&source, &data.path, &bytes).unwrap().is_none());
+    }
+}
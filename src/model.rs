@@ -1,197 +1,1545 @@
-use std::{ops::Range, path::Path};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    f32::consts::FRAC_PI_3,
+    io::Cursor,
+    ops::Range,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Context;
 use glam::{Vec2, Vec3};
+use image::RgbaImage;
 use tobj::LoadOptions;
 use wgpu::{util::DeviceExt, BindGroup, Buffer, IndexFormat};
 
-use crate::texture::Texture;
+use crate::{
+    assets::{Assets, Handle},
+    atlas::TextureAtlas,
+    gltf_model, mesh_cache,
+    skin::{SkeletalAnimation, Skeleton},
+    source::AssetSource,
+    texture::{ImageData, Texture},
+};
+
+/// Used when a material doesn't have a diffuse texture.
+const WHITE: [u8; 4] = [255, 255, 255, 255];
+/// Used when a material doesn't have a normal map, points straight out of
+/// the surface.
+const FLAT_NORMAL: [u8; 4] = [128, 128, 255, 255];
+
+/// Used for MTL displacement maps that don't give their own scale.
+const DEFAULT_HEIGHT_SCALE: f32 = 0.05;
+
+/// How much of the screen's height a model has to cover to be drawn at
+/// each LOD, anything smaller than the last goes to the coarsest. There's a
+/// simplified LOD made for each of these, after the full mesh.
+pub const LOD_SCREEN_SIZES: [f32; 3] = [0.25, 0.1, 0.04];
+/// How far the first simplified LOD may move the surface, as a fraction of
+/// the mesh's size. Doubles for each LOD after it, since they're only seen
+/// smaller.
+const LOD_TARGET_ERROR: f32 = 0.01;
+
+/// Faces meeting at a sharper angle than this get a hard edge when we
+/// generate normals, so a cube comes out flat shaded but a sphere smooth.
+const CREASE_ANGLE: f32 = FRAC_PI_3;
+
+/// The fewest array layers any adapter can make, so texture arrays are split
+/// into this many at most.
+const MAX_ARRAY_LAYERS: usize = 256;
+/// Diffuse textures bigger than this on a side aren't worth packing into an
+/// atlas.
+const MAX_ATLAS_IMAGE_SIZE: u32 = 512;
+/// There's no device yet when a model is loaded, so atlases stay within the
+/// smallest `max_texture_dimension_2d` any adapter has.
+const MAX_ATLAS_SIZE: u32 = 2048;
 
 pub trait Vertex {
     fn desc<'a>() -> wgpu::VertexBufferLayout<'a>;
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+#[derive(Copy, Clone, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct ModelVertex {
-    position: Vec3,
-    tex_coords: Vec2,
-    normal: Vec3,
-    tangent: Vec3,
-    bitangent: Vec3,
+    pub(crate) position: Vec3,
+    pub(crate) tex_coords: Vec2,
+    pub(crate) normal: Vec3,
+    /// The direction in `xyz` and the handedness of the bitangent in `w`.
+    /// An array since `Vec4` would add padding.
+    pub(crate) tangent: [f32; 4],
+    /// Which layer of the material's diffuse texture to sample, for
+    /// materials that were merged into a texture array.
+    pub(crate) layer: u32,
+}
+
+/// How meshes are packed into their vertex buffers. Picked once at startup,
+/// since every pipeline that draws meshes has to agree with it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VertexEncoding {
+    /// [`ModelVertex`] as is, 52 bytes a vertex.
+    Full,
+    /// [`CompactVertex`], 32 bytes a vertex.
+    Compact,
+    /// [`QuantizedVertex`], 24 bytes a vertex.
+    Quantized,
+}
+
+/// Full precision positions, with everything else squeezed down.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct CompactVertex {
+    /// `w` is the handedness of the bitangent, 0 for -1 and 1 for 1.
+    position: [f32; 4],
+    /// Half floats.
+    tex_coords: [u16; 2],
+    /// Octahedral encoded, see [`encode_octahedral`].
+    normal: [i16; 2],
+    tangent: [i16; 2],
+    layer: u32,
+}
+
+/// A [`CompactVertex`] with its position stored as 16 bit fractions of the
+/// mesh's bounds, which the vertex shader scales back out.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct QuantizedVertex {
+    /// `w` is the handedness of the bitangent, 0 for -1 and 1 for 1.
+    position: [u16; 4],
+    tex_coords: [u16; 2],
+    normal: [i16; 2],
+    tangent: [i16; 2],
+    layer: u32,
+}
+
+/// Which joints move a vertex and by how much. Kept in a vertex buffer of
+/// its own alongside the mesh's, so everything that works on
+/// [`ModelVertex`] carries on as is.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct SkinVertex {
+    /// Indices into the skeleton's joints.
+    pub(crate) joints: [u16; 4],
+    /// Add up to one.
+    pub(crate) weights: [f32; 4],
+}
+
+/// Matches `Dequantize` in the shaders, takes positions in the compact
+/// encodings back to model space.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct DequantizeUniform {
+    offset: Vec3,
+    _padding0: u32,
+    scale: Vec3,
+    _padding1: u32,
 }
 
 #[derive(Debug)]
 pub struct Model {
-    pub meshes: Vec<Mesh>,
-    pub materials: Vec<Material>,
+    pub meshes: Vec<Handle<Mesh>>,
+    pub materials: Vec<Handle<Material>>,
+    /// Of a sphere around the model's origin that holds all of it, for
+    /// picking LODs.
+    pub radius: f32,
+    /// What the skinned meshes follow, if there are any.
+    pub skeleton: Option<Skeleton>,
+    pub animations: Vec<SkeletalAnimation>,
 }
 
 #[derive(Debug)]
 pub struct Material {
     pub name: String,
-    pub diffuse_texture: Texture,
-    pub normal_texture: Texture,
+    pub textures: MaterialTextures<Handle<Texture>>,
+    pub factors: MaterialFactors,
+    /// Whether anything behind this material shows through it.
+    pub translucent: bool,
+    pub factors_buffer: Buffer,
     pub bind_group: BindGroup,
 }
 
+/// The scalar parts of a material, what an MTL gives as `Kd`, `Ks`, `Ns`,
+/// `Ke` and `d`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MaterialFactors {
+    /// Tints the diffuse texture.
+    pub diffuse: Vec3,
+    pub specular: Vec3,
+    pub shininess: f32,
+    /// Added on top of the lighting, so it shows up in the dark.
+    pub emissive: Vec3,
+    pub opacity: f32,
+    /// How deep the height map goes in UV units, zero turns parallax
+    /// occlusion mapping off.
+    pub height_scale: f32,
+    /// How many steps to march through the height map. More gets rid of
+    /// stepping at glancing angles but costs more samples. MTLs set it with
+    /// a `-layers` option on `disp`.
+    pub parallax_layers: u32,
+}
+
+/// One of each texture a material samples. The same shape is used for what
+/// was read from disk, the handles and the textures themselves.
+#[derive(Debug, Clone)]
+pub struct MaterialTextures<T> {
+    pub diffuse: T,
+    pub normal: T,
+    /// Tints the specular factor.
+    pub specular: T,
+    /// Scales the shininess factor by its red channel.
+    pub shininess: T,
+    pub emissive: T,
+    /// Scales the opacity factor by its red channel.
+    pub opacity: T,
+    /// White is the surface, black is `height_scale` below it.
+    pub height: T,
+}
+
+/// What a material uses a texture for, which decides how it's decoded and
+/// what stands in for it when it's missing.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TextureUsage {
+    Color,
+    Normal,
+    /// Anything else that isn't a color, like shininess or opacity.
+    Data,
+}
+
+/// Matches `Material` in shader.wgsl and gbuffer.wgsl.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct MaterialUniform {
+    diffuse: Vec3,
+    opacity: f32,
+    specular: Vec3,
+    shininess: f32,
+    emissive: Vec3,
+    height_scale: f32,
+    parallax_layers: u32,
+    _padding: [u32; 3],
+}
+
 #[derive(Debug)]
 pub struct Mesh {
     pub name: String,
     pub vertex_buffer: Buffer,
     pub index_buffer: Buffer,
-    pub num_elements: u32,
+    /// `Uint16` when the mesh has few enough vertices for it.
+    pub index_format: IndexFormat,
+    /// Where each LOD is in the index buffer, the full mesh first.
+    pub lods: Vec<Range<u32>>,
+    /// Index into the owning model's `materials`.
     pub material: usize,
+    /// Only there for the compact encodings, how the vertex shader unpacks
+    /// the positions.
+    pub dequantize_buffer: Option<Buffer>,
+    pub bind_group: Option<BindGroup>,
+    /// Only skinned meshes have one, holding a [`SkinVertex`] for each
+    /// vertex. They're always stored in full and drawn with the skinning
+    /// pipelines.
+    pub skin_buffer: Option<Buffer>,
+}
+
+/// A model as read from disk, before anything has been uploaded.
+#[derive(Debug)]
+pub struct ModelData {
+    pub path: PathBuf,
+    /// Every file the model was read from, so the mesh cache can tell when
+    /// it's out of date.
+    pub sources: Vec<PathBuf>,
+    pub meshes: Vec<MeshData>,
+    pub materials: Vec<MaterialData>,
+    pub skeleton: Option<Skeleton>,
+    pub animations: Vec<SkeletalAnimation>,
+}
+
+#[derive(Debug)]
+pub struct MeshData {
+    pub name: String,
+    pub vertices: Vec<ModelVertex>,
+    pub indices: Vec<u32>,
+    /// Simplified versions of `indices` over the same vertices, each
+    /// coarser than the last.
+    pub lods: Vec<Vec<u32>>,
+    pub material: usize,
+    /// One for each vertex, for meshes that follow the model's skeleton.
+    pub skin: Option<Vec<SkinVertex>>,
+}
+
+#[derive(Debug)]
+pub struct MaterialData {
+    pub name: String,
+    pub textures: MaterialTextures<TextureData>,
+    pub factors: MaterialFactors,
+}
+
+/// Where a material's texture comes from.
+#[derive(Debug)]
+pub enum TextureData {
+    /// A file relative to the asset root, shared through the asset cache.
+    File(PathBuf),
+    /// Already decoded, e.g. an image embedded in a GLB, or the texture array
+    /// or atlas that materials were merged into.
+    Image { label: String, image: ImageData },
+    /// A single color, for materials that only give a factor.
+    Solid([u8; 4]),
+}
+
+impl MaterialData {
+    /// The colors of every texture but the diffuse, if they're all solid.
+    fn solid_colors(&self) -> Option<Vec<[u8; 4]>> {
+        self.textures
+            .iter()
+            .skip(1)
+            .map(|(texture, _)| match texture {
+                TextureData::Solid(color) => Some(*color),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The diffuse texture decoded, for packing with others. Compressed ones
+    /// are left alone, they'd only get bigger.
+    fn diffuse_image(&self, source: &AssetSource) -> Option<RgbaImage> {
+        match &self.textures.diffuse {
+            TextureData::File(path) => match source
+                .read(path)
+                .and_then(|bytes| ImageData::from_bytes(&bytes))
+            {
+                Ok(ImageData::Uncompressed(image)) => Some(image.to_rgba8()),
+                _ => None,
+            },
+            TextureData::Image {
+                image: ImageData::Uncompressed(image),
+                ..
+            } => Some(image.to_rgba8()),
+            _ => None,
+        }
+    }
+
+    pub fn is_translucent(&self) -> bool {
+        let opaque_texture = matches!(self.textures.opacity, TextureData::Solid([255, ..]));
+        self.factors.opacity < 1.0 || !opaque_texture
+    }
+
+    /// A plain white material, for meshes that don't have one.
+    pub fn untextured(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            textures: MaterialTextures::solid(),
+            factors: MaterialFactors::default(),
+        }
+    }
+}
+
+impl MaterialTextures<TextureData> {
+    /// What each texture falls back to when a material doesn't have it,
+    /// leaving just the factors.
+    pub fn solid() -> Self {
+        MaterialTextures::usages().map(|usage, _| TextureData::Solid(usage.fallback()))
+    }
+}
+
+impl MaterialTextures<TextureUsage> {
+    pub fn usages() -> Self {
+        Self {
+            diffuse: TextureUsage::Color,
+            normal: TextureUsage::Normal,
+            specular: TextureUsage::Color,
+            shininess: TextureUsage::Data,
+            emissive: TextureUsage::Color,
+            opacity: TextureUsage::Data,
+            height: TextureUsage::Data,
+        }
+    }
+}
+
+impl<T> MaterialTextures<T> {
+    pub fn as_ref(&self) -> MaterialTextures<&T> {
+        MaterialTextures {
+            diffuse: &self.diffuse,
+            normal: &self.normal,
+            specular: &self.specular,
+            shininess: &self.shininess,
+            emissive: &self.emissive,
+            opacity: &self.opacity,
+            height: &self.height,
+        }
+    }
+
+    /// Also says what each texture is for.
+    pub fn map<U>(self, mut f: impl FnMut(T, TextureUsage) -> U) -> MaterialTextures<U> {
+        let usages = MaterialTextures::usages();
+        MaterialTextures {
+            diffuse: f(self.diffuse, usages.diffuse),
+            normal: f(self.normal, usages.normal),
+            specular: f(self.specular, usages.specular),
+            shininess: f(self.shininess, usages.shininess),
+            emissive: f(self.emissive, usages.emissive),
+            opacity: f(self.opacity, usages.opacity),
+            height: f(self.height, usages.height),
+        }
+    }
+
+    /// Like [`map`](Self::map), but stops at the first error.
+    pub fn try_map<U, E>(
+        self,
+        mut f: impl FnMut(T, TextureUsage) -> Result<U, E>,
+    ) -> Result<MaterialTextures<U>, E> {
+        let usages = MaterialTextures::usages();
+        Ok(MaterialTextures {
+            diffuse: f(self.diffuse, usages.diffuse)?,
+            normal: f(self.normal, usages.normal)?,
+            specular: f(self.specular, usages.specular)?,
+            shininess: f(self.shininess, usages.shininess)?,
+            emissive: f(self.emissive, usages.emissive)?,
+            opacity: f(self.opacity, usages.opacity)?,
+            height: f(self.height, usages.height)?,
+        })
+    }
+
+    /// In binding order, alongside what each one is for.
+    pub fn iter(&self) -> impl Iterator<Item = (&T, TextureUsage)> {
+        let usages = MaterialTextures::usages();
+        std::array::IntoIter::new([
+            (&self.diffuse, usages.diffuse),
+            (&self.normal, usages.normal),
+            (&self.specular, usages.specular),
+            (&self.shininess, usages.shininess),
+            (&self.emissive, usages.emissive),
+            (&self.opacity, usages.opacity),
+            (&self.height, usages.height),
+        ])
+    }
+}
+
+impl TextureUsage {
+    /// Colors are stored as sRGB, everything else is read as is.
+    pub fn is_linear(self) -> bool {
+        self != TextureUsage::Color
+    }
+
+    /// Leaves the factors untouched, or in the case of normal maps points
+    /// straight out of the surface.
+    pub fn fallback(self) -> [u8; 4] {
+        match self {
+            TextureUsage::Normal => FLAT_NORMAL,
+            TextureUsage::Color | TextureUsage::Data => WHITE,
+        }
+    }
+}
+
+impl Default for MaterialFactors {
+    fn default() -> Self {
+        Self {
+            diffuse: Vec3::ONE,
+            specular: Vec3::ONE,
+            shininess: 32.0,
+            emissive: Vec3::ZERO,
+            opacity: 1.0,
+            height_scale: 0.0,
+            parallax_layers: 16,
+        }
+    }
+}
+
+impl MaterialFactors {
+    /// Reads the factors out of an MTL material.
+    fn from_mtl(mat: &tobj::Material) -> Self {
+        // tobj can't tell us whether `Kd` or `Ns` were given at all, zero is
+        // what we get for both. A black textured material is far more likely
+        // to be a missing `Kd` than intentional.
+        let diffuse = Vec3::from(mat.diffuse);
+        let diffuse = if diffuse == Vec3::ZERO && !mat.diffuse_texture.is_empty() {
+            Vec3::ONE
+        } else {
+            diffuse
+        };
+        let shininess = if mat.shininess > 0.0 {
+            mat.shininess
+        } else {
+            Self::default().shininess
+        };
+
+        // tobj doesn't know about `Ke`
+        let emissive = mat
+            .unknown_param
+            .get("Ke")
+            .and_then(|ke| {
+                let rgb = ke
+                    .split_whitespace()
+                    .map(str::parse)
+                    .collect::<Result<Vec<f32>, _>>()
+                    .ok()?;
+                match rgb[..] {
+                    [r, g, b] => Some(Vec3::new(r, g, b)),
+                    [v] => Some(Vec3::splat(v)),
+                    _ => None,
+                }
+            })
+            .unwrap_or(Vec3::ZERO);
+
+        // `-mm base gain` is how MTL scales a displacement map. `-layers`
+        // isn't standard, it's ours for how many steps to march.
+        let mut parallax_layers = Self::default().parallax_layers;
+        let height_scale = match mat.unknown_param.get("disp") {
+            Some(disp) => {
+                let (options, _) = parse_texture_options(disp);
+                if let Some(&layers) = options.get("-layers").and_then(|layers| layers.first()) {
+                    parallax_layers = layers.max(1.0) as u32;
+                }
+                options
+                    .get("-mm")
+                    .and_then(|mm| mm.get(1).copied())
+                    .unwrap_or(DEFAULT_HEIGHT_SCALE)
+            }
+            None => 0.0,
+        };
+
+        Self {
+            diffuse,
+            specular: mat.specular.into(),
+            shininess,
+            emissive,
+            opacity: mat.dissolve,
+            height_scale,
+            parallax_layers,
+        }
+    }
+
+    pub(crate) fn to_uniform(&self) -> MaterialUniform {
+        MaterialUniform {
+            diffuse: self.diffuse,
+            opacity: self.opacity.clamp(0.0, 1.0),
+            specular: self.specular,
+            // pow(x, 0) is 1 everywhere, which lights up the whole surface
+            shininess: self.shininess.max(1.0),
+            emissive: self.emissive,
+            height_scale: self.height_scale.max(0.0),
+            parallax_layers: self.parallax_layers.max(1),
+            _padding: [0; 3],
+        }
+    }
 }
 
 impl Model {
+    /// Loads a model and its textures, blocking until everything is on the GPU.
     pub fn load(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         layout: &wgpu::BindGroupLayout,
+        assets: &mut Assets,
         path: impl AsRef<Path>,
     ) -> anyhow::Result<Self> {
-        let (obj_models, obj_materials) = tobj::load_obj(
-            path.as_ref(),
+        let data = ModelData::load(assets.source(), path)?;
+        Self::from_data(
+            device,
+            queue,
+            layout,
+            assets,
+            data,
+            |assets, path, usage| assets.load_texture(device, queue, path, usage),
+        )
+    }
+
+    /// Uploads the meshes in `data` and builds its materials. Texture files
+    /// are resolved through `load_texture` so the caller decides whether
+    /// they're loaded up front or in the background.
+    pub fn from_data(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        assets: &mut Assets,
+        data: ModelData,
+        mut load_texture: impl FnMut(
+            &mut Assets,
+            &Path,
+            TextureUsage,
+        ) -> anyhow::Result<Handle<Texture>>,
+    ) -> anyhow::Result<Self> {
+        let mut materials = Vec::new();
+        for mat in data.materials {
+            let translucent = mat.is_translucent();
+            let textures = mat.textures.try_map(|texture, usage| {
+                Ok(match texture {
+                    TextureData::File(path) => load_texture(assets, &path, usage)?,
+                    TextureData::Image { label, image } => {
+                        let texture =
+                            assets.create_texture(device, queue, &image, &label, usage)?;
+                        assets.add_texture(texture)
+                    }
+                    TextureData::Solid(color) => {
+                        assets.solid_texture(device, queue, color, usage.is_linear())
+                    }
+                })
+            })?;
+
+            let material = Material::new(
+                device,
+                &mat.name,
+                assets,
+                textures,
+                mat.factors,
+                translucent,
+                layout,
+            );
+            materials.push(assets.add_material(material));
+        }
+
+        let radius = data
+            .meshes
+            .iter()
+            .flat_map(|mesh| &mesh.vertices)
+            .fold(0.0f32, |radius, v| radius.max(v.position.length()));
+
+        let label = format!("{:?}", data.path);
+        let meshes = data
+            .meshes
+            .into_iter()
+            .map(|mesh| {
+                let mesh = assets.create_mesh(device, &label, mesh);
+                assets.add_mesh(mesh)
+            })
+            .collect();
+
+        Ok(Self {
+            meshes,
+            materials,
+            radius,
+            skeleton: data.skeleton,
+            animations: data.animations,
+        })
+    }
+}
+
+impl Mesh {
+    /// Uploads a single mesh, `label` names its buffers. `layout` is only
+    /// needed for the compact encodings, see [`Mesh::create_bind_group_layout`].
+    pub fn from_data(
+        device: &wgpu::Device,
+        label: &str,
+        mesh: MeshData,
+        encoding: VertexEncoding,
+        layout: Option<&wgpu::BindGroupLayout>,
+    ) -> Self {
+        let (vertex_bytes, dequantize) = encoding.encode(&mesh.vertices);
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{} Vertex Buffer", label)),
+            contents: &vertex_bytes,
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        // Every LOD goes in the same buffer, one after the other
+        let mut lods = Vec::with_capacity(mesh.lods.len() + 1);
+        let mut indices = Vec::with_capacity(mesh.indices.len());
+        for lod in std::iter::once(&mesh.indices).chain(&mesh.lods) {
+            let start = indices.len() as u32;
+            indices.extend_from_slice(lod);
+            lods.push(start..indices.len() as u32);
+        }
+
+        // Half the size when every index fits
+        let (index_format, index_bytes) = if mesh.vertices.len() < 65536 {
+            let indices = indices.iter().map(|&i| i as u16).collect::<Vec<_>>();
+            (IndexFormat::Uint16, bytemuck::cast_slice(&indices).to_vec())
+        } else {
+            (IndexFormat::Uint32, bytemuck::cast_slice(&indices).to_vec())
+        };
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{} Index Buffer", label)),
+            contents: &index_bytes,
+            usage: wgpu::BufferUsages::INDEX,
+        });
+
+        let skin_buffer = mesh.skin.as_ref().map(|skin| {
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{} Skin Buffer", label)),
+                contents: bytemuck::cast_slice(skin),
+                usage: wgpu::BufferUsages::VERTEX,
+            })
+        });
+
+        let (dequantize_buffer, bind_group) = match (dequantize, layout) {
+            (Some(dequantize), Some(layout)) => {
+                let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some(&format!("{} Dequantize Buffer", label)),
+                    contents: bytemuck::cast_slice(&[dequantize]),
+                    usage: wgpu::BufferUsages::UNIFORM,
+                });
+                let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    layout,
+                    entries: &[wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: buffer.as_entire_binding(),
+                    }],
+                    label: Some(label),
+                });
+                (Some(buffer), Some(bind_group))
+            }
+            (Some(_), None) => panic!("{:?} vertices need a mesh bind group layout", encoding),
+            (None, _) => (None, None),
+        };
+
+        Self {
+            name: mesh.name,
+            vertex_buffer,
+            index_buffer,
+            index_format,
+            lods,
+            material: mesh.material,
+            dequantize_buffer,
+            bind_group,
+            skin_buffer,
+        }
+    }
+
+    /// For meshes with one of the compact encodings, which have to be drawn
+    /// with this after the pipeline's other bind groups.
+    pub fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+            label: Some("mesh_bind_group_layout"),
+        })
+    }
+}
+
+impl VertexEncoding {
+    pub fn desc<'a>(self) -> wgpu::VertexBufferLayout<'a> {
+        match self {
+            VertexEncoding::Full => ModelVertex::desc(),
+            VertexEncoding::Compact => CompactVertex::desc(),
+            VertexEncoding::Quantized => QuantizedVertex::desc(),
+        }
+    }
+
+    /// Every shader that draws meshes has a vertex entry point for each
+    /// layout they can come in.
+    pub fn entry_point(self) -> &'static str {
+        match self {
+            VertexEncoding::Full => "main",
+            VertexEncoding::Compact | VertexEncoding::Quantized => "main_compact",
+        }
+    }
+
+    pub fn is_compact(self) -> bool {
+        self != VertexEncoding::Full
+    }
+
+    /// Packs `vertices` into a vertex buffer's worth of bytes, along with
+    /// how to unpack them for the compact encodings.
+    fn encode(self, vertices: &[ModelVertex]) -> (Vec<u8>, Option<DequantizeUniform>) {
+        match self {
+            VertexEncoding::Full => (bytemuck::cast_slice(vertices).to_vec(), None),
+            VertexEncoding::Compact => {
+                let compact = vertices
+                    .iter()
+                    .map(|v| CompactVertex {
+                        position: [
+                            v.position.x,
+                            v.position.y,
+                            v.position.z,
+                            if v.tangent[3] < 0.0 { 0.0 } else { 1.0 },
+                        ],
+                        tex_coords: encode_half(v.tex_coords),
+                        normal: encode_octahedral(v.normal),
+                        tangent: encode_octahedral(Vec3::new(
+                            v.tangent[0],
+                            v.tangent[1],
+                            v.tangent[2],
+                        )),
+                        layer: v.layer,
+                    })
+                    .collect::<Vec<_>>();
+                let dequantize = DequantizeUniform::new(Vec3::ZERO, Vec3::ONE);
+                (bytemuck::cast_slice(&compact).to_vec(), Some(dequantize))
+            }
+            VertexEncoding::Quantized => {
+                let (min, max) = vertices.iter().fold(
+                    (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+                    |(min, max), v| (min.min(v.position), max.max(v.position)),
+                );
+                // Flat meshes have no extent along one axis, everything
+                // there quantizes to zero
+                let extent = (max - min).max(Vec3::splat(f32::MIN_POSITIVE));
+
+                let quantized = vertices
+                    .iter()
+                    .map(|v| {
+                        let unit = (v.position - min) / extent;
+                        QuantizedVertex {
+                            position: [
+                                encode_unorm16(unit.x),
+                                encode_unorm16(unit.y),
+                                encode_unorm16(unit.z),
+                                if v.tangent[3] < 0.0 { 0 } else { u16::MAX },
+                            ],
+                            tex_coords: encode_half(v.tex_coords),
+                            normal: encode_octahedral(v.normal),
+                            tangent: encode_octahedral(Vec3::new(
+                                v.tangent[0],
+                                v.tangent[1],
+                                v.tangent[2],
+                            )),
+                            layer: v.layer,
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                let dequantize = DequantizeUniform::new(min, extent);
+                (bytemuck::cast_slice(&quantized).to_vec(), Some(dequantize))
+            }
+        }
+    }
+}
+
+impl DequantizeUniform {
+    fn new(offset: Vec3, scale: Vec3) -> Self {
+        Self {
+            offset,
+            _padding0: 0,
+            scale,
+            _padding1: 0,
+        }
+    }
+}
+
+/// Folds a unit vector onto an octahedron and flattens that out into a
+/// square, which spreads two components' worth of precision evenly over
+/// every direction.
+fn encode_octahedral(v: Vec3) -> [i16; 2] {
+    let sum = v.x.abs() + v.y.abs() + v.z.abs();
+    if sum == 0.0 {
+        return [0, 0];
+    }
+    let v = v / sum;
+
+    let folded = if v.z >= 0.0 {
+        Vec2::new(v.x, v.y)
+    } else {
+        // The lower half goes over the diagonals, into the corners
+        (Vec2::ONE - Vec2::new(v.y.abs(), v.x.abs())) * Vec2::new(v.x.signum(), v.y.signum())
+    };
+    [encode_snorm16(folded.x), encode_snorm16(folded.y)]
+}
+
+fn encode_snorm16(x: f32) -> i16 {
+    (x.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16
+}
+
+fn encode_unorm16(x: f32) -> u16 {
+    (x.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16
+}
+
+fn encode_half(v: Vec2) -> [u16; 2] {
+    [
+        half::f16::from_f32(v.x).to_bits(),
+        half::f16::from_f32(v.y).to_bits(),
+    ]
+}
+
+impl ModelData {
+    /// Wraps meshes that weren't loaded from a file, like the ones in
+    /// [`primitives`](crate::primitives), with a plain white material for
+    /// them all to use.
+    pub fn from_meshes(name: &str, meshes: Vec<MeshData>) -> Self {
+        Self {
+            path: PathBuf::from(name),
+            sources: Vec::new(),
+            meshes,
+            materials: vec![MaterialData::untextured(name)],
+            skeleton: None,
+            animations: Vec::new(),
+        }
+    }
+
+    /// Reads a model, picking the format from the file extension. Doesn't
+    /// need the GPU, so this is safe to run off the render thread.
+    ///
+    /// Goes through the mesh cache, the first load of a model writes it and
+    /// later ones read it back as long as the source files haven't changed.
+    pub fn load(source: &AssetSource, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let mut data = match mesh_cache::read(source, path.as_ref()) {
+            Some(data) => data,
+            None => {
+                let data = Self::load_uncached(source, path)?;
+                if let Err(e) = mesh_cache::write(source, &data) {
+                    log::warn!("Failed to cache {:?}: {:?}", data.path, e);
+                }
+                data
+            }
+        };
+        data.pack_textures(source);
+        Ok(data)
+    }
+
+    /// Merges materials that only differ by their diffuse texture, so their
+    /// meshes share one bind group. Only materials whose other textures are
+    /// all solid colors are merged.
+    ///
+    /// Diffuse textures that are the same size become the layers of a
+    /// texture array, picked by each vertex's `layer`. Small ones left over
+    /// get packed into an atlas instead, as long as their meshes keep their
+    /// UVs in 0..1, since tiling doesn't survive it.
+    ///
+    /// Runs after the mesh cache, so this is redone on every load, but a
+    /// material's texture file changing on its own doesn't refresh it.
+    fn pack_textures(&mut self, source: &AssetSource) {
+        let mut candidates = Vec::new();
+        for (index, material) in self.materials.iter().enumerate() {
+            let mut meshes = self.meshes.iter().filter(|mesh| mesh.material == index);
+            let mut has_meshes = false;
+            let uvs_in_range = meshes.all(|mesh| {
+                has_meshes = true;
+                mesh.vertices.iter().all(|vertex| {
+                    let uv = vertex.tex_coords;
+                    uv.cmpge(Vec2::splat(-1e-3)).all() && uv.cmple(Vec2::splat(1.0 + 1e-3)).all()
+                })
+            });
+            if !has_meshes {
+                continue;
+            }
+
+            let colors = match material.solid_colors() {
+                Some(colors) => colors,
+                None => continue,
+            };
+            if let Some(image) = material.diffuse_image(source) {
+                candidates.push((index, colors, image, uvs_in_range));
+            }
+        }
+
+        // Materials can only be merged if everything but the diffuse
+        // texture matches
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+        for (candidate, (index, colors, ..)) in candidates.iter().enumerate() {
+            let group = groups.iter_mut().find(|group| {
+                let (first, first_colors, ..) = &candidates[group[0]];
+                first_colors == colors
+                    && self.materials[*first].factors == self.materials[*index].factors
+            });
+            match group {
+                Some(group) => group.push(candidate),
+                None => groups.push(vec![candidate]),
+            }
+        }
+
+        let mut merged_into = (0..self.materials.len()).collect::<Vec<_>>();
+        let mut images = candidates
+            .into_iter()
+            .map(|(index, _, image, uvs_in_range)| (index, Some(image), uvs_in_range))
+            .collect::<Vec<_>>();
+        for group in groups.into_iter().filter(|group| group.len() > 1) {
+            let mut sizes: Vec<Vec<usize>> = Vec::new();
+            for candidate in group {
+                let dimensions = images[candidate].1.as_ref().map(RgbaImage::dimensions);
+                match sizes.iter_mut().find(|size| {
+                    images[size[0]].1.as_ref().map(RgbaImage::dimensions) == dimensions
+                }) {
+                    Some(size) => size.push(candidate),
+                    None => sizes.push(vec![candidate]),
+                }
+            }
+
+            let mut leftover = Vec::new();
+            for size in sizes {
+                for layers in size.chunks(MAX_ARRAY_LAYERS) {
+                    if layers.len() > 1 {
+                        self.merge_into_array(layers, &mut images, &mut merged_into);
+                    } else {
+                        leftover.extend_from_slice(layers);
+                    }
+                }
+            }
+
+            leftover.retain(|&candidate| {
+                let (_, image, uvs_in_range) = &images[candidate];
+                *uvs_in_range
+                    && image.as_ref().map_or(false, |image| {
+                        image.width() <= MAX_ATLAS_IMAGE_SIZE
+                            && image.height() <= MAX_ATLAS_IMAGE_SIZE
+                    })
+            });
+            if leftover.len() > 1 {
+                self.merge_into_atlas(&leftover, &mut images, &mut merged_into);
+            }
+        }
+
+        // Drop the merged materials and point the meshes at what's left
+        let mut new_indices = Vec::with_capacity(merged_into.len());
+        let mut kept = 0;
+        for (index, &into) in merged_into.iter().enumerate() {
+            new_indices.push(kept);
+            if into == index {
+                kept += 1;
+            }
+        }
+        for mesh in &mut self.meshes {
+            mesh.material = new_indices[merged_into[mesh.material]];
+        }
+        let mut index = 0;
+        self.materials.retain(|_| {
+            let keep = merged_into[index] == index;
+            index += 1;
+            keep
+        });
+    }
+
+    /// Makes the first of `candidates`' materials a texture array of all
+    /// their diffuse images, and points the others' vertices at its layers.
+    fn merge_into_array(
+        &mut self,
+        candidates: &[usize],
+        images: &mut [(usize, Option<RgbaImage>, bool)],
+        merged_into: &mut [usize],
+    ) {
+        let materials = candidates.iter().map(|&i| images[i].0).collect::<Vec<_>>();
+        let layers = candidates
+            .iter()
+            .filter_map(|&i| images[i].1.take())
+            .collect::<Vec<_>>();
+
+        for mesh in &mut self.meshes {
+            if let Some(layer) = materials.iter().position(|&index| index == mesh.material) {
+                for vertex in &mut mesh.vertices {
+                    vertex.layer = layer as u32;
+                }
+            }
+        }
+        self.merge_materials(&materials, merged_into, |name| TextureData::Image {
+            label: format!("{} array", name),
+            image: ImageData::Layers(layers),
+        });
+    }
+
+    /// Like [`Self::merge_into_array`], but packing the images into an atlas
+    /// and remapping the UVs. Leaves the materials alone if they don't fit.
+    fn merge_into_atlas(
+        &mut self,
+        candidates: &[usize],
+        images: &mut [(usize, Option<RgbaImage>, bool)],
+        merged_into: &mut [usize],
+    ) {
+        let materials = candidates.iter().map(|&i| images[i].0).collect::<Vec<_>>();
+        let group_images = candidates
+            .iter()
+            .filter_map(|&i| images[i].1.take())
+            .collect::<Vec<_>>();
+        let atlas = match TextureAtlas::new(&group_images, MAX_ATLAS_SIZE) {
+            Some(atlas) => atlas,
+            None => {
+                log::debug!("Couldn't fit {:?} textures into an atlas", self.path);
+                return;
+            }
+        };
+
+        for mesh in &mut self.meshes {
+            if let Some(region) = materials.iter().position(|&index| index == mesh.material) {
+                atlas.remap_vertices(region, &mut mesh.vertices);
+            }
+        }
+        self.merge_materials(&materials, merged_into, |name| TextureData::Image {
+            label: format!("{} atlas", name),
+            image: ImageData::Mipmapped(atlas.levels),
+        });
+    }
+
+    /// Marks all of `materials` but the first as merged into it, and gives
+    /// that one the combined diffuse texture.
+    fn merge_materials(
+        &mut self,
+        materials: &[usize],
+        merged_into: &mut [usize],
+        diffuse: impl FnOnce(String) -> TextureData,
+    ) {
+        for &index in &materials[1..] {
+            merged_into[index] = materials[0];
+        }
+        let merged = &mut self.materials[materials[0]];
+        merged.textures.diffuse = diffuse(format!("{:?} {}", self.path, merged.name));
+    }
+
+    fn load_uncached(source: &AssetSource, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let extension = path
+            .as_ref()
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+
+        let mut data = match extension.as_deref() {
+            Some("gltf") | Some("glb") => gltf_model::load(source, path.as_ref())?,
+            _ => Self::load_obj(source, path)?,
+        };
+        for mesh in &mut data.meshes {
+            mesh.optimize();
+            // Skinned meshes are always drawn at full detail
+            if mesh.skin.is_none() {
+                mesh.generate_lods();
+            }
+        }
+        Ok(data)
+    }
+
+    fn load_obj(source: &AssetSource, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        // We're assuming that the mtl and texture files are stored with the obj file
+        let containing_folder = path.as_ref().parent().context("Directory has no parent")?;
+
+        let obj = source.read(&path)?;
+        // tobj only hands us a `Fn`
+        let sources = RefCell::new(vec![path.as_ref().to_path_buf()]);
+        let (obj_models, obj_materials) = tobj::load_obj_buf(
+            &mut Cursor::new(obj),
             &LoadOptions {
                 triangulate: true,
                 single_index: true,
                 ..Default::default()
             },
-        )?;
-
-        let obj_materials = obj_materials?;
+            |mtl_path| {
+                let mtl_path = containing_folder.join(mtl_path);
+                let mtl = source
+                    .read(&mtl_path)
+                    .map_err(|_| tobj::LoadError::OpenFileFailed)?;
+                sources.borrow_mut().push(mtl_path);
+                tobj::load_mtl_buf(&mut Cursor::new(mtl))
+            },
+        )
+        .context("Malformed OBJ")?;
 
-        // We're assuming that the texture files are stored with the obj file
-        let containing_folder = path.as_ref().parent().context("Directory has no parent")?;
+        // The geometry is still usable without its materials
+        let obj_materials = obj_materials.unwrap_or_else(|e| {
+            log::warn!(
+                "Failed to load the materials for {:?}: {}",
+                path.as_ref(),
+                e
+            );
+            Vec::new()
+        });
 
-        let mut materials = Vec::new();
-        for mat in obj_materials {
-            let diffuse_path = mat.diffuse_texture;
-            let diffuse_texture =
-                Texture::load(device, queue, containing_folder.join(diffuse_path), false)?;
+        let mut materials = obj_materials
+            .into_iter()
+            .map(|mat| {
+                // tobj doesn't know about `map_Ke` or `disp`
+                let files = MaterialTextures {
+                    diffuse: Some(&mat.diffuse_texture),
+                    normal: Some(&mat.normal_texture),
+                    specular: Some(&mat.specular_texture),
+                    shininess: Some(&mat.shininess_texture),
+                    emissive: mat.unknown_param.get("map_Ke"),
+                    opacity: Some(&mat.dissolve_texture),
+                    height: mat.unknown_param.get("disp"),
+                };
+                let textures = files.map(|file, usage| {
+                    // The statement can have options in front of the file
+                    let file = file.map(|file| parse_texture_options(file).1);
+                    match file.filter(|file| !file.is_empty()) {
+                        Some(file) => TextureData::File(containing_folder.join(file)),
+                        None => TextureData::Solid(usage.fallback()),
+                    }
+                });
 
-            let normal_path = mat.normal_texture;
-            let normal_texture =
-                Texture::load(device, queue, containing_folder.join(normal_path), true)?;
+                MaterialData {
+                    factors: MaterialFactors::from_mtl(&mat),
+                    textures,
+                    name: mat.name,
+                }
+            })
+            .collect::<Vec<_>>();
 
-            materials.push(Material::new(
-                device,
-                &mat.name,
-                diffuse_texture,
-                normal_texture,
-                layout,
-            ));
-        }
+        // Only added if some mesh doesn't have a material
+        let mut default_material = None;
 
         let mut meshes = Vec::new();
         for m in obj_models {
+            let mesh = m.mesh;
+            let vertex_count = mesh.positions.len() / 3;
+            let has_tex_coords = !mesh.texcoords.is_empty();
+            let has_normals = !mesh.normals.is_empty();
+
+            if has_tex_coords && mesh.texcoords.len() != vertex_count * 2 {
+                anyhow::bail!(
+                    "{} has {} texture coordinates for {} vertices",
+                    m.name,
+                    mesh.texcoords.len() / 2,
+                    vertex_count
+                );
+            }
+            if has_normals && mesh.normals.len() != vertex_count * 3 {
+                anyhow::bail!(
+                    "{} has {} normals for {} vertices",
+                    m.name,
+                    mesh.normals.len() / 3,
+                    vertex_count
+                );
+            }
+            if mesh.indices.len() % 3 != 0 {
+                anyhow::bail!("{} has a face that isn't a triangle", m.name);
+            }
+            if let Some(index) = mesh.indices.iter().find(|&&i| i as usize >= vertex_count) {
+                anyhow::bail!(
+                    "{} uses vertex {} but only has {}",
+                    m.name,
+                    index,
+                    vertex_count
+                );
+            }
+
             let mut vertices = Vec::new();
-            for i in 0..m.mesh.positions.len() / 3 {
+            for i in 0..vertex_count {
                 vertices.push(ModelVertex {
                     position: Vec3::new(
-                        m.mesh.positions[i * 3],
-                        m.mesh.positions[i * 3 + 1],
-                        m.mesh.positions[i * 3 + 2],
+                        mesh.positions[i * 3],
+                        mesh.positions[i * 3 + 1],
+                        mesh.positions[i * 3 + 2],
                     ),
-                    tex_coords: Vec2::new(m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]),
-                    normal: Vec3::new(
-                        m.mesh.normals[i * 3],
-                        m.mesh.normals[i * 3 + 1],
-                        m.mesh.normals[i * 3 + 2],
-                    ),
-                    tangent: Vec3::ZERO,
-                    bitangent: Vec3::ZERO,
+                    tex_coords: if has_tex_coords {
+                        Vec2::new(mesh.texcoords[i * 2], mesh.texcoords[i * 2 + 1])
+                    } else {
+                        Vec2::ZERO
+                    },
+                    normal: if has_normals {
+                        Vec3::new(
+                            mesh.normals[i * 3],
+                            mesh.normals[i * 3 + 1],
+                            mesh.normals[i * 3 + 2],
+                        )
+                    } else {
+                        Vec3::ZERO
+                    },
+                    tangent: [0.0; 4],
+                    layer: 0,
                 });
             }
 
-            let indices = &m.mesh.indices;
-            let mut triangles_included = (0..vertices.len()).collect::<Vec<_>>();
-
-            // Calculate tangents and bitangets. We're going to
-            // use the triangles, so we need to loop through the
-            // indices in chunks of 3
-            for c in indices.chunks(3) {
-                let v0 = vertices[c[0] as usize];
-                let v1 = vertices[c[1] as usize];
-                let v2 = vertices[c[2] as usize];
-
-                let pos0 = v0.position;
-                let pos1 = v1.position;
-                let pos2 = v2.position;
-
-                let uv0 = v0.tex_coords;
-                let uv1 = v1.tex_coords;
-                let uv2 = v2.tex_coords;
-
-                // Calculate the edges of the triangle
-                let delta_pos1 = pos1 - pos0;
-                let delta_pos2 = pos2 - pos0;
-
-                // This will give us a direction to calculate the
-                // tangent and bitangent
-                let delta_uv1 = uv1 - uv0;
-                let delta_uv2 = uv2 - uv0;
-
-                // Solving the following system of equations will
-                // give us the tangent and bitangent.
-                //     delta_pos1 = delta_uv1.x * T + delta_u.y * B
-                //     delta_pos2 = delta_uv2.x * T + delta_uv2.y * B
-                // Luckily, the place I found this equation provided
-                // the solution!
-                let r = 1.0 / (delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x);
-                let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
-                let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * r;
-
-                // We'll use the same tangent/bitangent for each vertex in the triangle
-                vertices[c[0] as usize].tangent =
-                    (tangent + vertices[c[0] as usize].tangent).into();
-                vertices[c[1] as usize].tangent =
-                    (tangent + vertices[c[1] as usize].tangent).into();
-                vertices[c[2] as usize].tangent =
-                    (tangent + vertices[c[2] as usize].tangent).into();
-                vertices[c[0] as usize].bitangent =
-                    (bitangent + vertices[c[0] as usize].bitangent).into();
-                vertices[c[1] as usize].bitangent =
-                    (bitangent + vertices[c[1] as usize].bitangent).into();
-                vertices[c[2] as usize].bitangent =
-                    (bitangent + vertices[c[2] as usize].bitangent).into();
-
-                // Used to average the tangents/bitangents
-                triangles_included[c[0] as usize] += 1;
-                triangles_included[c[1] as usize] += 1;
-                triangles_included[c[2] as usize] += 1;
-            }
-
-            // Average the tangents/bitangents
-            for (i, n) in triangles_included.into_iter().enumerate() {
-                let denom = 1.0 / n as f32;
-                let mut v = &mut vertices[i];
-                v.tangent = (v.tangent * denom).normalize().into();
-                v.bitangent = (v.bitangent * denom).normalize().into();
+            let mut indices = mesh.indices;
+            if !has_normals {
+                generate_normals(&mut vertices, &mut indices, None);
             }
+            compute_tangents(&mut vertices, &mut indices, None);
 
-            let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Vertex Buffer", path.as_ref())),
-                contents: bytemuck::cast_slice(&vertices),
-                usage: wgpu::BufferUsages::VERTEX,
-            });
-            let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Index Buffer", path.as_ref())),
-                contents: bytemuck::cast_slice(&m.mesh.indices),
-                usage: wgpu::BufferUsages::INDEX,
-            });
+            let material = match mesh.material_id {
+                Some(id) if id < materials.len() => id,
+                Some(id) => anyhow::bail!(
+                    "{} uses material {} but there are only {}",
+                    m.name,
+                    id,
+                    materials.len()
+                ),
+                None => *default_material.get_or_insert_with(|| {
+                    materials.push(MaterialData::untextured("OBJ default material"));
+                    materials.len() - 1
+                }),
+            };
 
-            meshes.push(Mesh {
+            meshes.push(MeshData {
                 name: m.name,
-                vertex_buffer,
-                index_buffer,
-                num_elements: m.mesh.indices.len() as u32,
-                material: m.mesh.material_id.unwrap_or(0),
+                vertices,
+                indices,
+                lods: Vec::new(),
+                material,
+                skin: None,
             });
         }
 
-        Ok(Self { meshes, materials })
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+            sources: sources.into_inner(),
+            meshes,
+            materials,
+            skeleton: None,
+            animations: Vec::new(),
+        })
+    }
+}
+
+impl MeshData {
+    /// Welds identical vertices, then reorders the triangles so the GPU's
+    /// post-transform cache gets more hits and the vertices so they're
+    /// fetched in order.
+    ///
+    /// The vertices move around, so any LODs are dropped and need
+    /// generating again afterwards. The skin moves with them.
+    pub fn optimize(&mut self) {
+        // Vertices only weld when their joints and weights match too
+        let (vertex_count, remap) = match &self.skin {
+            Some(skin) => meshopt::generate_vertex_remap_multi(
+                self.vertices.len(),
+                &[
+                    meshopt::VertexStream::new(self.vertices.as_ptr()),
+                    meshopt::VertexStream::new(skin.as_ptr()),
+                ],
+                Some(&self.indices),
+            ),
+            None => meshopt::generate_vertex_remap(&self.vertices, Some(&self.indices)),
+        };
+        self.remap(vertex_count, &remap);
+
+        self.indices = meshopt::optimize_vertex_cache(&self.indices, vertex_count);
+        let fetch_order = meshopt::optimize_vertex_fetch_remap(&self.indices, vertex_count);
+        self.remap(vertex_count, &fetch_order);
+        self.lods.clear();
+    }
+
+    /// Moves vertex `i`, and its skin, to `remap[i]`.
+    fn remap(&mut self, vertex_count: usize, remap: &[u32]) {
+        self.indices = meshopt::remap_index_buffer(Some(&self.indices), vertex_count, remap);
+        self.vertices = meshopt::remap_vertex_buffer(&self.vertices, vertex_count, remap);
+        if let Some(skin) = &mut self.skin {
+            *skin = meshopt::remap_vertex_buffer(skin, vertex_count, remap);
+        }
+    }
+
+    /// Simplifies the mesh into a LOD for each of [`LOD_SCREEN_SIZES`],
+    /// roughly halving the triangles each time. Stops early once
+    /// simplifying stops getting anywhere without breaking the surface.
+    pub fn generate_lods(&mut self) {
+        self.lods.clear();
+        let mut target_error = LOD_TARGET_ERROR;
+        for _ in 0..LOD_SCREEN_SIZES.len() {
+            let previous = self.lods.last().unwrap_or(&self.indices);
+            let target_count = previous.len() / 6 * 3;
+            let lod =
+                meshopt::simplify_decoder(previous, &self.vertices, target_count, target_error);
+            // Not worth a LOD of its own
+            if lod.is_empty() || lod.len() * 5 > previous.len() * 4 {
+                break;
+            }
+
+            let lod = meshopt::optimize_vertex_cache(&lod, self.vertices.len());
+            self.lods.push(lod);
+            target_error *= 2.0;
+        }
+    }
+}
+
+impl meshopt::DecodePosition for ModelVertex {
+    fn decode_position(&self) -> [f32; 3] {
+        self.position.into()
+    }
+}
+
+/// Which LOD to draw something at when it covers `screen_size` of the
+/// screen's height. Meshes that didn't simplify that far use their coarsest.
+pub fn lod_for_screen_size(screen_size: f32) -> usize {
+    LOD_SCREEN_SIZES
+        .iter()
+        .position(|&min| screen_size >= min)
+        .unwrap_or(LOD_SCREEN_SIZES.len())
+}
+
+/// Splits an MTL texture statement like `-mm 0 0.1 height.png` into its
+/// options and the file name. Only numeric arguments are kept.
+fn parse_texture_options(statement: &str) -> (HashMap<&str, Vec<f32>>, &str) {
+    // These take a word rather than numbers
+    const WORD_OPTIONS: &[&str] = &["-blendu", "-blendv", "-cc", "-clamp", "-imfchan", "-type"];
+
+    fn next_word(s: &str) -> (&str, &str) {
+        let s = s.trim_start();
+        match s.find(char::is_whitespace) {
+            Some(end) => (&s[..end], &s[end..]),
+            None => (s, ""),
+        }
+    }
+
+    let mut options = HashMap::new();
+    let mut rest = statement.trim();
+    while rest.starts_with('-') {
+        let (name, after) = next_word(rest);
+        rest = after;
+
+        let mut args = Vec::new();
+        if WORD_OPTIONS.contains(&name) {
+            rest = next_word(rest).1;
+        } else {
+            loop {
+                let (word, after) = next_word(rest);
+                match word.parse() {
+                    Ok(arg) => args.push(arg),
+                    Err(_) => break,
+                }
+                rest = after;
+            }
+        }
+        options.insert(name, args);
+    }
+
+    (options, rest.trim())
+}
+
+/// Works out normals from the faces, for meshes that don't come with any.
+///
+/// Normals are averaged across faces that meet at less than
+/// [`CREASE_ANGLE`], anything sharper gets a hard edge, which means
+/// splitting the vertices there. A skin gets split along with them.
+pub(crate) fn generate_normals(
+    vertices: &mut Vec<ModelVertex>,
+    indices: &mut [u32],
+    skin: Option<&mut Vec<SkinVertex>>,
+) {
+    // Left unnormalized so bigger faces count for more
+    let face_normals = indices
+        .chunks(3)
+        .map(|c| {
+            let p0 = vertices[c[0] as usize].position;
+            let p1 = vertices[c[1] as usize].position;
+            let p2 = vertices[c[2] as usize].position;
+            (p1 - p0).cross(p2 - p0)
+        })
+        .collect::<Vec<_>>();
+
+    let mut faces_using = vec![Vec::new(); vertices.len()];
+    for (face, c) in indices.chunks(3).enumerate() {
+        for &i in c {
+            faces_using[i as usize].push(face);
+        }
+    }
+
+    let min_cos = CREASE_ANGLE.cos();
+    let normals = indices
+        .iter()
+        .enumerate()
+        .map(|(corner, &index)| {
+            let faces = &faces_using[index as usize];
+            let face_normal = face_normals[corner / 3].normalize_or_zero();
+
+            let normal = faces
+                .iter()
+                .map(|&face| face_normals[face])
+                .filter(|n| n.normalize_or_zero().dot(face_normal) >= min_cos)
+                .fold(Vec3::ZERO, |sum, n| sum + n)
+                .normalize_or_zero();
+            if normal != Vec3::ZERO {
+                return normal;
+            }
+
+            // Degenerate faces don't have a direction of their own, so
+            // borrow one from their neighbours
+            let normal = faces
+                .iter()
+                .fold(Vec3::ZERO, |sum, &face| sum + face_normals[face])
+                .normalize_or_zero();
+            if normal != Vec3::ZERO {
+                normal
+            } else {
+                Vec3::Y
+            }
+        })
+        .collect::<Vec<_>>();
+
+    split_vertices(vertices, indices, skin, |corner, vertex| {
+        vertex.normal = normals[corner]
+    });
+}
+
+/// Generates MikkTSpace tangents, which is what Blender and most bakers
+/// use, so normal maps made elsewhere come out the right way round.
+///
+/// `w` holds the handedness, the bitangent is `cross(normal, tangent) * w`.
+pub(crate) fn compute_tangents(
+    vertices: &mut Vec<ModelVertex>,
+    indices: &mut [u32],
+    skin: Option<&mut Vec<SkinVertex>>,
+) {
+    struct Corners<'a> {
+        vertices: &'a [ModelVertex],
+        indices: &'a [u32],
+        tangents: Vec<[f32; 4]>,
+    }
+
+    impl Corners<'_> {
+        fn vertex(&self, face: usize, vert: usize) -> &ModelVertex {
+            &self.vertices[self.indices[face * 3 + vert] as usize]
+        }
+    }
+
+    impl mikktspace::Geometry for Corners<'_> {
+        fn num_faces(&self) -> usize {
+            self.indices.len() / 3
+        }
+
+        fn num_vertices_of_face(&self, _face: usize) -> usize {
+            3
+        }
+
+        fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+            self.vertex(face, vert).position.into()
+        }
+
+        fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+            self.vertex(face, vert).normal.into()
+        }
+
+        fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+            self.vertex(face, vert).tex_coords.into()
+        }
+
+        fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+            self.tangents[face * 3 + vert] = tangent;
+        }
+    }
+
+    let corner_count = indices.len();
+    let mut corners = Corners {
+        vertices,
+        indices,
+        tangents: vec![[0.0; 4]; corner_count],
+    };
+    if !mikktspace::generate_tangents(&mut corners) {
+        log::warn!("Failed to generate tangents");
+    }
+
+    let tangents = corners.tangents;
+    split_vertices(vertices, indices, skin, |corner, vertex| {
+        let [x, y, z, w] = tangents[corner];
+        let direction = Vec3::new(x, y, z);
+
+        // Without usable UVs any direction along the surface is as good as
+        // another
+        vertex.tangent = if direction.is_finite() && direction != Vec3::ZERO {
+            [x, y, z, if w < 0.0 { -1.0 } else { 1.0 }]
+        } else {
+            let direction = vertex.normal.normalize_or_zero().any_orthonormal_vector();
+            [direction.x, direction.y, direction.z, 1.0]
+        };
+    });
+}
+
+/// Gives every corner its own copy of its vertex, changed by `update`, then
+/// merges the copies that came out identical. Used when an attribute can
+/// differ between the faces sharing a vertex.
+fn split_vertices(
+    vertices: &mut Vec<ModelVertex>,
+    indices: &mut [u32],
+    skin: Option<&mut Vec<SkinVertex>>,
+    mut update: impl FnMut(usize, &mut ModelVertex),
+) {
+    let mut merged = HashMap::new();
+    let mut new_vertices = Vec::with_capacity(vertices.len());
+    let mut new_skin = Vec::new();
+    for (corner, index) in indices.iter_mut().enumerate() {
+        let mut vertex = vertices[*index as usize];
+        update(corner, &mut vertex);
+        let vertex_skin = skin.as_deref().map(|skin| skin[*index as usize]);
+
+        // Only exact duplicates get merged, so comparing bytes is fine
+        let mut key = bytemuck::bytes_of(&vertex).to_vec();
+        if let Some(vertex_skin) = &vertex_skin {
+            key.extend_from_slice(bytemuck::bytes_of(vertex_skin));
+        }
+        *index = *merged.entry(key).or_insert_with(|| {
+            new_vertices.push(vertex);
+            new_skin.extend(vertex_skin);
+            (new_vertices.len() - 1) as u32
+        });
+    }
+
+    *vertices = new_vertices;
+    if let Some(skin) = skin {
+        *skin = new_skin;
     }
 }
 
@@ -199,40 +1547,110 @@ impl Material {
     pub fn new(
         device: &wgpu::Device,
         name: &str,
-        diffuse_texture: Texture,
-        normal_texture: Texture,
+        assets: &Assets,
+        textures: MaterialTextures<Handle<Texture>>,
+        factors: MaterialFactors,
+        translucent: bool,
         layout: &wgpu::BindGroupLayout,
     ) -> Self {
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: wgpu::BindingResource::TextureView(&normal_texture.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 3,
-                    resource: wgpu::BindingResource::Sampler(&normal_texture.sampler),
-                },
-            ],
-            label: Some(name),
+        let factors_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{} Factors Buffer", name)),
+            contents: bytemuck::cast_slice(&[factors.to_uniform()]),
+            usage: wgpu::BufferUsages::UNIFORM,
         });
 
+        let bind_group = Self::create_bind_group(
+            device,
+            layout,
+            name,
+            textures.as_ref().map(|handle, _| assets.texture(handle)),
+            &factors_buffer,
+        );
+
         Self {
             name: String::from(name),
-            diffuse_texture,
-            normal_texture,
+            textures,
+            factors,
+            translucent,
+            factors_buffer,
             bind_group,
         }
     }
+
+    /// Every texture gets a texture and sampler binding, in the order of
+    /// [`MaterialTextures::iter`], followed by the factors. They're all
+    /// bound as arrays, for materials merged into one with a layer each.
+    pub fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        let mut entries = Vec::new();
+        for (binding, _) in (0..).step_by(2).zip(MaterialTextures::usages().iter()) {
+            entries.push(wgpu::BindGroupLayoutEntry {
+                binding,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2Array,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            });
+            entries.push(wgpu::BindGroupLayoutEntry {
+                binding: binding + 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler {
+                    comparison: false,
+                    filtering: true,
+                },
+                count: None,
+            });
+        }
+        entries.push(wgpu::BindGroupLayoutEntry {
+            binding: entries.len() as u32,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        });
+
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &entries,
+            label: Some("texture_bind_group_layout"),
+        })
+    }
+
+    /// Bind groups hold on to the texture views they were made with, so
+    /// this needs calling again whenever one of the textures is replaced.
+    pub fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        name: &str,
+        textures: MaterialTextures<&Texture>,
+        factors: &Buffer,
+    ) -> BindGroup {
+        let mut entries = Vec::new();
+        for (binding, (texture, _)) in (0..).step_by(2).zip(textures.iter()) {
+            entries.push(wgpu::BindGroupEntry {
+                binding,
+                resource: wgpu::BindingResource::TextureView(&texture.view),
+            });
+            entries.push(wgpu::BindGroupEntry {
+                binding: binding + 1,
+                resource: wgpu::BindingResource::Sampler(&texture.sampler),
+            });
+        }
+        entries.push(wgpu::BindGroupEntry {
+            binding: entries.len() as u32,
+            resource: factors.as_entire_binding(),
+        });
+
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &entries,
+            label: Some(name),
+        })
+    }
 }
 
 impl Vertex for ModelVertex {
@@ -260,12 +1678,109 @@ impl Vertex for ModelVertex {
                 wgpu::VertexAttribute {
                     offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
                     shader_location: 3,
-                    format: wgpu::VertexFormat::Float32x2,
+                    format: wgpu::VertexFormat::Float32x4,
                 },
                 wgpu::VertexAttribute {
-                    offset: mem::size_of::<[f32; 11]>() as wgpu::BufferAddress,
-                    shader_location: 4,
-                    format: wgpu::VertexFormat::Float32x3,
+                    offset: mem::size_of::<[f32; 12]>() as wgpu::BufferAddress,
+                    shader_location: 13,
+                    format: wgpu::VertexFormat::Uint32,
+                },
+            ],
+        }
+    }
+}
+
+impl Vertex for CompactVertex {
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        use std::mem;
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<CompactVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float16x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                    format: wgpu::VertexFormat::Snorm16x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 6]>() as wgpu::BufferAddress,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Snorm16x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 7]>() as wgpu::BufferAddress,
+                    shader_location: 13,
+                    format: wgpu::VertexFormat::Uint32,
+                },
+            ],
+        }
+    }
+}
+
+impl Vertex for QuantizedVertex {
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        use std::mem;
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<QuantizedVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Unorm16x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[u16; 4]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float16x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[u16; 6]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                    format: wgpu::VertexFormat::Snorm16x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[u16; 8]>() as wgpu::BufferAddress,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Snorm16x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[u16; 10]>() as wgpu::BufferAddress,
+                    shader_location: 13,
+                    format: wgpu::VertexFormat::Uint32,
+                },
+            ],
+        }
+    }
+}
+
+impl Vertex for SkinVertex {
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        use std::mem;
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<SkinVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                // After the instance attributes, which end at 10
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 11,
+                    format: wgpu::VertexFormat::Uint16x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[u16; 4]>() as wgpu::BufferAddress,
+                    shader_location: 12,
+                    format: wgpu::VertexFormat::Float32x4,
                 },
             ],
         }
@@ -288,29 +1803,65 @@ pub trait DrawModel<'a> {
         camera: &'a wgpu::BindGroup,
         light: &'a wgpu::BindGroup,
     );
+    fn draw_mesh_lod_instanced(
+        &mut self,
+        mesh: &'a Mesh,
+        material: &'a Material,
+        lod: usize,
+        instances: Range<u32>,
+        camera: &'a wgpu::BindGroup,
+        light: &'a wgpu::BindGroup,
+    );
 
     fn draw_model(
         &mut self,
         model: &'a Model,
+        assets: &'a Assets,
         camera: &'a wgpu::BindGroup,
         light: &'a wgpu::BindGroup,
     );
     fn draw_model_instanced(
         &mut self,
         model: &'a Model,
+        assets: &'a Assets,
         instances: Range<u32>,
         camera: &'a wgpu::BindGroup,
         light: &'a wgpu::BindGroup,
     );
+    /// `lods[i]` are the instances to draw at LOD `i`.
+    ///
+    /// Like the rest of these, draws skinned meshes with whatever pipeline
+    /// is set, which leaves them in the pose they were bound in.
+    /// [`draw_posed_model`](crate::render::draw_posed_model) poses them.
+    fn draw_model_lods_instanced(
+        &mut self,
+        model: &'a Model,
+        assets: &'a Assets,
+        lods: &[Range<u32>],
+        camera: &'a wgpu::BindGroup,
+        light: &'a wgpu::BindGroup,
+    );
 
     fn draw_model_instanced_with_material(
         &mut self,
         model: &'a Model,
+        assets: &'a Assets,
         material: &'a Material,
         instances: Range<u32>,
         camera: &'a wgpu::BindGroup,
         light: &'a wgpu::BindGroup,
     );
+
+    /// Needs a skinning pipeline, with `skin` holding the joint matrices.
+    fn draw_skinned_mesh_instanced(
+        &mut self,
+        mesh: &'a Mesh,
+        material: &'a Material,
+        skin: &'a wgpu::BindGroup,
+        instances: Range<u32>,
+        camera: &'a wgpu::BindGroup,
+        light: &'a wgpu::BindGroup,
+    );
 }
 
 impl<'a, 'b> DrawModel<'b> for wgpu::RenderPass<'a>
@@ -335,48 +1886,114 @@ where
         camera: &'b wgpu::BindGroup,
         light: &'b wgpu::BindGroup,
     ) {
+        self.draw_mesh_lod_instanced(mesh, material, 0, instances, camera, light);
+    }
+
+    fn draw_mesh_lod_instanced(
+        &mut self,
+        mesh: &'b Mesh,
+        material: &'b Material,
+        lod: usize,
+        instances: Range<u32>,
+        camera: &'b wgpu::BindGroup,
+        light: &'b wgpu::BindGroup,
+    ) {
+        let indices = mesh.lods[lod.min(mesh.lods.len() - 1)].clone();
         self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-        self.set_index_buffer(mesh.index_buffer.slice(..), IndexFormat::Uint32);
+        self.set_index_buffer(mesh.index_buffer.slice(..), mesh.index_format);
         self.set_bind_group(0, &material.bind_group, &[]);
         self.set_bind_group(1, camera, &[]);
         self.set_bind_group(2, light, &[]);
-        self.draw_indexed(0..mesh.num_elements, 0, instances);
+        if let Some(bind_group) = &mesh.bind_group {
+            self.set_bind_group(3, bind_group, &[]);
+        }
+        self.draw_indexed(indices, 0, instances);
     }
 
     fn draw_model(
         &mut self,
         model: &'b Model,
+        assets: &'b Assets,
         camera: &'b wgpu::BindGroup,
         light: &'b wgpu::BindGroup,
     ) {
-        self.draw_model_instanced(model, 0..1, camera, light);
+        self.draw_model_instanced(model, assets, 0..1, camera, light);
     }
 
     fn draw_model_instanced(
         &mut self,
         model: &'b Model,
+        assets: &'b Assets,
         instances: Range<u32>,
         camera: &'b wgpu::BindGroup,
         light: &'b wgpu::BindGroup,
     ) {
-        for mesh in &model.meshes {
-            let material = &model.materials[mesh.material];
-            self.draw_mesh_instanced(mesh, material, instances.clone(), camera, light);
+        self.draw_model_lods_instanced(model, assets, &[instances], camera, light);
+    }
+
+    fn draw_model_lods_instanced(
+        &mut self,
+        model: &'b Model,
+        assets: &'b Assets,
+        lods: &[Range<u32>],
+        camera: &'b wgpu::BindGroup,
+        light: &'b wgpu::BindGroup,
+    ) {
+        // Translucent meshes go last so there's something behind them to
+        // blend with. They aren't sorted among themselves.
+        for translucent in [false, true] {
+            for mesh in &model.meshes {
+                let mesh = assets.mesh(mesh);
+                let material = assets.material(&model.materials[mesh.material]);
+                if material.translucent != translucent {
+                    continue;
+                }
+                for (lod, instances) in lods.iter().enumerate() {
+                    self.draw_mesh_lod_instanced(
+                        mesh,
+                        material,
+                        lod,
+                        instances.clone(),
+                        camera,
+                        light,
+                    );
+                }
+            }
         }
     }
 
     fn draw_model_instanced_with_material(
         &mut self,
         model: &'b Model,
+        assets: &'b Assets,
         material: &'b Material,
         instances: Range<u32>,
         camera: &'b wgpu::BindGroup,
         light: &'b wgpu::BindGroup,
     ) {
         for mesh in &model.meshes {
+            let mesh = assets.mesh(mesh);
             self.draw_mesh_instanced(mesh, material, instances.clone(), camera, light);
         }
     }
+
+    fn draw_skinned_mesh_instanced(
+        &mut self,
+        mesh: &'b Mesh,
+        material: &'b Material,
+        skin: &'b wgpu::BindGroup,
+        instances: Range<u32>,
+        camera: &'b wgpu::BindGroup,
+        light: &'b wgpu::BindGroup,
+    ) {
+        let skin_buffer = match &mesh.skin_buffer {
+            Some(skin_buffer) => skin_buffer,
+            None => return,
+        };
+        self.set_vertex_buffer(2, skin_buffer.slice(..));
+        self.set_bind_group(3, skin, &[]);
+        self.draw_mesh_lod_instanced(mesh, material, 0, instances, camera, light);
+    }
 }
 
 pub trait DrawLight<'a> {
@@ -397,12 +2014,14 @@ pub trait DrawLight<'a> {
     fn draw_light_model(
         &mut self,
         model: &'a Model,
+        assets: &'a Assets,
         camera: &'a wgpu::BindGroup,
         light: &'a wgpu::BindGroup,
     );
     fn draw_light_model_instanced(
         &mut self,
         model: &'a Model,
+        assets: &'a Assets,
         instances: Range<u32>,
         camera: &'a wgpu::BindGroup,
         light: &'a wgpu::BindGroup,
@@ -430,28 +2049,34 @@ where
         light: &'b wgpu::BindGroup,
     ) {
         self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-        self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        self.set_index_buffer(mesh.index_buffer.slice(..), mesh.index_format);
         self.set_bind_group(0, camera, &[]);
         self.set_bind_group(1, light, &[]);
-        self.draw_indexed(0..mesh.num_elements, 0, instances);
+        if let Some(bind_group) = &mesh.bind_group {
+            self.set_bind_group(2, bind_group, &[]);
+        }
+        self.draw_indexed(mesh.lods[0].clone(), 0, instances);
     }
 
     fn draw_light_model(
         &mut self,
         model: &'b Model,
+        assets: &'b Assets,
         camera: &'b wgpu::BindGroup,
         light: &'b wgpu::BindGroup,
     ) {
-        self.draw_light_model_instanced(model, 0..1, camera, light);
+        self.draw_light_model_instanced(model, assets, 0..1, camera, light);
     }
     fn draw_light_model_instanced(
         &mut self,
         model: &'b Model,
+        assets: &'b Assets,
         instances: Range<u32>,
         camera: &'b wgpu::BindGroup,
         light: &'b wgpu::BindGroup,
     ) {
         for mesh in &model.meshes {
+            let mesh = assets.mesh(mesh);
             self.draw_light_mesh_instanced(mesh, instances.clone(), camera, light);
         }
     }
@@ -0,0 +1,662 @@
+use std::borrow::Cow;
+
+use wgpu::{
+    util::DeviceExt, BindGroup, BindGroupLayout, Buffer, CommandEncoder, Device, PipelineLayout,
+    Queue, RenderPipeline, Sampler, ShaderModule, SurfaceConfiguration, TextureFormat, TextureView,
+};
+
+use crate::texture::Texture;
+
+/// The scene is rendered into a float target so bloom has something to work
+/// with, everything up until the blit into the swapchain stays in this format.
+pub const HDR_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+
+/// How many times bloom halves the bright pass before blurring back up.
+const BLOOM_MIPS: usize = 5;
+
+/// Entries per side of the color grading LUT.
+const LUT_SIZE: u32 = 32;
+
+/// Runtime knobs for the post stack, tweakable from the UI.
+#[derive(Debug, Clone)]
+pub struct PostSettings {
+    pub bloom: bool,
+    pub bloom_threshold: f32,
+    pub bloom_knee: f32,
+    pub bloom_intensity: f32,
+
+    pub exposure: f32,
+
+    pub color_grading: bool,
+    pub lut_strength: f32,
+
+    pub fxaa: bool,
+
+    pub vignette: bool,
+    pub vignette_intensity: f32,
+    pub vignette_radius: f32,
+    pub vignette_softness: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct PostUniform {
+    bloom_threshold: f32,
+    bloom_knee: f32,
+    bloom_intensity: f32,
+    exposure: f32,
+    lut_strength: f32,
+    vignette_intensity: f32,
+    vignette_radius: f32,
+    vignette_softness: f32,
+}
+
+/// Everything that depends on the surface size.
+#[derive(Debug)]
+struct Targets {
+    scene: Texture,
+    bloom: Vec<Texture>,
+    ping_pong: [Texture; 2],
+
+    scene_source: BindGroup,
+    bloom_sources: Vec<BindGroup>,
+    ping_pong_sources: [BindGroup; 2],
+    composite_bloom: BindGroup,
+}
+
+#[derive(Debug)]
+pub struct PostProcess {
+    settings: PostSettings,
+
+    uniform_buffer: Buffer,
+    uniform_bind_group: BindGroup,
+
+    source_layout: BindGroupLayout,
+    bloom_layout: BindGroupLayout,
+    sampler: Sampler,
+
+    threshold_pipeline: RenderPipeline,
+    downsample_pipeline: RenderPipeline,
+    upsample_pipeline: RenderPipeline,
+    composite_pipeline: RenderPipeline,
+    grade_pipeline: RenderPipeline,
+    fxaa_pipeline: RenderPipeline,
+    vignette_pipeline: RenderPipeline,
+    blit_pipeline: RenderPipeline,
+
+    lut: Texture,
+    lut_bind_group: BindGroup,
+
+    targets: Targets,
+}
+
+impl Default for PostSettings {
+    fn default() -> Self {
+        Self {
+            bloom: true,
+            bloom_threshold: 1.0,
+            bloom_knee: 0.5,
+            bloom_intensity: 0.6,
+            exposure: 1.0,
+            color_grading: true,
+            lut_strength: 1.0,
+            fxaa: true,
+            vignette: true,
+            vignette_intensity: 0.5,
+            vignette_radius: 0.75,
+            vignette_softness: 0.45,
+        }
+    }
+}
+
+impl PostSettings {
+    fn to_uniform(&self) -> PostUniform {
+        PostUniform {
+            bloom_threshold: self.bloom_threshold,
+            bloom_knee: self.bloom_knee,
+            // The composite pass always samples the bloom texture, switching
+            // bloom off just stops it contributing
+            bloom_intensity: if self.bloom {
+                self.bloom_intensity
+            } else {
+                0.0
+            },
+            exposure: self.exposure,
+            lut_strength: self.lut_strength,
+            vignette_intensity: self.vignette_intensity,
+            vignette_radius: self.vignette_radius,
+            vignette_softness: self.vignette_softness,
+        }
+    }
+}
+
+impl PostProcess {
+    pub fn new(device: &Device, queue: &Queue, config: &SurfaceConfiguration) -> Self {
+        let settings = PostSettings::default();
+
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Post Settings Buffer"),
+            contents: bytemuck::cast_slice(&[settings.to_uniform()]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let uniform_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+            label: Some("post_uniform_bind_group_layout"),
+        });
+        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &uniform_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.as_entire_binding(),
+            }],
+            label: Some("post_uniform_bind_group"),
+        });
+
+        let source_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                texture_entry(0, wgpu::TextureViewDimension::D2),
+                sampler_entry(1),
+            ],
+            label: Some("post_source_bind_group_layout"),
+        });
+        let bloom_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[texture_entry(0, wgpu::TextureViewDimension::D2)],
+            label: Some("post_bloom_bind_group_layout"),
+        });
+        let lut_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                texture_entry(1, wgpu::TextureViewDimension::D3),
+                sampler_entry(2),
+            ],
+            label: Some("post_lut_bind_group_layout"),
+        });
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("post_shader"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!(
+                "../shaders/wgsl/post.wgsl"
+            ))),
+        });
+
+        let layout = |label: &str, extra: Option<&BindGroupLayout>| {
+            let mut bind_group_layouts = vec![&source_layout, &uniform_layout];
+            bind_group_layouts.extend(extra);
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some(label),
+                bind_group_layouts: &bind_group_layouts,
+                push_constant_ranges: &[],
+            })
+        };
+        let base_layout = layout("Post Pipeline Layout", None);
+        let composite_layout = layout("Composite Pipeline Layout", Some(&bloom_layout));
+        let grade_layout = layout("Grade Pipeline Layout", Some(&lut_layout));
+
+        let additive = wgpu::BlendState {
+            color: wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::One,
+                dst_factor: wgpu::BlendFactor::One,
+                operation: wgpu::BlendOperation::Add,
+            },
+            alpha: wgpu::BlendComponent::REPLACE,
+        };
+
+        let pipeline = |name, layout, entry_point, format, blend| {
+            create_post_pipeline(name, device, layout, &shader, entry_point, format, blend)
+        };
+        let threshold_pipeline = pipeline(
+            "bloom_threshold",
+            &base_layout,
+            "threshold",
+            HDR_FORMAT,
+            None,
+        );
+        let downsample_pipeline = pipeline(
+            "bloom_downsample",
+            &base_layout,
+            "downsample",
+            HDR_FORMAT,
+            None,
+        );
+        let upsample_pipeline = pipeline(
+            "bloom_upsample",
+            &base_layout,
+            "upsample",
+            HDR_FORMAT,
+            Some(additive),
+        );
+        let composite_pipeline = pipeline(
+            "composite",
+            &composite_layout,
+            "composite",
+            HDR_FORMAT,
+            None,
+        );
+        let grade_pipeline = pipeline("color_grade", &grade_layout, "grade", HDR_FORMAT, None);
+        let fxaa_pipeline = pipeline("fxaa", &base_layout, "fxaa", HDR_FORMAT, None);
+        let vignette_pipeline = pipeline("vignette", &base_layout, "vignette", HDR_FORMAT, None);
+        let blit_pipeline = pipeline("blit", &base_layout, "blit", config.format, None);
+
+        let lut = create_lut(device, queue);
+        let lut_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &lut_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&lut.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&lut.sampler),
+                },
+            ],
+            label: Some("post_lut_bind_group"),
+        });
+
+        let targets = Targets::new(device, config, &source_layout, &bloom_layout, &sampler);
+
+        Self {
+            settings,
+            uniform_buffer,
+            uniform_bind_group,
+            source_layout,
+            bloom_layout,
+            sampler,
+            threshold_pipeline,
+            downsample_pipeline,
+            upsample_pipeline,
+            composite_pipeline,
+            grade_pipeline,
+            fxaa_pipeline,
+            vignette_pipeline,
+            blit_pipeline,
+            lut,
+            lut_bind_group,
+            targets,
+        }
+    }
+
+    /// Where the scene should be rendered to before `run` is called.
+    pub fn scene_view(&self) -> &TextureView {
+        &self.targets.scene.view
+    }
+
+    pub fn settings_mut(&mut self) -> &mut PostSettings {
+        &mut self.settings
+    }
+
+    pub fn resize(&mut self, device: &Device, config: &SurfaceConfiguration) {
+        self.targets = Targets::new(
+            device,
+            config,
+            &self.source_layout,
+            &self.bloom_layout,
+            &self.sampler,
+        );
+    }
+
+    pub fn update(&self, queue: &Queue) {
+        queue.write_buffer(
+            &self.uniform_buffer,
+            0,
+            bytemuck::cast_slice(&[self.settings.to_uniform()]),
+        );
+    }
+
+    /// Runs every enabled effect over the scene target and writes the result
+    /// into `output`.
+    pub fn run(&self, encoder: &mut CommandEncoder, output: &TextureView) {
+        let targets = &self.targets;
+
+        if self.settings.bloom {
+            self.fullscreen_pass(
+                encoder,
+                "Bloom Threshold Pass",
+                &self.threshold_pipeline,
+                &targets.bloom[0].view,
+                &targets.scene_source,
+                None,
+                false,
+            );
+
+            for i in 1..targets.bloom.len() {
+                self.fullscreen_pass(
+                    encoder,
+                    "Bloom Downsample Pass",
+                    &self.downsample_pipeline,
+                    &targets.bloom[i].view,
+                    &targets.bloom_sources[i - 1],
+                    None,
+                    false,
+                );
+            }
+
+            for i in (0..targets.bloom.len() - 1).rev() {
+                self.fullscreen_pass(
+                    encoder,
+                    "Bloom Upsample Pass",
+                    &self.upsample_pipeline,
+                    &targets.bloom[i].view,
+                    &targets.bloom_sources[i + 1],
+                    None,
+                    true,
+                );
+            }
+        }
+
+        self.fullscreen_pass(
+            encoder,
+            "Composite Pass",
+            &self.composite_pipeline,
+            &targets.ping_pong[0].view,
+            &targets.scene_source,
+            Some(&targets.composite_bloom),
+            false,
+        );
+
+        // Each enabled effect reads the last result and writes the other
+        // ping-pong target
+        let effects = [
+            (
+                self.settings.color_grading,
+                "Color Grading Pass",
+                &self.grade_pipeline,
+                Some(&self.lut_bind_group),
+            ),
+            (self.settings.fxaa, "FXAA Pass", &self.fxaa_pipeline, None),
+            (
+                self.settings.vignette,
+                "Vignette Pass",
+                &self.vignette_pipeline,
+                None,
+            ),
+        ];
+
+        let mut current = 0;
+        for (enabled, label, pipeline, extra) in effects {
+            if !enabled {
+                continue;
+            }
+
+            self.fullscreen_pass(
+                encoder,
+                label,
+                pipeline,
+                &targets.ping_pong[1 - current].view,
+                &targets.ping_pong_sources[current],
+                extra,
+                false,
+            );
+            current = 1 - current;
+        }
+
+        self.fullscreen_pass(
+            encoder,
+            "Blit Pass",
+            &self.blit_pipeline,
+            output,
+            &targets.ping_pong_sources[current],
+            None,
+            false,
+        );
+    }
+
+    fn fullscreen_pass(
+        &self,
+        encoder: &mut CommandEncoder,
+        label: &str,
+        pipeline: &RenderPipeline,
+        target: &TextureView,
+        source: &BindGroup,
+        extra: Option<&BindGroup>,
+        accumulate: bool,
+    ) {
+        let load = if accumulate {
+            wgpu::LoadOp::Load
+        } else {
+            wgpu::LoadOp::Clear(wgpu::Color::BLACK)
+        };
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some(label),
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations { load, store: true },
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, source, &[]);
+        render_pass.set_bind_group(1, &self.uniform_bind_group, &[]);
+        if let Some(extra) = extra {
+            render_pass.set_bind_group(2, extra, &[]);
+        }
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+impl Targets {
+    fn new(
+        device: &Device,
+        config: &SurfaceConfiguration,
+        source_layout: &BindGroupLayout,
+        bloom_layout: &BindGroupLayout,
+        sampler: &Sampler,
+    ) -> Self {
+        let (width, height) = (config.width, config.height);
+
+        let scene = Texture::create_render_target(device, width, height, HDR_FORMAT, "scene");
+        let ping_pong = [
+            Texture::create_render_target(device, width, height, HDR_FORMAT, "post_ping"),
+            Texture::create_render_target(device, width, height, HDR_FORMAT, "post_pong"),
+        ];
+
+        let bloom = (1..=BLOOM_MIPS)
+            .map(|i| {
+                Texture::create_render_target(
+                    device,
+                    (width >> i).max(1),
+                    (height >> i).max(1),
+                    HDR_FORMAT,
+                    "bloom",
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let source = |texture: &Texture| {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: source_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&texture.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(sampler),
+                    },
+                ],
+                label: Some("post_source_bind_group"),
+            })
+        };
+
+        let scene_source = source(&scene);
+        let bloom_sources = bloom.iter().map(source).collect();
+        let ping_pong_sources = [source(&ping_pong[0]), source(&ping_pong[1])];
+
+        let composite_bloom = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: bloom_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&bloom[0].view),
+            }],
+            label: Some("post_bloom_bind_group"),
+        });
+
+        Self {
+            scene,
+            bloom,
+            ping_pong,
+            scene_source,
+            bloom_sources,
+            ping_pong_sources,
+            composite_bloom,
+        }
+    }
+}
+
+fn texture_entry(
+    binding: u32,
+    view_dimension: wgpu::TextureViewDimension,
+) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Texture {
+            multisampled: false,
+            view_dimension,
+            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+        },
+        count: None,
+    }
+}
+
+fn sampler_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Sampler {
+            comparison: false,
+            filtering: true,
+        },
+        count: None,
+    }
+}
+
+fn create_post_pipeline(
+    name: &str,
+    device: &Device,
+    layout: &PipelineLayout,
+    shader: &ShaderModule,
+    entry_point: &str,
+    format: TextureFormat,
+    blend: Option<wgpu::BlendState>,
+) -> RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(name),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "fullscreen",
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point,
+            targets: &[wgpu::ColorTargetState {
+                format,
+                blend: Some(blend.unwrap_or(wgpu::BlendState {
+                    alpha: wgpu::BlendComponent::REPLACE,
+                    color: wgpu::BlendComponent::REPLACE,
+                })),
+                write_mask: wgpu::ColorWrites::ALL,
+            }],
+        }),
+        primitive: wgpu::PrimitiveState::default(),
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+    })
+}
+
+/// Bakes a gentle filmic grade (a bit of contrast, warmer highlights and
+/// cooler shadows) into a 3D lookup table.
+fn create_lut(device: &Device, queue: &Queue) -> Texture {
+    let mut data = Vec::with_capacity((LUT_SIZE * LUT_SIZE * LUT_SIZE * 4) as usize);
+    for b in 0..LUT_SIZE {
+        for g in 0..LUT_SIZE {
+            for r in 0..LUT_SIZE {
+                let max = (LUT_SIZE - 1) as f32;
+                let color = [r as f32 / max, g as f32 / max, b as f32 / max];
+                let luma = color[0] * 0.2126 + color[1] * 0.7152 + color[2] * 0.0722;
+
+                let tint = [1.0 + 0.06 * (luma - 0.5), 1.0, 1.0 - 0.06 * (luma - 0.5)];
+                for (channel, tint) in color.iter().zip(tint.iter()) {
+                    // Smoothstep-ish contrast curve, mixed back in halfway
+                    let curved = channel * channel * (3.0 - 2.0 * channel);
+                    let graded = (channel + (curved - channel) * 0.5) * tint;
+                    data.push((graded.clamp(0.0, 1.0) * 255.0).round() as u8);
+                }
+                data.push(255);
+            }
+        }
+    }
+
+    let size = wgpu::Extent3d {
+        width: LUT_SIZE,
+        height: LUT_SIZE,
+        depth_or_array_layers: LUT_SIZE,
+    };
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("color_grading_lut"),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D3,
+        format: TextureFormat::Rgba8Unorm,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+    });
+
+    queue.write_texture(
+        wgpu::ImageCopyTexture {
+            aspect: wgpu::TextureAspect::All,
+            texture: &texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+        },
+        &data,
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: std::num::NonZeroU32::new(4 * LUT_SIZE),
+            rows_per_image: std::num::NonZeroU32::new(LUT_SIZE),
+        },
+        size,
+    );
+
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+        address_mode_u: wgpu::AddressMode::ClampToEdge,
+        address_mode_v: wgpu::AddressMode::ClampToEdge,
+        address_mode_w: wgpu::AddressMode::ClampToEdge,
+        mag_filter: wgpu::FilterMode::Linear,
+        min_filter: wgpu::FilterMode::Linear,
+        mipmap_filter: wgpu::FilterMode::Nearest,
+        ..Default::default()
+    });
+
+    Texture {
+        texture,
+        view,
+        sampler,
+    }
+}
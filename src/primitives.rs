@@ -0,0 +1,535 @@
+//! Meshes built in code rather than loaded, for tests, debugging and
+//! blocking things out before the real models exist.
+//!
+//! Everything is centered on the origin with `+Y` up and wound
+//! counter-clockwise from the outside. `v` runs top to bottom across each
+//! face like it does across an image. Tangents come from
+//! [`compute_tangents`], the same as for loaded models, so normal maps
+//! behave the same on both.
+
+use std::{
+    collections::HashMap,
+    f32::consts::{PI, TAU},
+};
+
+use glam::{Vec2, Vec3};
+
+use crate::model::{compute_tangents, MeshData, ModelVertex};
+
+/// An axis aligned box `size` across on every side, with flat faces that
+/// each show the whole texture.
+pub fn cube(size: f32) -> MeshData {
+    let half = size / 2.0;
+    // The normal and the directions along the face that `u` and `-v` run
+    let faces = [
+        (Vec3::X, -Vec3::Z, Vec3::Y),
+        (-Vec3::X, Vec3::Z, Vec3::Y),
+        (Vec3::Y, Vec3::X, -Vec3::Z),
+        (-Vec3::Y, Vec3::X, Vec3::Z),
+        (Vec3::Z, Vec3::X, Vec3::Y),
+        (-Vec3::Z, -Vec3::X, Vec3::Y),
+    ];
+
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    for (normal, right, up) in faces {
+        add_grid(
+            &mut vertices,
+            &mut indices,
+            normal * half,
+            right * half,
+            up * half,
+            1,
+        );
+    }
+
+    finish("Cube", vertices, indices)
+}
+
+/// A flat `width` by `depth` rectangle facing `+Y`, split into
+/// `subdivisions` squares along each side so it can be displaced or lit
+/// per vertex.
+pub fn plane(width: f32, depth: f32, subdivisions: u32) -> MeshData {
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    add_grid(
+        &mut vertices,
+        &mut indices,
+        Vec3::ZERO,
+        Vec3::X * width / 2.0,
+        -Vec3::Z * depth / 2.0,
+        subdivisions.max(1),
+    );
+
+    finish("Plane", vertices, indices)
+}
+
+/// A sphere made of `rings` bands of latitude, each split into `segments`.
+/// The texture wraps around once, pinching at the poles.
+pub fn uv_sphere(radius: f32, segments: u32, rings: u32) -> MeshData {
+    let segments = segments.max(3);
+    let rings = rings.max(2);
+
+    let mut vertices = Vec::new();
+    for ring in 0..=rings {
+        let v = ring as f32 / rings as f32;
+        let polar = v * PI;
+        for segment in 0..=segments {
+            let u = segment as f32 / segments as f32;
+            let normal = around(u * TAU) * polar.sin() + Vec3::Y * polar.cos();
+            vertices.push(vertex(normal * radius, normal, Vec2::new(u, v)));
+        }
+    }
+
+    let mut indices = Vec::new();
+    let stride = segments + 1;
+    for ring in 0..rings {
+        for segment in 0..segments {
+            let a = ring * stride + segment;
+            let (b, c, d) = (a + stride, a + stride + 1, a + 1);
+            // The triangles touching a pole would have no area
+            if ring != rings - 1 {
+                indices.extend_from_slice(&[a, b, c]);
+            }
+            if ring != 0 {
+                indices.extend_from_slice(&[a, c, d]);
+            }
+        }
+    }
+
+    finish("UV Sphere", vertices, indices)
+}
+
+/// A sphere made by splitting each face of an icosahedron into four
+/// `subdivisions` times. Its triangles are far more even than a
+/// [`uv_sphere`]'s, but the texture is mapped the same way.
+pub fn icosphere(radius: f32, subdivisions: u32) -> MeshData {
+    let t = (1.0 + 5.0f32.sqrt()) / 2.0;
+    let mut positions = [
+        (-1.0, t, 0.0),
+        (1.0, t, 0.0),
+        (-1.0, -t, 0.0),
+        (1.0, -t, 0.0),
+        (0.0, -1.0, t),
+        (0.0, 1.0, t),
+        (0.0, -1.0, -t),
+        (0.0, 1.0, -t),
+        (t, 0.0, -1.0),
+        (t, 0.0, 1.0),
+        (-t, 0.0, -1.0),
+        (-t, 0.0, 1.0),
+    ]
+    .iter()
+    .map(|&(x, y, z)| Vec3::new(x, y, z).normalize())
+    .collect::<Vec<_>>();
+    let mut faces = vec![
+        [0, 11, 5],
+        [0, 5, 1],
+        [0, 1, 7],
+        [0, 7, 10],
+        [0, 10, 11],
+        [1, 5, 9],
+        [5, 11, 4],
+        [11, 10, 2],
+        [10, 7, 6],
+        [7, 1, 8],
+        [3, 9, 4],
+        [3, 4, 2],
+        [3, 2, 6],
+        [3, 6, 8],
+        [3, 8, 9],
+        [4, 9, 5],
+        [2, 4, 11],
+        [6, 2, 10],
+        [8, 6, 7],
+        [9, 8, 1],
+    ];
+
+    for _ in 0..subdivisions {
+        // Neighbouring faces share their midpoints
+        let mut midpoints = HashMap::new();
+        let mut midpoint = |a: usize, b: usize| {
+            *midpoints.entry((a.min(b), a.max(b))).or_insert_with(|| {
+                positions.push(((positions[a] + positions[b]) / 2.0).normalize());
+                positions.len() - 1
+            })
+        };
+
+        faces = faces
+            .iter()
+            .flat_map(|&[a, b, c]| {
+                let (ab, bc, ca) = (midpoint(a, b), midpoint(b, c), midpoint(c, a));
+                std::array::IntoIter::new([[a, ab, ca], [b, bc, ab], [c, ca, bc], [ab, bc, ca]])
+            })
+            .collect();
+    }
+
+    // Every corner gets its own vertex so the ones on the seam can take
+    // either side's `u`, `finish` welds the rest back together
+    let mut vertices = Vec::with_capacity(faces.len() * 3);
+    for face in &faces {
+        let mut uvs = face
+            .iter()
+            .map(|&i| {
+                let p = positions[i];
+                let u = (-p.z).atan2(p.x) / TAU;
+                Vec2::new(u.rem_euclid(1.0), p.y.clamp(-1.0, 1.0).acos() / PI)
+            })
+            .collect::<Vec<_>>();
+
+        let wraps = uvs.iter().any(|uv| uv.x > 0.75) && uvs.iter().any(|uv| uv.x < 0.25);
+        for (i, uv) in face.iter().zip(&mut uvs) {
+            if wraps && uv.x < 0.5 {
+                uv.x += 1.0;
+            }
+            let normal = positions[*i];
+            vertices.push(vertex(normal * radius, normal, *uv));
+        }
+    }
+    let indices = (0..vertices.len() as u32).collect();
+
+    finish("Icosphere", vertices, indices)
+}
+
+/// A capped tube `height` tall along `Y`, with `segments` sides.
+pub fn cylinder(radius: f32, height: f32, segments: u32) -> MeshData {
+    frustum("Cylinder", radius, radius, height, segments)
+}
+
+/// A capped cone `height` tall along `Y`, pointing up, with `segments`
+/// sides.
+pub fn cone(radius: f32, height: f32, segments: u32) -> MeshData {
+    frustum("Cone", radius, 0.0, height, segments)
+}
+
+/// A ring around `Y`. `major_radius` is out to the middle of the tube and
+/// `minor_radius` is the tube's own. `u` runs around the ring and `v`
+/// around the tube.
+pub fn torus(major_radius: f32, minor_radius: f32, segments: u32, sides: u32) -> MeshData {
+    let segments = segments.max(3);
+    let sides = sides.max(3);
+
+    let mut vertices = Vec::new();
+    for segment in 0..=segments {
+        let u = segment as f32 / segments as f32;
+        let outward = around(u * TAU);
+        for side in 0..=sides {
+            let v = side as f32 / sides as f32;
+            let angle = v * TAU;
+            let normal = outward * angle.cos() + Vec3::Y * angle.sin();
+            let position = outward * major_radius + normal * minor_radius;
+            vertices.push(vertex(position, normal, Vec2::new(u, 1.0 - v)));
+        }
+    }
+
+    let mut indices = Vec::new();
+    let stride = sides + 1;
+    for segment in 0..segments {
+        for side in 0..sides {
+            let a = segment * stride + side;
+            let (b, c, d) = (a + stride, a + stride + 1, a + 1);
+            indices.extend_from_slice(&[a, b, c, a, c, d]);
+        }
+    }
+
+    finish("Torus", vertices, indices)
+}
+
+/// What [`cylinder`] and [`cone`] are made of, a tube that narrows from
+/// `bottom_radius` to `top_radius`. The top cap is left off when it comes
+/// to a point.
+fn frustum(
+    name: &str,
+    bottom_radius: f32,
+    top_radius: f32,
+    height: f32,
+    segments: u32,
+) -> MeshData {
+    let segments = segments.max(3);
+    let half = height / 2.0;
+    let pointed = top_radius <= 0.0;
+
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+
+    // The slope tips the side's normals up as the radius shrinks
+    let side_normal =
+        |angle: f32| (around(angle) * height + Vec3::Y * (bottom_radius - top_radius)).normalize();
+    for segment in 0..=segments {
+        let u = segment as f32 / segments as f32;
+        // A point has no direction of its own, so each triangle's tip
+        // leans the way the middle of its side faces
+        let top_u = if pointed {
+            (segment as f32 + 0.5) / segments as f32
+        } else {
+            u
+        };
+        vertices.push(vertex(
+            around(top_u * TAU) * top_radius + Vec3::Y * half,
+            side_normal(top_u * TAU),
+            Vec2::new(top_u, 0.0),
+        ));
+        vertices.push(vertex(
+            around(u * TAU) * bottom_radius - Vec3::Y * half,
+            side_normal(u * TAU),
+            Vec2::new(u, 1.0),
+        ));
+    }
+    for segment in 0..segments {
+        let a = segment * 2;
+        let (b, c, d) = (a + 1, a + 3, a + 2);
+        indices.extend_from_slice(&[a, b, c]);
+        if !pointed {
+            indices.extend_from_slice(&[a, c, d]);
+        }
+    }
+
+    add_cap(
+        &mut vertices,
+        &mut indices,
+        -half,
+        bottom_radius,
+        -Vec3::Y,
+        segments,
+    );
+    if !pointed {
+        add_cap(
+            &mut vertices,
+            &mut indices,
+            half,
+            top_radius,
+            Vec3::Y,
+            segments,
+        );
+    }
+
+    finish(name, vertices, indices)
+}
+
+/// Adds a disc at height `y` facing `normal`, which is either `Y` or `-Y`.
+/// The texture is projected straight down onto it.
+fn add_cap(
+    vertices: &mut Vec<ModelVertex>,
+    indices: &mut Vec<u32>,
+    y: f32,
+    radius: f32,
+    normal: Vec3,
+    segments: u32,
+) {
+    let center = vertices.len() as u32;
+    let facing_up = normal.y > 0.0;
+    // Seen from outside, `v` runs towards `+Z` on top and `-Z` underneath
+    let uv = |direction: Vec3| {
+        let v = if facing_up { direction.z } else { -direction.z };
+        Vec2::new(0.5 + direction.x / 2.0, 0.5 + v / 2.0)
+    };
+
+    vertices.push(vertex(Vec3::Y * y, normal, Vec2::splat(0.5)));
+    for segment in 0..=segments {
+        let direction = around(segment as f32 / segments as f32 * TAU);
+        vertices.push(vertex(
+            direction * radius + Vec3::Y * y,
+            normal,
+            uv(direction),
+        ));
+    }
+
+    for segment in 0..segments {
+        let (a, b) = (center + 1 + segment, center + 2 + segment);
+        if facing_up {
+            indices.extend_from_slice(&[center, a, b]);
+        } else {
+            indices.extend_from_slice(&[center, b, a]);
+        }
+    }
+}
+
+/// Adds a flat rectangle around `center`, stretching `right` and `up` each
+/// way, split into `divisions` squares along each side. `right` has to be
+/// `up` turned clockwise when looking at the front.
+fn add_grid(
+    vertices: &mut Vec<ModelVertex>,
+    indices: &mut Vec<u32>,
+    center: Vec3,
+    right: Vec3,
+    up: Vec3,
+    divisions: u32,
+) {
+    let first = vertices.len() as u32;
+    let normal = right.cross(up).normalize();
+    for row in 0..=divisions {
+        let v = row as f32 / divisions as f32;
+        for column in 0..=divisions {
+            let u = column as f32 / divisions as f32;
+            let position = center + right * (u * 2.0 - 1.0) + up * (1.0 - v * 2.0);
+            vertices.push(vertex(position, normal, Vec2::new(u, v)));
+        }
+    }
+
+    // Rows run down the face, so the next row is below
+    let stride = divisions + 1;
+    for row in 0..divisions {
+        for column in 0..divisions {
+            let a = first + row * stride + column;
+            let (b, c, d) = (a + stride, a + stride + 1, a + 1);
+            indices.extend_from_slice(&[a, b, c, a, c, d]);
+        }
+    }
+}
+
+/// Outward from `Y`, turning counter-clockwise seen from above as `angle`
+/// goes up, starting at `+X`.
+fn around(angle: f32) -> Vec3 {
+    Vec3::new(angle.cos(), 0.0, -angle.sin())
+}
+
+fn vertex(position: Vec3, normal: Vec3, tex_coords: Vec2) -> ModelVertex {
+    ModelVertex {
+        position,
+        tex_coords,
+        normal,
+        ..ModelVertex::default()
+    }
+}
+
+/// Fills in the tangents and optimizes the result like a loaded mesh. It
+/// uses material 0, the only one in [`ModelData::from_meshes`].
+///
+/// [`ModelData::from_meshes`]: crate::model::ModelData::from_meshes
+fn finish(name: &str, mut vertices: Vec<ModelVertex>, mut indices: Vec<u32>) -> MeshData {
+    compute_tangents(&mut vertices, &mut indices, None);
+    let mut mesh = MeshData {
+        name: name.to_string(),
+        vertices,
+        indices,
+        lods: Vec::new(),
+        material: 0,
+        skin: None,
+    };
+    mesh.optimize();
+    mesh
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Shapes that every face of looks away from the origin.
+    fn convex_shapes() -> Vec<MeshData> {
+        vec![
+            cube(2.0),
+            uv_sphere(1.0, 16, 8),
+            icosphere(1.0, 2),
+            cylinder(1.0, 2.0, 12),
+            cone(1.0, 2.0, 12),
+        ]
+    }
+
+    fn all_shapes() -> Vec<MeshData> {
+        let mut shapes = convex_shapes();
+        shapes.push(plane(2.0, 3.0, 4));
+        shapes.push(torus(1.0, 0.25, 16, 8));
+        shapes
+    }
+
+    /// The corners of each triangle with any area, and which way its
+    /// winding says it faces.
+    fn triangles(mesh: &MeshData) -> impl Iterator<Item = ([&ModelVertex; 3], Vec3)> + '_ {
+        mesh.indices.chunks_exact(3).filter_map(move |triangle| {
+            let corners = [0, 1, 2].map(|i| &mesh.vertices[triangle[i] as usize]);
+            let [a, b, c] = corners.map(|corner| corner.position);
+            let normal = (b - a).cross(c - a);
+            (normal.length() > 1e-6).then(|| (corners, normal.normalize()))
+        })
+    }
+
+    fn centroid(corners: &[&ModelVertex; 3]) -> Vec3 {
+        (corners[0].position + corners[1].position + corners[2].position) / 3.0
+    }
+
+    #[test]
+    fn indices_are_whole_triangles_in_range() {
+        for mesh in all_shapes() {
+            assert!(!mesh.indices.is_empty(), "{}", mesh.name);
+            assert_eq!(mesh.indices.len() % 3, 0, "{}", mesh.name);
+            assert!(
+                mesh.indices
+                    .iter()
+                    .all(|&index| (index as usize) < mesh.vertices.len()),
+                "{}",
+                mesh.name
+            );
+        }
+    }
+
+    #[test]
+    fn normals_are_unit_length() {
+        for mesh in all_shapes() {
+            for vertex in &mesh.vertices {
+                assert!(
+                    (vertex.normal.length() - 1.0).abs() < 1e-3,
+                    "{} has a normal of {:?}",
+                    mesh.name,
+                    vertex.normal
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn convex_shapes_wind_outward() {
+        for mesh in convex_shapes() {
+            for (corners, normal) in triangles(&mesh) {
+                assert!(normal.dot(centroid(&corners)) > 0.0, "{}", mesh.name);
+            }
+        }
+    }
+
+    #[test]
+    fn plane_winds_up() {
+        let mesh = plane(2.0, 3.0, 4);
+        for (_, normal) in triangles(&mesh) {
+            assert!(normal.dot(Vec3::Y) > 0.99);
+        }
+    }
+
+    #[test]
+    fn torus_winds_out_of_the_tube() {
+        let (major_radius, minor_radius) = (1.0, 0.25);
+        let mesh = torus(major_radius, minor_radius, 16, 8);
+        for (corners, normal) in triangles(&mesh) {
+            let centroid = centroid(&corners);
+            let tube = Vec3::new(centroid.x, 0.0, centroid.z).normalize() * major_radius;
+            assert!(normal.dot(centroid - tube) > 0.0);
+        }
+    }
+
+    #[test]
+    fn vertex_normals_agree_with_the_winding() {
+        for mesh in all_shapes() {
+            for (corners, normal) in triangles(&mesh) {
+                for corner in &corners {
+                    assert!(corner.normal.dot(normal) > 0.0, "{}", mesh.name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn uvs_are_in_range() {
+        for mesh in all_shapes() {
+            // The icosphere's seam triangles carry on past 1 rather than
+            // stretching back across the texture
+            let max_u = if mesh.name == "Icosphere" { 1.25 } else { 1.0 };
+            for vertex in &mesh.vertices {
+                let uv = vertex.tex_coords;
+                assert!(
+                    (-1e-5..=max_u + 1e-5).contains(&uv.x) && (-1e-5..=1.0 + 1e-5).contains(&uv.y),
+                    "{} has a UV of {:?}",
+                    mesh.name,
+                    uv
+                );
+            }
+        }
+    }
+}
@@ -1,78 +1,190 @@
-use std::{borrow::Cow, path::Path};
+use std::{borrow::Cow, ops::Range, path::Path, time::Duration};
 
 use bytemuck::{Pod, Zeroable};
+use egui_wgpu_backend::{RenderPass as EguiRenderPass, ScreenDescriptor};
 use glam::{vec3, Mat3, Mat4, Quat, Vec3};
 use wgpu::{
     util::{BufferInitDescriptor, DeviceExt},
-    Backends, BindGroup, Buffer, BufferUsages, CommandEncoder, CommandEncoderDescriptor, Device,
-    DeviceDescriptor, IndexFormat, PipelineLayoutDescriptor, PresentMode, Queue, RenderPipeline,
-    RenderPipelineDescriptor, RequestAdapterOptions, ShaderModuleDescriptor, ShaderSource, Surface,
-    SurfaceConfiguration, TextureAspect, TextureUsages, TextureView, TextureViewDescriptor,
+    Backends, BindGroup, BindGroupLayout, Buffer, BufferUsages, CommandEncoder,
+    CommandEncoderDescriptor, Device, DeviceDescriptor, IndexFormat, PipelineLayoutDescriptor,
+    PresentMode, Queue, RenderPass, RenderPipeline, RenderPipelineDescriptor,
+    RequestAdapterOptions, ShaderModuleDescriptor, ShaderSource, Surface, SurfaceConfiguration,
+    TextureAspect, TextureUsages, TextureView, TextureViewDescriptor,
 };
 use winit::{dpi::PhysicalSize, window::Window};
 
 use crate::{
+    animation::{Animatable, AnimationPlayer, Target, Transform},
+    assets::{Assets, Handle},
     camera::{Camera, CameraUniform, Projection},
     controller::CameraController,
-    model::{DrawLight, DrawModel, Mesh, Model, ModelVertex, Vertex},
-    texture::{self, Texture},
+    deferred::DeferredRenderer,
+    fog::FogSettings,
+    loader::AssetLoader,
+    model::{
+        lod_for_screen_size, DrawLight, DrawModel, Material, Mesh, Model, ModelData, ModelVertex,
+        SkinVertex, Vertex, VertexEncoding, LOD_SCREEN_SIZES,
+    },
+    post::{PostProcess, PostSettings, HDR_FORMAT},
+    primitives,
+    skin::{Skeleton, Skin},
+    source::AssetSource,
+    ssao::{Ssao, SsaoSettings},
+    texture::{self, SamplerSettings, Texture},
+    ui::GuiFrame,
+    watcher::AssetWatcher,
 };
 
 const NUM_INSTANCES_PER_ROW: u32 = 10;
 
+const CLEAR_COLOR: wgpu::Color = wgpu::Color {
+    r: 0.1,
+    g: 0.2,
+    b: 0.3,
+    a: 1.0,
+};
+
 #[derive(Debug)]
 struct Instance {
     position: Vec3,
     rotation: Quat,
+    scale: Vec3,
 }
 
 #[repr(C)]
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
-struct InstanceRaw {
+pub(crate) struct InstanceRaw {
     model: [[f32; 4]; 4],
     normal: [[f32; 3]; 3],
 }
 
+/// A model drawn on its own rather than as part of the instance grid, posed
+/// by its skeleton if it has one.
+pub(crate) struct AnimatedModel {
+    model: Handle<Model>,
+    instance_buffer: Buffer,
+    /// Made once the model has loaded and we know how many joints it has.
+    skin: Option<Skin>,
+    /// Index into the model's animations, which one is playing.
+    animation: usize,
+    /// How far into the animation we are, in seconds.
+    time: f32,
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct LightUniform {
     pub position: Vec3,
-    _padding: u32,
+    /// Distance at which the light has faded out completely, zero means the
+    /// light doesn't fall off at all.
+    pub radius: f32,
     pub color: Vec3,
+    /// Multiplies `color`, so the light can be dimmed without losing its
+    /// hue.
+    pub intensity: f32,
+}
+
+/// Which renderer draws the scene, picked once at startup.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RenderPath {
+    Forward,
+    Deferred,
+}
+
+/// What the main pipeline writes out, mostly useful for checking normal maps
+/// and textures in isolation.
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DebugView {
+    Lit = 0,
+    Albedo = 1,
+    Normals = 2,
+    Lighting = 3,
 }
 
 #[derive(Debug)]
+pub struct DebugSettings {
+    pub view: DebugView,
+    pub show_light: bool,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct DebugUniform {
+    view: u32,
+    _padding: [u32; 3],
+}
+
 pub struct Render {
     surface: Surface,
     device: Device,
     queue: Queue,
     main_pipeline: RenderPipeline,
     light_pipeline: RenderPipeline,
+    depth_prepass_pipeline: RenderPipeline,
+    skinned_pipeline: RenderPipeline,
+    skinned_depth_prepass_pipeline: RenderPipeline,
 
-    obj_model: Model,
+    assets: Assets,
+    loader: AssetLoader,
+    watcher: Option<AssetWatcher>,
+    texture_bind_group_layout: BindGroupLayout,
+    obj_model: Handle<Model>,
+    /// Holds `obj_model` in its rest pose, if it has a skeleton.
+    obj_skin: Option<Skin>,
 
     camera: Camera,
     projection: Projection,
 
     camera_uniform: CameraUniform,
     camera_buffer: Buffer,
+    camera_bind_group_layout: BindGroupLayout,
     camera_bind_group: BindGroup,
 
+    debug: DebugSettings,
+    debug_buffer: Buffer,
+
+    fog: FogSettings,
+    fog_buffer: Buffer,
+
     instances: Vec<Instance>,
+    /// Sorted by the LOD each instance is drawn at, see `update_lods`.
     instance_buffer: Buffer,
+    /// Which instances in `instance_buffer` are drawn at each LOD.
+    instance_lods: Vec<Range<u32>>,
+
+    animated_models: Vec<AnimatedModel>,
+    skin_bind_group_layout: BindGroupLayout,
+
+    /// Keyframed clips moving the instances, lights and camera.
+    animations: Vec<AnimationPlayer>,
 
     light_uniform: LightUniform,
     light_buffer: Buffer,
     light_bind_group: BindGroup,
 
+    // Only the deferred path shades with these, the forward path just uses
+    // `light_uniform`.
+    point_lights: Vec<LightUniform>,
+    deferred: Option<DeferredRenderer>,
+
     depth_texture: Texture,
 
+    ssao: Ssao,
+    post: PostProcess,
+    egui_rpass: EguiRenderPass,
+
     config: SurfaceConfiguration,
     size: PhysicalSize<u32>,
 }
 
 impl Render {
-    pub async fn new(window: &Window) -> Self {
+    pub async fn new(
+        window: &Window,
+        path: RenderPath,
+        vertices: VertexEncoding,
+        source: AssetSource,
+    ) -> Self {
         let size = window.inner_size();
 
         let instance = wgpu::Instance::new(Backends::all());
@@ -88,7 +200,12 @@ impl Render {
         let (device, queue) = adapter
             .request_device(
                 &DeviceDescriptor {
-                    features: wgpu::Features::empty(),
+                    // Whatever compressed formats the adapter has, see
+                    // Texture::from_compressed
+                    features: adapter.features()
+                        & (wgpu::Features::TEXTURE_COMPRESSION_BC
+                            | wgpu::Features::TEXTURE_COMPRESSION_ETC2
+                            | wgpu::Features::TEXTURE_COMPRESSION_ASTC_LDR),
                     limits: wgpu::Limits::default(),
                     label: None,
                 },
@@ -113,105 +230,107 @@ impl Render {
         };
         surface.configure(&device, &config);
 
-        let texture_bind_group_layout =
+        let texture_bind_group_layout = Material::create_bind_group_layout(&device);
+
+        let camera = Camera::new(
+            vec3(0.0, 5.0, 100.0),
+            -90f32.to_radians(),
+            -20f32.to_radians(),
+        );
+        let projection =
+            Projection::new(config.width, config.height, 45f32.to_radians(), 0.1, 100.0);
+        let mut camera_uniform = CameraUniform::default();
+
+        camera_uniform.update_view_proj(&camera, &projection);
+
+        let camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Camera Buffer"),
+            contents: bytemuck::cast_slice(&[camera_uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let debug = DebugSettings {
+            view: DebugView::Lit,
+            show_light: true,
+        };
+
+        let debug_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Debug Buffer"),
+            contents: bytemuck::cast_slice(&[DebugUniform {
+                view: debug.view as u32,
+                _padding: [0; 3],
+            }]),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
+
+        let fog = FogSettings::default();
+        let fog_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Fog Buffer"),
+            contents: bytemuck::cast_slice(&[fog.to_uniform()]),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
+
+        let camera_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 entries: &[
                     wgpu::BindGroupLayoutEntry {
                         binding: 0,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Texture {
-                            multisampled: false,
-                            view_dimension: wgpu::TextureViewDimension::D2,
-                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
                         },
                         count: None,
                     },
                     wgpu::BindGroupLayoutEntry {
                         binding: 1,
                         visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Sampler {
-                            // This is only for TextureSampleType::Depth
-                            comparison: false,
-                            // This should be true if the sample_type of the texture is:
-                            //     TextureSampleType::Float { filterable: true }
-                            // Otherwise you'll get an error.
-                            filtering: true,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
                         },
                         count: None,
                     },
+                    // Ambient occlusion, read with textureLoad
                     wgpu::BindGroupLayoutEntry {
                         binding: 2,
                         visibility: wgpu::ShaderStages::FRAGMENT,
                         ty: wgpu::BindingType::Texture {
                             multisampled: false,
                             view_dimension: wgpu::TextureViewDimension::D2,
-                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            sample_type: wgpu::TextureSampleType::Float { filterable: false },
                         },
                         count: None,
                     },
                     wgpu::BindGroupLayoutEntry {
                         binding: 3,
                         visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Sampler {
-                            // This is only for TextureSampleType::Depth
-                            comparison: false,
-                            // This should be true if the sample_type of the texture is:
-                            //     TextureSampleType::Float { filterable: true }
-                            // Otherwise you'll get an error.
-                            filtering: true,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
                         },
                         count: None,
                     },
                 ],
-                label: Some("texture_bind_group_layout"),
-            });
-
-        let camera = Camera::new(
-            vec3(0.0, 5.0, 100.0),
-            -90f32.to_radians(),
-            -20f32.to_radians(),
-        );
-        let projection =
-            Projection::new(config.width, config.height, 45f32.to_radians(), 0.1, 100.0);
-        let mut camera_uniform = CameraUniform::default();
-
-        camera_uniform.update_view_proj(&camera, &projection);
-
-        let camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Camera Buffer"),
-            contents: bytemuck::cast_slice(&[camera_uniform]),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-        });
-
-        let camera_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                }],
                 label: Some("camera_bind_group_layout"),
             });
 
-        let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &camera_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: camera_buffer.as_entire_binding(),
-            }],
-            label: Some("camera_bind_group"),
-        });
+        let depth_texture = Texture::create_depth_texture(&device, &config, "depth_texture");
+        let ssao = Ssao::new(&device, &queue, &config, &depth_texture);
 
-        let light_uniform = LightUniform {
-            position: Vec3::new(10.0, 2.0, 100.0),
-            _padding: 0,
-            color: Vec3::new(1.0, 1.0, 1.0),
-        };
+        let camera_bind_group = create_camera_bind_group(
+            &device,
+            &camera_bind_group_layout,
+            &camera_buffer,
+            &debug_buffer,
+            &fog_buffer,
+            ssao.occlusion_view(),
+        );
+
+        let light_uniform = LightUniform::new(Vec3::new(10.0, 2.0, 100.0), 0.0, Vec3::ONE);
 
         let light_buffer = device.create_buffer_init(&BufferInitDescriptor {
             label: Some("Light VB"),
@@ -243,24 +362,40 @@ impl Render {
             label: None,
         });
 
-        let depth_texture = Texture::create_depth_texture(&device, &config, "depth_texture");
-
-        let asset_dir = Path::new(env!("OUT_DIR")).join("assets");
-        let obj_model = Model::load(
+        // Embedded assets can't change, so there's only something to watch
+        // when loading from disk
+        let watcher = source
+            .root()
+            .and_then(|root| match AssetWatcher::new(root) {
+                Ok(watcher) => Some(watcher),
+                Err(e) => {
+                    log::warn!("Hot reload disabled, couldn't watch {:?}: {}", root, e);
+                    None
+                }
+            });
+        let mut assets = Assets::new(source);
+        assets.set_vertex_encoding(&device, vertices);
+        // We're always created inside the runtime's block_on
+        let mut loader = AssetLoader::new(tokio::runtime::Handle::current());
+        let obj_model = loader.load_model(
+            &mut assets,
             &device,
             &queue,
             &texture_bind_group_layout,
-            asset_dir.join("cube/cube.obj"),
-        )
-        .unwrap();
+            "cube/cube.obj",
+        );
 
+        // Compact meshes bring a bind group of their own, after the rest
+        let mesh_layout = assets.mesh_bind_group_layout();
+        let mut bind_group_layouts = vec![
+            &texture_bind_group_layout,
+            &camera_bind_group_layout,
+            &light_bind_group_layout,
+        ];
+        bind_group_layouts.extend(mesh_layout);
         let render_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
             label: Some("Render Pipeline Layout"),
-            bind_group_layouts: &[
-                &texture_bind_group_layout,
-                &camera_bind_group_layout,
-                &light_bind_group_layout,
-            ],
+            bind_group_layouts: &bind_group_layouts,
             push_constant_ranges: &[],
         });
 
@@ -273,20 +408,25 @@ impl Render {
             "main_pipeline",
             &device,
             &render_pipeline_layout,
-            config.format,
+            &[HDR_FORMAT],
             Some(Texture::DEPTH_FORMAT),
-            &[ModelVertex::desc(), InstanceRaw::desc()],
+            &[vertices.desc(), InstanceRaw::desc()],
             // "main_vs",
             // "main_fs",
-            "main",
+            vertices.entry_point(),
             "main",
             shader,
+            // Opaque materials come out with an alpha of 1, so this only
+            // affects translucent ones
+            Some(wgpu::BlendState::ALPHA_BLENDING),
         );
 
         let light_pipeline = {
+            let mut bind_group_layouts = vec![&camera_bind_group_layout, &light_bind_group_layout];
+            bind_group_layouts.extend(mesh_layout);
             let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Light Pipeline Layout"),
-                bind_group_layouts: &[&camera_bind_group_layout, &light_bind_group_layout],
+                bind_group_layouts: &bind_group_layouts,
                 push_constant_ranges: &[],
             });
 
@@ -294,17 +434,73 @@ impl Render {
                 "light_pipeline",
                 &device,
                 &layout,
-                config.format,
+                &[HDR_FORMAT],
                 Some(texture::Texture::DEPTH_FORMAT),
-                &[ModelVertex::desc()],
+                &[vertices.desc()],
                 // "light_vs",
                 // "light_fs",
-                "main",
+                vertices.entry_point(),
                 "main",
                 shader2,
+                None,
             )
         };
 
+        // Fills the depth buffer ahead of the forward pass so SSAO has
+        // something to work from
+        let depth_prepass_pipeline = create_render_pipeline(
+            "depth_prepass_pipeline",
+            &device,
+            &render_pipeline_layout,
+            &[],
+            Some(Texture::DEPTH_FORMAT),
+            &[vertices.desc(), InstanceRaw::desc()],
+            vertices.entry_point(),
+            "main",
+            wgpu::include_wgsl!("../shaders/wgsl/depth.wgsl"),
+            None,
+        );
+
+        // Skinned meshes are always stored in full, and have their joints
+        // where the compact encodings would have their mesh bind group
+        let skin_bind_group_layout = Skin::create_bind_group_layout(&device);
+        let skinned_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("Skinned Pipeline Layout"),
+            bind_group_layouts: &[
+                &texture_bind_group_layout,
+                &camera_bind_group_layout,
+                &light_bind_group_layout,
+                &skin_bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+        let skinned_vertex_layouts = [ModelVertex::desc(), InstanceRaw::desc(), SkinVertex::desc()];
+
+        let skinned_pipeline = create_render_pipeline(
+            "skinned_pipeline",
+            &device,
+            &skinned_pipeline_layout,
+            &[HDR_FORMAT],
+            Some(Texture::DEPTH_FORMAT),
+            &skinned_vertex_layouts,
+            "main_skinned",
+            "main",
+            wgpu::include_wgsl!("../shaders/wgsl/shader.wgsl"),
+            Some(wgpu::BlendState::ALPHA_BLENDING),
+        );
+        let skinned_depth_prepass_pipeline = create_render_pipeline(
+            "skinned_depth_prepass_pipeline",
+            &device,
+            &skinned_pipeline_layout,
+            &[],
+            Some(Texture::DEPTH_FORMAT),
+            &skinned_vertex_layouts,
+            "main_skinned",
+            "main",
+            wgpu::include_wgsl!("../shaders/wgsl/depth.wgsl"),
+            None,
+        );
+
         const SPACE_BETWEEN: f32 = 3.0;
         let instances = (0..NUM_INSTANCES_PER_ROW)
             .flat_map(|z| {
@@ -322,7 +518,11 @@ impl Render {
                         Quat::from_axis_angle(position.normalize(), 0.78)
                     };
 
-                    Instance { position, rotation }
+                    Instance {
+                        position,
+                        rotation,
+                        scale: Vec3::ONE,
+                    }
                 })
             })
             .collect::<Vec<_>>();
@@ -331,8 +531,47 @@ impl Render {
         let instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Instance Buffer"),
             contents: bytemuck::cast_slice(&instance_data),
-            usage: wgpu::BufferUsages::VERTEX,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
         });
+        // Everything at full detail until the first update
+        let instance_lods = vec![0..instances.len() as u32];
+
+        let (deferred, point_lights) = match path {
+            RenderPath::Forward => (None, Vec::new()),
+            RenderPath::Deferred => {
+                let deferred = DeferredRenderer::new(
+                    &device,
+                    &config,
+                    &texture_bind_group_layout,
+                    &camera_bind_group_layout,
+                    &light_bind_group_layout,
+                    mesh_layout,
+                    &skin_bind_group_layout,
+                    vertices,
+                    &depth_texture,
+                );
+
+                // A ring of colored lights over the instance grid so there's
+                // something for the lighting pass to chew on
+                let point_lights = (0..8)
+                    .map(|i| {
+                        let angle = i as f32 / 8.0 * std::f32::consts::TAU;
+                        let color = Vec3::new(
+                            angle.cos() * 0.5 + 0.5,
+                            (angle + 2.1).cos() * 0.5 + 0.5,
+                            (angle + 4.2).cos() * 0.5 + 0.5,
+                        );
+                        let position = Vec3::new(angle.cos() * 10.0, 2.0, angle.sin() * 10.0);
+                        LightUniform::new(position, 8.0, color)
+                    })
+                    .collect();
+
+                (Some(deferred), point_lights)
+            }
+        };
+
+        let post = PostProcess::new(&device, &queue, &config);
+        let egui_rpass = EguiRenderPass::new(&device, config.format, 1);
 
         Self {
             surface,
@@ -340,18 +579,40 @@ impl Render {
             queue,
             main_pipeline: pipeline,
             light_pipeline,
+            depth_prepass_pipeline,
+            skinned_pipeline,
+            skinned_depth_prepass_pipeline,
+            assets,
+            loader,
+            watcher,
+            texture_bind_group_layout,
             obj_model,
+            obj_skin: None,
             camera,
             projection,
             camera_uniform,
             camera_buffer,
+            camera_bind_group_layout,
             camera_bind_group,
+            debug,
+            debug_buffer,
+            fog,
+            fog_buffer,
             instances,
             instance_buffer,
+            instance_lods,
+            animated_models: Vec::new(),
+            skin_bind_group_layout,
+            animations: Vec::new(),
             light_uniform,
             light_buffer,
             light_bind_group,
+            point_lights,
+            deferred,
             depth_texture,
+            ssao,
+            post,
+            egui_rpass,
             config,
             size,
         }
@@ -365,12 +626,26 @@ impl Render {
             self.surface.configure(&self.device, &self.config);
             self.depth_texture =
                 Texture::create_depth_texture(&self.device, &self.config, "depth_texture");
+            if let Some(deferred) = &mut self.deferred {
+                deferred.resize(&self.device, &self.config, &self.depth_texture);
+            }
+            self.ssao
+                .resize(&self.device, &self.config, &self.depth_texture);
+            self.camera_bind_group = create_camera_bind_group(
+                &self.device,
+                &self.camera_bind_group_layout,
+                &self.camera_buffer,
+                &self.debug_buffer,
+                &self.fog_buffer,
+                self.ssao.occlusion_view(),
+            );
+            self.post.resize(&self.device, &self.config);
 
             self.projection.resize(new_size.width, new_size.height);
         }
     }
 
-    pub fn render(&mut self) -> anyhow::Result<()> {
+    pub fn render(&mut self, gui: &GuiFrame) -> anyhow::Result<()> {
         let output = self.surface.get_current_frame()?.output;
         let view = output
             .texture
@@ -382,33 +657,77 @@ impl Render {
                 label: Some("Render Encoder"),
             });
 
-        self.pass(&mut encoder, &view);
+        self.pass(&mut encoder, self.post.scene_view());
+        self.post.run(&mut encoder, &view);
+        self.gui_pass(&mut encoder, &view, gui)?;
 
         self.queue.submit(Some(encoder.finish()));
 
         Ok(())
     }
 
-    fn pass(&mut self, encoder: &mut CommandEncoder, view: &TextureView) {
+    fn gui_pass(
+        &mut self,
+        encoder: &mut CommandEncoder,
+        view: &TextureView,
+        gui: &GuiFrame,
+    ) -> anyhow::Result<()> {
+        let screen_descriptor = ScreenDescriptor {
+            physical_width: self.config.width,
+            physical_height: self.config.height,
+            scale_factor: gui.scale_factor,
+        };
+
+        self.egui_rpass
+            .update_texture(&self.device, &self.queue, &gui.texture);
+        self.egui_rpass
+            .update_user_textures(&self.device, &self.queue);
+        self.egui_rpass.update_buffers(
+            &self.device,
+            &self.queue,
+            &gui.paint_jobs,
+            &screen_descriptor,
+        );
+
+        // Draw on top of whatever the main pass left behind.
+        self.egui_rpass
+            .execute(encoder, view, &gui.paint_jobs, &screen_descriptor, None)?;
+
+        Ok(())
+    }
+
+    fn pass(&self, encoder: &mut CommandEncoder, view: &TextureView) {
+        match &self.deferred {
+            Some(deferred) => self.deferred_pass(deferred, encoder, view),
+            None => self.forward_pass(encoder, view),
+        }
+    }
+
+    fn forward_pass(&self, encoder: &mut CommandEncoder, view: &TextureView) {
+        // With SSAO on the depth buffer is already filled in by the time we
+        // shade, so keep it rather than clearing
+        let depth_load = if self.ssao.enabled() {
+            self.depth_prepass(encoder);
+            wgpu::LoadOp::Load
+        } else {
+            wgpu::LoadOp::Clear(1.0)
+        };
+        self.ssao.run(encoder);
+
         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             label: Some("Render Pass"),
             color_attachments: &[wgpu::RenderPassColorAttachment {
                 view: &view,
                 resolve_target: None,
                 ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(wgpu::Color {
-                        r: 0.1,
-                        g: 0.2,
-                        b: 0.3,
-                        a: 1.0,
-                    }),
+                    load: wgpu::LoadOp::Clear(self.clear_color()),
                     store: true,
                 },
             }],
             depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
                 view: &self.depth_texture.view,
                 depth_ops: Some(wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(1.0),
+                    load: depth_load,
                     store: true,
                 }),
                 stencil_ops: None,
@@ -417,20 +736,300 @@ impl Render {
 
         render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
 
-        render_pass.set_pipeline(&self.light_pipeline);
-        render_pass.draw_light_model(
-            &self.obj_model,
+        if self.debug.show_light {
+            render_pass.set_pipeline(&self.light_pipeline);
+            render_pass.draw_light_model(
+                self.assets.model(&self.obj_model),
+                &self.assets,
+                &self.camera_bind_group,
+                &self.light_bind_group,
+            );
+        }
+
+        // Everything opaque goes first so the translucent meshes have
+        // something behind them to blend with. They aren't sorted among
+        // themselves.
+        for translucent in [false, true] {
+            render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+            draw_posed_model(
+                &mut render_pass,
+                self.assets.model(&self.obj_model),
+                self.obj_skin.as_ref(),
+                &self.instance_lods,
+                &self.main_pipeline,
+                &self.skinned_pipeline,
+                translucent,
+                &self.assets,
+                &self.camera_bind_group,
+                &self.light_bind_group,
+            );
+
+            for animated in &self.animated_models {
+                animated.draw(
+                    &mut render_pass,
+                    &self.main_pipeline,
+                    &self.skinned_pipeline,
+                    translucent,
+                    &self.assets,
+                    &self.camera_bind_group,
+                    &self.light_bind_group,
+                );
+            }
+        }
+    }
+
+    fn depth_prepass(&self, encoder: &mut CommandEncoder) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Depth Prepass"),
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &self.depth_texture.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+
+        // Translucent meshes would hide whatever is behind them from the
+        // forward pass, so they're left out
+        render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+        draw_posed_model(
+            &mut render_pass,
+            self.assets.model(&self.obj_model),
+            self.obj_skin.as_ref(),
+            &self.instance_lods,
+            &self.depth_prepass_pipeline,
+            &self.skinned_depth_prepass_pipeline,
+            false,
+            &self.assets,
             &self.camera_bind_group,
             &self.light_bind_group,
         );
 
-        render_pass.set_pipeline(&self.main_pipeline);
-        render_pass.draw_model_instanced(
-            &self.obj_model,
-            0..self.instances.len() as u32,
+        for animated in &self.animated_models {
+            animated.draw(
+                &mut render_pass,
+                &self.depth_prepass_pipeline,
+                &self.skinned_depth_prepass_pipeline,
+                false,
+                &self.assets,
+                &self.camera_bind_group,
+                &self.light_bind_group,
+            );
+        }
+    }
+
+    fn deferred_pass(
+        &self,
+        deferred: &DeferredRenderer,
+        encoder: &mut CommandEncoder,
+        view: &TextureView,
+    ) {
+        deferred.geometry_pass(
+            encoder,
+            &self.depth_texture.view,
+            self.assets.model(&self.obj_model),
+            self.obj_skin.as_ref(),
+            &self.assets,
+            &self.instance_buffer,
+            &self.instance_lods,
+            &self.animated_models,
             &self.camera_bind_group,
             &self.light_bind_group,
         );
+
+        self.ssao.run(encoder);
+        deferred.lighting_pass(encoder, view, self.clear_color(), &self.camera_bind_group);
+
+        if self.debug.show_light {
+            // The light marker isn't lit, so draw it forward on top using
+            // the depth from the geometry pass
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Light Marker Pass"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.depth_texture.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+
+            render_pass.set_pipeline(&self.light_pipeline);
+            render_pass.draw_light_model(
+                self.assets.model(&self.obj_model),
+                &self.assets,
+                &self.camera_bind_group,
+                &self.light_bind_group,
+            );
+        }
+    }
+
+    /// Picks a LOD for each instance from how much of the screen it covers,
+    /// then sorts the instance buffer so each LOD's instances are together
+    /// and can be drawn with one call.
+    fn update_lods(&mut self) {
+        let radius = self.assets.model(&self.obj_model).radius;
+        let half_height = (self.projection.fov / 2.0).tan();
+
+        let mut by_lod = vec![Vec::new(); LOD_SCREEN_SIZES.len() + 1];
+        for instance in &self.instances {
+            let distance = instance
+                .position
+                .distance(self.camera.position)
+                .max(f32::EPSILON);
+            let radius = radius * instance.scale.abs().max_element();
+            let screen_size = radius / (distance * half_height);
+            by_lod[lod_for_screen_size(screen_size)].push(instance.to_raw());
+        }
+
+        let mut start = 0;
+        self.instance_lods = by_lod
+            .iter()
+            .map(|instances| {
+                let range = start..start + instances.len() as u32;
+                start = range.end;
+                range
+            })
+            .collect();
+        self.queue.write_buffer(
+            &self.instance_buffer,
+            0,
+            bytemuck::cast_slice(&by_lod.concat()),
+        );
+    }
+
+    /// Plays the animations on by `dt` and moves their targets. Later
+    /// animations win when two drive the same property.
+    fn update_animations(&mut self, dt: Duration) {
+        for player in &mut self.animations {
+            player.advance(dt);
+
+            let time = player.time();
+            for track in player.clip().tracks() {
+                let target: Option<&mut dyn Animatable> = match track.target {
+                    Target::Instance(i) => {
+                        self.instances.get_mut(i).map(|i| i as &mut dyn Animatable)
+                    }
+                    Target::Light(0) => Some(&mut self.light_uniform),
+                    Target::Light(i) => self
+                        .point_lights
+                        .get_mut(i - 1)
+                        .map(|l| l as &mut dyn Animatable),
+                    Target::Camera => Some(&mut self.camera),
+                };
+                if let Some(target) = target {
+                    track.apply(time, target);
+                }
+            }
+        }
+    }
+
+    fn clear_color(&self) -> wgpu::Color {
+        self.fog.clear_color().unwrap_or(CLEAR_COLOR)
+    }
+
+    /// Loads a skinned model and stands it at `position`, looping its first
+    /// animation.
+    pub fn add_animated_model(&mut self, path: impl AsRef<Path>, position: Vec3) {
+        let model = self.loader.load_model(
+            &mut self.assets,
+            &self.device,
+            &self.queue,
+            &self.texture_bind_group_layout,
+            path,
+        );
+        self.place_model(model, position);
+    }
+
+    /// Stands one of each shape from [`primitives`] in a row along `X` from
+    /// `position`, for checking lighting, normal maps and the vertex
+    /// encodings against meshes that are known to be right.
+    pub fn add_primitives(&mut self, position: Vec3) {
+        let shapes = vec![
+            primitives::cube(1.0),
+            primitives::plane(1.5, 1.5, 4),
+            primitives::uv_sphere(0.6, 32, 16),
+            primitives::icosphere(0.6, 3),
+            primitives::cylinder(0.5, 1.2, 24),
+            primitives::cone(0.6, 1.2, 24),
+            primitives::torus(0.5, 0.2, 32, 16),
+        ];
+        for (i, mesh) in shapes.into_iter().enumerate() {
+            let name = mesh.name.clone();
+            let data = ModelData::from_meshes(&name, vec![mesh]);
+            // Its only material is a solid color, so there's no texture to
+            // fail on
+            let model = Model::from_data(
+                &self.device,
+                &self.queue,
+                &self.texture_bind_group_layout,
+                &mut self.assets,
+                data,
+                |_, path, _| Err(anyhow::anyhow!("{} has no texture {:?}", name, path)),
+            )
+            .expect("Failed to build a primitive");
+            let model = self.assets.add_model(model);
+            self.place_model(model, position + vec3(i as f32 * 2.0, 0.0, 0.0));
+        }
+    }
+
+    fn place_model(&mut self, model: Handle<Model>, position: Vec3) {
+        let instance = Instance {
+            position,
+            rotation: Quat::IDENTITY,
+            scale: Vec3::ONE,
+        };
+        let instance_buffer = self.device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Animated Instance Buffer"),
+            contents: bytemuck::cast_slice(&[instance.to_raw()]),
+            usage: BufferUsages::VERTEX,
+        });
+
+        self.animated_models.push(AnimatedModel {
+            model,
+            instance_buffer,
+            skin: None,
+            animation: 0,
+            time: 0.0,
+        });
+    }
+
+    /// The names of each animated model's animations, and which one it's
+    /// playing. Empty until the model has loaded.
+    pub fn animated_model_animations(&self) -> Vec<(Vec<String>, usize)> {
+        self.animated_models
+            .iter()
+            .map(|animated| {
+                let model = self.assets.model(&animated.model);
+                let names = model.animations.iter().map(|a| a.name.clone()).collect();
+                (names, animated.animation)
+            })
+            .collect()
+    }
+
+    /// Starts animated model `model` over on its `animation`th animation.
+    pub fn play_animated_model_animation(&mut self, model: usize, animation: usize) {
+        if let Some(animated) = self.animated_models.get_mut(model) {
+            animated.animation = animation;
+            animated.time = 0.0;
+        }
+    }
+
+    /// How many assets are still loading in the background.
+    pub fn pending_loads(&self) -> usize {
+        self.loader.pending()
     }
 
     pub fn camera_mut(&mut self) -> &mut Camera {
@@ -441,7 +1040,54 @@ impl Render {
         &mut self.light_uniform
     }
 
-    pub fn update(&mut self) {
+    /// The extra lights the deferred path shades with, or `None` on the
+    /// forward path, which only has the one.
+    pub fn point_lights_mut(&mut self) -> Option<&mut Vec<LightUniform>> {
+        match self.deferred {
+            Some(_) => Some(&mut self.point_lights),
+            None => None,
+        }
+    }
+
+    pub fn animations_mut(&mut self) -> &mut Vec<AnimationPlayer> {
+        &mut self.animations
+    }
+
+    pub fn projection_mut(&mut self) -> &mut Projection {
+        &mut self.projection
+    }
+
+    pub fn debug_mut(&mut self) -> &mut DebugSettings {
+        &mut self.debug
+    }
+
+    pub fn post_mut(&mut self) -> &mut PostSettings {
+        self.post.settings_mut()
+    }
+
+    pub fn fog_mut(&mut self) -> &mut FogSettings {
+        &mut self.fog
+    }
+
+    pub fn ssao_mut(&mut self) -> &mut SsaoSettings {
+        self.ssao.settings_mut()
+    }
+
+    pub fn sampler_settings(&self) -> SamplerSettings {
+        self.assets.sampler_settings()
+    }
+
+    /// Switches every texture over to `sampler`, see
+    /// [`Assets::set_sampler_settings`].
+    pub fn set_sampler_settings(&mut self, sampler: SamplerSettings) {
+        self.assets
+            .set_sampler_settings(&self.device, &self.texture_bind_group_layout, sampler);
+    }
+
+    pub fn update(&mut self, dt: Duration) {
+        // Before anything reads the instances, lights or camera
+        self.update_animations(dt);
+
         self.camera_uniform
             .update_view_proj(&self.camera, &self.projection);
 
@@ -456,20 +1102,264 @@ impl Render {
             0,
             bytemuck::cast_slice(&[self.light_uniform]),
         );
+
+        self.update_lods();
+
+        if let Some(watcher) = &self.watcher {
+            for path in watcher.changed() {
+                self.loader.reload(&self.assets, path);
+            }
+        }
+        self.loader.finish(
+            &mut self.assets,
+            &self.device,
+            &self.queue,
+            &self.texture_bind_group_layout,
+        );
+        self.assets.collect_garbage();
+
+        match &self.assets.model(&self.obj_model).skeleton {
+            Some(skeleton) => upload_pose(
+                &mut self.obj_skin,
+                &self.device,
+                &self.queue,
+                &self.skin_bind_group_layout,
+                skeleton,
+                &skeleton.rest_pose(),
+            ),
+            None => self.obj_skin = None,
+        }
+
+        for animated in &mut self.animated_models {
+            animated.update(
+                &self.device,
+                &self.queue,
+                &self.assets,
+                &self.skin_bind_group_layout,
+                dt,
+            );
+        }
+
+        self.ssao.update(&self.queue, &self.projection);
+        self.post.update(&self.queue);
+
+        if let Some(deferred) = &mut self.deferred {
+            deferred.update_lights(
+                &self.queue,
+                std::iter::once(&self.light_uniform).chain(&self.point_lights),
+            );
+        }
+
+        self.queue.write_buffer(
+            &self.debug_buffer,
+            0,
+            bytemuck::cast_slice(&[DebugUniform {
+                view: self.debug.view as u32,
+                _padding: [0; 3],
+            }]),
+        );
+
+        self.queue.write_buffer(
+            &self.fog_buffer,
+            0,
+            bytemuck::cast_slice(&[self.fog.to_uniform()]),
+        );
+    }
+}
+
+impl LightUniform {
+    pub fn new(position: Vec3, radius: f32, color: Vec3) -> Self {
+        Self {
+            position,
+            radius,
+            color,
+            intensity: 1.0,
+        }
+    }
+}
+
+impl Animatable for LightUniform {
+    fn set_position(&mut self, position: Vec3) {
+        self.position = position;
+    }
+
+    fn set_color(&mut self, color: Vec3) {
+        self.color = color;
+    }
+
+    fn set_intensity(&mut self, intensity: f32) {
+        self.intensity = intensity;
+    }
+}
+
+impl AnimatedModel {
+    /// Moves the animation on by `dt` and uploads the pose. Does nothing
+    /// until the model has loaded, or at all if it doesn't have a skeleton.
+    fn update(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        assets: &Assets,
+        layout: &BindGroupLayout,
+        dt: Duration,
+    ) {
+        let model = assets.model(&self.model);
+        let skeleton = match &model.skeleton {
+            Some(skeleton) => skeleton,
+            None => return,
+        };
+
+        let mut pose = skeleton.rest_pose();
+        if let Some(animation) = model.animations.get(self.animation) {
+            self.time += dt.as_secs_f32();
+            if animation.duration > 0.0 {
+                self.time %= animation.duration;
+            }
+            animation.sample(self.time, &mut pose);
+        }
+
+        upload_pose(&mut self.skin, device, queue, layout, skeleton, &pose);
+    }
+
+    /// Draws the opaque or the translucent meshes, see [`draw_posed_model`].
+    pub(crate) fn draw<'a>(
+        &'a self,
+        render_pass: &mut RenderPass<'a>,
+        pipeline: &'a RenderPipeline,
+        skinned_pipeline: &'a RenderPipeline,
+        translucent: bool,
+        assets: &'a Assets,
+        camera: &'a BindGroup,
+        light: &'a BindGroup,
+    ) {
+        render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+        draw_posed_model(
+            render_pass,
+            assets.model(&self.model),
+            self.skin.as_ref(),
+            &[0..1],
+            pipeline,
+            skinned_pipeline,
+            translucent,
+            assets,
+            camera,
+            light,
+        );
+    }
+}
+
+/// Makes `skin` fit `skeleton` and uploads `pose` to it. Hot reloading can
+/// swap in a different skeleton, so this has to be checked every time.
+fn upload_pose(
+    skin: &mut Option<Skin>,
+    device: &Device,
+    queue: &Queue,
+    layout: &BindGroupLayout,
+    skeleton: &Skeleton,
+    pose: &[Transform],
+) {
+    let joint_count = skeleton.joints.len();
+    if skin.as_ref().map(Skin::joint_count) != Some(joint_count) {
+        *skin = Some(Skin::new(device, layout, joint_count));
+    }
+    if let Some(skin) = skin {
+        skin.update(queue, &skeleton.joint_matrices(pose));
+    }
+}
+
+/// Draws either the opaque or the translucent meshes of `model`, the
+/// skinned ones with `skinned_pipeline` posed by `skin` and the rest with
+/// `pipeline`. Skinned meshes are left out until there's a pose for them.
+///
+/// The instances have to be bound already, `lods` says which of them are
+/// drawn at each LOD.
+pub(crate) fn draw_posed_model<'a>(
+    render_pass: &mut RenderPass<'a>,
+    model: &'a Model,
+    skin: Option<&'a Skin>,
+    lods: &[Range<u32>],
+    pipeline: &'a RenderPipeline,
+    skinned_pipeline: &'a RenderPipeline,
+    translucent: bool,
+    assets: &'a Assets,
+    camera: &'a BindGroup,
+    light: &'a BindGroup,
+) {
+    for mesh in &model.meshes {
+        let mesh = assets.mesh(mesh);
+        let material = assets.material(&model.materials[mesh.material]);
+        if material.translucent != translucent {
+            continue;
+        }
+
+        match (&mesh.skin_buffer, skin) {
+            (None, _) => {
+                render_pass.set_pipeline(pipeline);
+                for (lod, instances) in lods.iter().enumerate() {
+                    render_pass.draw_mesh_lod_instanced(
+                        mesh,
+                        material,
+                        lod,
+                        instances.clone(),
+                        camera,
+                        light,
+                    );
+                }
+            }
+            // Skinned meshes don't get LODs, every instance uses the full one
+            (Some(_), Some(skin)) => {
+                render_pass.set_pipeline(skinned_pipeline);
+                for instances in lods.iter().filter(|instances| !instances.is_empty()) {
+                    render_pass.draw_skinned_mesh_instanced(
+                        mesh,
+                        material,
+                        &skin.bind_group,
+                        instances.clone(),
+                        camera,
+                        light,
+                    );
+                }
+            }
+            (Some(_), None) => {}
+        }
     }
 }
 
 impl Instance {
     fn to_raw(&self) -> InstanceRaw {
+        let model = Mat4::from_scale_rotation_translation(self.scale, self.rotation, self.position);
+        // Scaling unevenly skews normals, the inverse transpose undoes that.
+        // Scaled flat to nothing there's no inverse, but nothing to light
+        // either.
+        let linear = Mat3::from_mat4(model);
+        let normal = if linear.determinant() == 0.0 {
+            Mat3::IDENTITY
+        } else {
+            linear.inverse().transpose()
+        };
         InstanceRaw {
-            model: Mat4::from_rotation_translation(self.rotation, self.position).to_cols_array_2d(),
-            normal: Mat3::from_quat(self.rotation).to_cols_array_2d(),
+            model: model.to_cols_array_2d(),
+            normal: normal.to_cols_array_2d(),
         }
     }
 }
 
+impl Animatable for Instance {
+    fn set_position(&mut self, position: Vec3) {
+        self.position = position;
+    }
+
+    fn set_rotation(&mut self, rotation: Quat) {
+        self.rotation = rotation;
+    }
+
+    fn set_scale(&mut self, scale: Vec3) {
+        self.scale = scale;
+    }
+}
+
 impl InstanceRaw {
-    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+    pub(crate) fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
         use std::mem;
         wgpu::VertexBufferLayout {
             array_stride: mem::size_of::<InstanceRaw>() as wgpu::BufferAddress,
@@ -480,9 +1370,9 @@ impl InstanceRaw {
             attributes: &[
                 wgpu::VertexAttribute {
                     offset: 0,
-                    // While our vertex shader only uses locations 0, and 1 now, in later tutorials we'll
-                    // be using 2, 3, and 4, for Vertex. We'll start at slot 5 not conflict with them later
-                    shader_location: 5,
+                    // Every vertex encoding uses locations 0 to 3, so we start at slot 4
+                    // to not conflict with them
+                    shader_location: 4,
                     format: wgpu::VertexFormat::Float32x4,
                 },
                 // A mat4 takes up 4 vertex slots as it is technically 4 vec4s. We need to define a slot
@@ -490,32 +1380,32 @@ impl InstanceRaw {
                 // the shader.
                 wgpu::VertexAttribute {
                     offset: mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
-                    shader_location: 6,
+                    shader_location: 5,
                     format: wgpu::VertexFormat::Float32x4,
                 },
                 wgpu::VertexAttribute {
                     offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
-                    shader_location: 7,
+                    shader_location: 6,
                     format: wgpu::VertexFormat::Float32x4,
                 },
                 wgpu::VertexAttribute {
                     offset: mem::size_of::<[f32; 12]>() as wgpu::BufferAddress,
-                    shader_location: 8,
+                    shader_location: 7,
                     format: wgpu::VertexFormat::Float32x4,
                 },
                 wgpu::VertexAttribute {
                     offset: mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
-                    shader_location: 9,
+                    shader_location: 8,
                     format: wgpu::VertexFormat::Float32x3,
                 },
                 wgpu::VertexAttribute {
                     offset: mem::size_of::<[f32; 19]>() as wgpu::BufferAddress,
-                    shader_location: 10,
+                    shader_location: 9,
                     format: wgpu::VertexFormat::Float32x3,
                 },
                 wgpu::VertexAttribute {
                     offset: mem::size_of::<[f32; 22]>() as wgpu::BufferAddress,
-                    shader_location: 11,
+                    shader_location: 10,
                     format: wgpu::VertexFormat::Float32x3,
                 },
             ],
@@ -523,19 +1413,64 @@ impl InstanceRaw {
     }
 }
 
-fn create_render_pipeline(
+fn create_camera_bind_group(
+    device: &Device,
+    layout: &BindGroupLayout,
+    camera_buffer: &Buffer,
+    debug_buffer: &Buffer,
+    fog_buffer: &Buffer,
+    ao_view: &TextureView,
+) -> BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: camera_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: debug_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(ao_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: fog_buffer.as_entire_binding(),
+            },
+        ],
+        label: Some("camera_bind_group"),
+    })
+}
+
+pub(crate) fn create_render_pipeline(
     name: &str,
     device: &wgpu::Device,
     layout: &wgpu::PipelineLayout,
-    color_format: wgpu::TextureFormat,
+    color_formats: &[wgpu::TextureFormat],
     depth_format: Option<wgpu::TextureFormat>,
     vertex_layouts: &[wgpu::VertexBufferLayout],
     vertex_entry_point: &str,
     frag_entry_point: &str,
     shader: wgpu::ShaderModuleDescriptor,
+    blend: Option<wgpu::BlendState>,
 ) -> wgpu::RenderPipeline {
     let shader = device.create_shader_module(&shader);
 
+    let targets = color_formats
+        .iter()
+        .map(|&format| wgpu::ColorTargetState {
+            format,
+            blend: Some(blend.unwrap_or(wgpu::BlendState {
+                alpha: wgpu::BlendComponent::REPLACE,
+                color: wgpu::BlendComponent::REPLACE,
+            })),
+            write_mask: wgpu::ColorWrites::ALL,
+        })
+        .collect::<Vec<_>>();
+
     device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
         label: Some(name),
         layout: Some(layout),
@@ -544,18 +1479,16 @@ fn create_render_pipeline(
             entry_point: vertex_entry_point,
             buffers: vertex_layouts,
         },
-        fragment: Some(wgpu::FragmentState {
-            module: &shader,
-            entry_point: frag_entry_point,
-            targets: &[wgpu::ColorTargetState {
-                format: color_format,
-                blend: Some(wgpu::BlendState {
-                    alpha: wgpu::BlendComponent::REPLACE,
-                    color: wgpu::BlendComponent::REPLACE,
-                }),
-                write_mask: wgpu::ColorWrites::ALL,
-            }],
-        }),
+        // Depth only pipelines don't need a fragment stage at all
+        fragment: if targets.is_empty() {
+            None
+        } else {
+            Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: frag_entry_point,
+                targets: &targets,
+            })
+        },
         primitive: wgpu::PrimitiveState {
             topology: wgpu::PrimitiveTopology::TriangleList,
             strip_index_format: None,
@@ -571,7 +1504,8 @@ fn create_render_pipeline(
         depth_stencil: depth_format.map(|format| wgpu::DepthStencilState {
             format,
             depth_write_enabled: true,
-            depth_compare: wgpu::CompareFunction::Less,
+            // LessEqual so the forward pass can draw over the depth prepass
+            depth_compare: wgpu::CompareFunction::LessEqual,
             stencil: wgpu::StencilState::default(),
             bias: wgpu::DepthBiasState::default(),
         }),
@@ -0,0 +1,165 @@
+use glam::{Mat4, Quat, Vec3};
+use wgpu::{BindGroup, Buffer};
+
+use crate::animation::{Keyframes, Transform};
+
+#[derive(Debug, Clone)]
+pub struct Joint {
+    /// Always comes before the joint itself in the skeleton.
+    pub parent: Option<usize>,
+    /// Where the joint sits relative to its parent when nothing's moving it.
+    pub rest: Transform,
+    /// Takes the mesh into the joint's space, as it was when the mesh was
+    /// bound to the skeleton.
+    pub inverse_bind: Mat4,
+    /// Only used by joints without a parent, whatever they hang off that
+    /// isn't part of the skeleton, like the armature in a Blender export.
+    pub root: Mat4,
+}
+
+/// A hierarchy of joints that skinned meshes follow, ordered so every
+/// joint's parent comes before it.
+#[derive(Debug, Clone)]
+pub struct Skeleton {
+    pub joints: Vec<Joint>,
+}
+
+/// Moves a skeleton's joints over time. Joints without channels stay where
+/// the pose they're sampled into already had them.
+#[derive(Debug, Clone)]
+pub struct SkeletalAnimation {
+    pub name: String,
+    /// In seconds, when the last channel ends.
+    pub duration: f32,
+    pub channels: Vec<JointChannel>,
+}
+
+#[derive(Debug, Clone)]
+pub struct JointChannel {
+    /// Index into the skeleton's `joints`.
+    pub joint: usize,
+    pub property: JointProperty,
+}
+
+/// Which part of a joint's transform a channel drives.
+#[derive(Debug, Clone)]
+pub enum JointProperty {
+    Translation(Keyframes<Vec3>),
+    Rotation(Keyframes<Quat>),
+    Scale(Keyframes<Vec3>),
+}
+
+impl Skeleton {
+    /// Every joint at rest, for animations to be sampled into.
+    pub fn rest_pose(&self) -> Vec<Transform> {
+        self.joints.iter().map(|joint| joint.rest).collect()
+    }
+
+    /// What the vertex shader needs for `pose`, which has a transform for
+    /// each joint. Each matrix takes a vertex from where it was bound to
+    /// where its joint has moved it.
+    pub fn joint_matrices(&self, pose: &[Transform]) -> Vec<Mat4> {
+        let mut globals = Vec::<Mat4>::with_capacity(self.joints.len());
+        for (joint, local) in self.joints.iter().zip(pose) {
+            let parent = joint.parent.map_or(joint.root, |parent| globals[parent]);
+            globals.push(parent * local.to_matrix());
+        }
+
+        globals
+            .iter()
+            .zip(&self.joints)
+            .map(|(global, joint)| *global * joint.inverse_bind)
+            .collect()
+    }
+}
+
+impl SkeletalAnimation {
+    /// Poses `pose` at `time` seconds in.
+    pub fn sample(&self, time: f32, pose: &mut [Transform]) {
+        for channel in &self.channels {
+            let transform = &mut pose[channel.joint];
+            match &channel.property {
+                JointProperty::Translation(keyframes) => {
+                    transform.translation = keyframes.sample(time)
+                }
+                JointProperty::Rotation(keyframes) => transform.rotation = keyframes.sample(time),
+                JointProperty::Scale(keyframes) => transform.scale = keyframes.sample(time),
+            }
+        }
+    }
+}
+
+impl JointProperty {
+    pub fn duration(&self) -> f32 {
+        match self {
+            JointProperty::Translation(keyframes) | JointProperty::Scale(keyframes) => {
+                keyframes.duration()
+            }
+            JointProperty::Rotation(keyframes) => keyframes.duration(),
+        }
+    }
+}
+
+/// The joint matrices for one posed skeleton, in a storage buffer the
+/// skinning pipelines read from their last bind group.
+#[derive(Debug)]
+pub struct Skin {
+    joint_count: usize,
+    buffer: Buffer,
+    pub bind_group: BindGroup,
+}
+
+impl Skin {
+    pub fn new(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, joint_count: usize) -> Self {
+        // Bindings can't be empty, even for a skeleton with no joints
+        let size = joint_count.max(1) * std::mem::size_of::<Mat4>();
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Joint Buffer"),
+            size: size as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 1,
+                resource: buffer.as_entire_binding(),
+            }],
+            label: Some("skin_bind_group"),
+        });
+
+        Self {
+            joint_count,
+            buffer,
+            bind_group,
+        }
+    }
+
+    /// The joints are at binding 1, since 0 in the same group is where the
+    /// compact vertex layouts keep their `Dequantize` uniform.
+    pub fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+            label: Some("skin_bind_group_layout"),
+        })
+    }
+
+    pub fn joint_count(&self) -> usize {
+        self.joint_count
+    }
+
+    /// `matrices` comes from [`Skeleton::joint_matrices`].
+    pub fn update(&self, queue: &wgpu::Queue, matrices: &[Mat4]) {
+        let matrices = &matrices[..matrices.len().min(self.joint_count)];
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(matrices));
+    }
+}
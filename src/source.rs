@@ -0,0 +1,175 @@
+use std::{
+    borrow::Cow,
+    env,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::Context;
+
+/// Environment variable that points at the asset directory, for when
+/// passing `--assets` isn't convenient.
+const ASSET_DIR_VAR: &str = "CRAFT_ASSETS";
+
+/// Where asset files are read from. Every path handed to [`Assets`] and the
+/// loaders is relative to this.
+///
+/// [`Assets`]: crate::assets::Assets
+#[derive(Debug, Clone)]
+pub enum AssetSource {
+    Dir(PathBuf),
+    /// Compiled into the binary with the `embed-assets` feature.
+    #[cfg(feature = "embed-assets")]
+    Embedded,
+}
+
+#[cfg(feature = "embed-assets")]
+macro_rules! embed {
+    ($($path:literal),* $(,)?) => {
+        &[$((
+            $path,
+            include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/", $path)) as &[u8],
+        )),*]
+    };
+}
+
+/// Everything under `assets/` that gets compiled in, new files need adding
+/// here too.
+#[cfg(feature = "embed-assets")]
+const EMBEDDED: &[(&str, &[u8])] = embed![
+    "cube/cube.obj",
+    "cube/cube.mtl",
+    "cube/cube-diffuse.jpg",
+    "cube/cube-normal.png",
+    "happy-tree.png",
+];
+
+impl AssetSource {
+    /// Works out where to load assets from. In order: the `--assets` flag,
+    /// `CRAFT_ASSETS`, the embedded assets if they were compiled in, an
+    /// `assets` directory next to the executable and finally, in debug
+    /// builds, the source tree.
+    pub fn resolve(cli_dir: Option<PathBuf>) -> anyhow::Result<Self> {
+        if let Some(dir) = cli_dir {
+            return Self::dir(dir).context("Invalid --assets directory");
+        }
+
+        if let Some(dir) = env::var_os(ASSET_DIR_VAR) {
+            return Self::dir(PathBuf::from(dir))
+                .with_context(|| format!("Invalid {} directory", ASSET_DIR_VAR));
+        }
+
+        #[cfg(feature = "embed-assets")]
+        return Ok(Self::Embedded);
+
+        #[cfg(not(feature = "embed-assets"))]
+        {
+            let next_to_exe = env::current_exe()?.parent().map(|dir| dir.join("assets"));
+            if let Some(dir) = next_to_exe.filter(|dir| dir.is_dir()) {
+                return Self::dir(dir);
+            }
+
+            // Handy for `cargo run`, and means edits to the source assets
+            // get hot reloaded
+            let source_tree = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets");
+            if cfg!(debug_assertions) && source_tree.is_dir() {
+                return Self::dir(source_tree);
+            }
+
+            anyhow::bail!(
+                "Couldn't find the assets, pass --assets or set {}",
+                ASSET_DIR_VAR
+            )
+        }
+    }
+
+    fn dir(dir: PathBuf) -> anyhow::Result<Self> {
+        let dir = dir
+            .canonicalize()
+            .with_context(|| format!("{:?} doesn't exist", dir))?;
+        Ok(Self::Dir(dir))
+    }
+
+    /// The directory on disk, if there is one to watch for changes.
+    pub fn root(&self) -> Option<&Path> {
+        match self {
+            Self::Dir(dir) => Some(dir),
+            #[cfg(feature = "embed-assets")]
+            Self::Embedded => None,
+        }
+    }
+
+    /// Resolves `path` to the same key no matter how it was spelled, so it
+    /// can be used to cache what was loaded from it.
+    pub fn cache_key(&self, path: &Path) -> PathBuf {
+        match self {
+            Self::Dir(dir) => {
+                let path = normalize(&dir.join(path));
+                path.canonicalize().unwrap_or(path)
+            }
+            #[cfg(feature = "embed-assets")]
+            Self::Embedded => normalize(path),
+        }
+    }
+
+    pub fn read(&self, path: impl AsRef<Path>) -> anyhow::Result<Cow<'static, [u8]>> {
+        let path = path.as_ref();
+        match self {
+            Self::Dir(dir) => {
+                let bytes = std::fs::read(dir.join(path))
+                    .with_context(|| format!("Failed to read {:?}", path))?;
+                Ok(Cow::Owned(bytes))
+            }
+            #[cfg(feature = "embed-assets")]
+            Self::Embedded => {
+                // The table always uses forward slashes
+                let key = normalize(path)
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                EMBEDDED
+                    .iter()
+                    .find(|(name, _)| *name == key)
+                    .map(|(_, bytes)| Cow::Borrowed(*bytes))
+                    .with_context(|| format!("{:?} isn't embedded", path))
+            }
+        }
+    }
+}
+
+/// Drops `.` and cancels `..` against the directory before it, without
+/// going to the filesystem, which the file might not be on.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(
+                    normalized.components().next_back(),
+                    Some(Component::Normal(_))
+                ) =>
+            {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_spellings() {
+        assert_eq!(normalize(Path::new("./foo.png")), Path::new("foo.png"));
+        assert_eq!(
+            normalize(Path::new("a/./b/../foo.png")),
+            Path::new("a/foo.png")
+        );
+        assert_eq!(normalize(Path::new("../foo.png")), Path::new("../foo.png"));
+        assert_eq!(normalize(Path::new("/a/../foo.png")), Path::new("/foo.png"));
+    }
+}
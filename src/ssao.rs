@@ -0,0 +1,426 @@
+use std::borrow::Cow;
+
+use glam::{Mat4, Vec3};
+use wgpu::{
+    util::DeviceExt, BindGroup, BindGroupLayout, Buffer, CommandEncoder, Device, Queue,
+    RenderPipeline, SurfaceConfiguration, TextureFormat, TextureView,
+};
+
+use crate::{camera::Projection, texture::Texture};
+
+/// Size of the hemisphere kernel the uniform has room for.
+const KERNEL_SIZE: usize = 32;
+const NOISE_SIZE: u32 = 4;
+const OCCLUSION_FORMAT: TextureFormat = TextureFormat::R8Unorm;
+
+#[derive(Debug, Clone)]
+pub struct SsaoSettings {
+    pub enabled: bool,
+    /// View space radius of the sampled hemisphere.
+    pub radius: f32,
+    pub bias: f32,
+    /// Exponent applied to the result, higher values darken creases more.
+    pub intensity: f32,
+    pub sample_count: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SsaoUniform {
+    proj: Mat4,
+    inv_proj: Mat4,
+    kernel: [[f32; 4]; KERNEL_SIZE],
+    sample_count: u32,
+    radius: f32,
+    bias: f32,
+    intensity: f32,
+}
+
+/// Screen-space ambient occlusion worked out from the depth buffer alone, so
+/// it works the same for the forward and deferred paths.
+#[derive(Debug)]
+pub struct Ssao {
+    settings: SsaoSettings,
+    uniform: SsaoUniform,
+    uniform_buffer: Buffer,
+    noise: Texture,
+
+    input_layout: BindGroupLayout,
+    input_bind_group: BindGroup,
+    blur_layout: BindGroupLayout,
+    blur_bind_group: BindGroup,
+
+    occlusion: Texture,
+    blurred: Texture,
+
+    occlusion_pipeline: RenderPipeline,
+    blur_pipeline: RenderPipeline,
+}
+
+impl Default for SsaoSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            radius: 0.5,
+            bias: 0.025,
+            intensity: 1.5,
+            sample_count: KERNEL_SIZE as u32,
+        }
+    }
+}
+
+impl Ssao {
+    pub fn new(
+        device: &Device,
+        queue: &Queue,
+        config: &SurfaceConfiguration,
+        depth: &Texture,
+    ) -> Self {
+        let settings = SsaoSettings::default();
+
+        let mut rng = XorShift(0x9e37_79b9);
+        let mut kernel = [[0.0; 4]; KERNEL_SIZE];
+        for (i, sample) in kernel.iter_mut().enumerate() {
+            let direction = Vec3::new(rng.next() * 2.0 - 1.0, rng.next() * 2.0 - 1.0, rng.next())
+                .normalize_or_zero();
+
+            // Bunch the samples up close to the center of the hemisphere
+            let t = i as f32 / KERNEL_SIZE as f32;
+            let scale = 0.1 + 0.9 * t * t;
+            let sample_position = direction * rng.next() * scale;
+            *sample = [sample_position.x, sample_position.y, sample_position.z, 0.0];
+        }
+
+        let uniform = SsaoUniform {
+            proj: Mat4::IDENTITY,
+            inv_proj: Mat4::IDENTITY,
+            kernel,
+            sample_count: settings.sample_count,
+            radius: settings.radius,
+            bias: settings.bias,
+            intensity: settings.intensity,
+        };
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("SSAO Buffer"),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let noise = create_noise_texture(device, queue, &mut rng);
+
+        let input_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                texture_entry(1, wgpu::TextureSampleType::Depth),
+                texture_entry(2, wgpu::TextureSampleType::Float { filterable: false }),
+            ],
+            label: Some("ssao_bind_group_layout"),
+        });
+        let blur_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[texture_entry(
+                0,
+                wgpu::TextureSampleType::Float { filterable: false },
+            )],
+            label: Some("ssao_blur_bind_group_layout"),
+        });
+
+        let (occlusion, blurred) = create_targets(device, config);
+        let input_bind_group =
+            create_input_bind_group(device, &input_layout, &uniform_buffer, depth, &noise);
+        let blur_bind_group = create_blur_bind_group(device, &blur_layout, &occlusion);
+
+        let shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("ssao_shader"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!(
+                "../shaders/wgsl/ssao.wgsl"
+            ))),
+        });
+
+        let occlusion_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("SSAO Pipeline Layout"),
+            bind_group_layouts: &[&input_layout],
+            push_constant_ranges: &[],
+        });
+        let blur_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("SSAO Blur Pipeline Layout"),
+            bind_group_layouts: &[&input_layout, &blur_layout],
+            push_constant_ranges: &[],
+        });
+
+        let occlusion_pipeline =
+            create_pipeline(device, "ssao", &occlusion_layout, &shader, "occlusion");
+        let blur_pipeline =
+            create_pipeline(device, "ssao_blur", &blur_pipeline_layout, &shader, "blur");
+
+        Self {
+            settings,
+            uniform,
+            uniform_buffer,
+            noise,
+            input_layout,
+            input_bind_group,
+            blur_layout,
+            blur_bind_group,
+            occlusion,
+            blurred,
+            occlusion_pipeline,
+            blur_pipeline,
+        }
+    }
+
+    /// The blurred occlusion, one value per pixel where 1.0 means unoccluded.
+    pub fn occlusion_view(&self) -> &TextureView {
+        &self.blurred.view
+    }
+
+    pub fn settings_mut(&mut self) -> &mut SsaoSettings {
+        &mut self.settings
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.settings.enabled
+    }
+
+    /// The depth texture gets recreated alongside us, so the caller has to
+    /// pass the new one in.
+    pub fn resize(&mut self, device: &Device, config: &SurfaceConfiguration, depth: &Texture) {
+        let (occlusion, blurred) = create_targets(device, config);
+        self.occlusion = occlusion;
+        self.blurred = blurred;
+
+        self.input_bind_group = create_input_bind_group(
+            device,
+            &self.input_layout,
+            &self.uniform_buffer,
+            depth,
+            &self.noise,
+        );
+        self.blur_bind_group = create_blur_bind_group(device, &self.blur_layout, &self.occlusion);
+    }
+
+    pub fn update(&mut self, queue: &Queue, projection: &Projection) {
+        let proj = projection.calc_matrix();
+        self.uniform.proj = proj;
+        self.uniform.inv_proj = proj.inverse();
+        self.uniform.sample_count = self.settings.sample_count.min(KERNEL_SIZE as u32);
+        self.uniform.radius = self.settings.radius;
+        self.uniform.bias = self.settings.bias;
+        self.uniform.intensity = self.settings.intensity;
+
+        queue.write_buffer(
+            &self.uniform_buffer,
+            0,
+            bytemuck::cast_slice(&[self.uniform]),
+        );
+    }
+
+    /// Works out occlusion from whatever is in the depth texture. When
+    /// disabled the result is just cleared to white so ambient isn't touched.
+    pub fn run(&self, encoder: &mut CommandEncoder) {
+        if !self.settings.enabled {
+            encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("SSAO Clear Pass"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: &self.blurred.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            return;
+        }
+
+        {
+            let mut render_pass = begin_pass(encoder, "SSAO Pass", &self.occlusion.view);
+            render_pass.set_pipeline(&self.occlusion_pipeline);
+            render_pass.set_bind_group(0, &self.input_bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+
+        let mut render_pass = begin_pass(encoder, "SSAO Blur Pass", &self.blurred.view);
+        render_pass.set_pipeline(&self.blur_pipeline);
+        render_pass.set_bind_group(0, &self.input_bind_group, &[]);
+        render_pass.set_bind_group(1, &self.blur_bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+/// Small deterministic generator, we only need the kernel and noise to look
+/// random, not to be different every run.
+struct XorShift(u32);
+
+impl XorShift {
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 as f32 / u32::MAX as f32
+    }
+}
+
+fn create_noise_texture(device: &Device, queue: &Queue, rng: &mut XorShift) -> Texture {
+    let data = (0..NOISE_SIZE * NOISE_SIZE)
+        .flat_map(|_| [rng.next() * 2.0 - 1.0, rng.next() * 2.0 - 1.0, 0.0, 0.0])
+        .collect::<Vec<f32>>();
+
+    let size = wgpu::Extent3d {
+        width: NOISE_SIZE,
+        height: NOISE_SIZE,
+        depth_or_array_layers: 1,
+    };
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("ssao_noise"),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: TextureFormat::Rgba32Float,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+    });
+
+    queue.write_texture(
+        wgpu::ImageCopyTexture {
+            aspect: wgpu::TextureAspect::All,
+            texture: &texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+        },
+        bytemuck::cast_slice(&data),
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: std::num::NonZeroU32::new(16 * NOISE_SIZE),
+            rows_per_image: std::num::NonZeroU32::new(NOISE_SIZE),
+        },
+        size,
+    );
+
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    // Only ever read with textureLoad, but Texture wants a sampler
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor::default());
+
+    Texture {
+        texture,
+        view,
+        sampler,
+    }
+}
+
+fn create_targets(device: &Device, config: &SurfaceConfiguration) -> (Texture, Texture) {
+    let (width, height) = (config.width, config.height);
+    (
+        Texture::create_render_target(device, width, height, OCCLUSION_FORMAT, "ssao"),
+        Texture::create_render_target(device, width, height, OCCLUSION_FORMAT, "ssao_blurred"),
+    )
+}
+
+fn create_input_bind_group(
+    device: &Device,
+    layout: &BindGroupLayout,
+    uniform_buffer: &Buffer,
+    depth: &Texture,
+    noise: &Texture,
+) -> BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(&depth.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(&noise.view),
+            },
+        ],
+        label: Some("ssao_bind_group"),
+    })
+}
+
+fn create_blur_bind_group(
+    device: &Device,
+    layout: &BindGroupLayout,
+    occlusion: &Texture,
+) -> BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[wgpu::BindGroupEntry {
+            binding: 0,
+            resource: wgpu::BindingResource::TextureView(&occlusion.view),
+        }],
+        label: Some("ssao_blur_bind_group"),
+    })
+}
+
+fn texture_entry(binding: u32, sample_type: wgpu::TextureSampleType) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Texture {
+            multisampled: false,
+            view_dimension: wgpu::TextureViewDimension::D2,
+            sample_type,
+        },
+        count: None,
+    }
+}
+
+fn begin_pass<'a>(
+    encoder: &'a mut CommandEncoder,
+    label: &str,
+    view: &'a TextureView,
+) -> wgpu::RenderPass<'a> {
+    encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some(label),
+        color_attachments: &[wgpu::RenderPassColorAttachment {
+            view,
+            resolve_target: None,
+            ops: wgpu::Operations {
+                load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                store: true,
+            },
+        }],
+        depth_stencil_attachment: None,
+    })
+}
+
+fn create_pipeline(
+    device: &Device,
+    name: &str,
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    entry_point: &str,
+) -> RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(name),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "fullscreen",
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point,
+            targets: &[OCCLUSION_FORMAT.into()],
+        }),
+        primitive: wgpu::PrimitiveState::default(),
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+    })
+}
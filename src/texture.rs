@@ -1,7 +1,8 @@
-use std::path::Path;
+use std::{collections::HashMap, num::NonZeroU32};
 
 use anyhow::*;
-use image::GenericImageView;
+
+use crate::compressed::{self, CompressedImage};
 
 #[derive(Debug)]
 pub struct Texture {
@@ -10,88 +11,595 @@ pub struct Texture {
     pub sampler: wgpu::Sampler,
 }
 
-impl Texture {
-    pub fn from_bytes(
+/// How a texture gets filtered and what happens past the edges of its UVs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplerSettings {
+    pub address_mode_u: wgpu::AddressMode,
+    pub address_mode_v: wgpu::AddressMode,
+    pub mag_filter: wgpu::FilterMode,
+    pub min_filter: wgpu::FilterMode,
+    pub mipmap_filter: wgpu::FilterMode,
+    /// 1 turns anisotropic filtering off, otherwise a power of two up to 16.
+    /// Ignored on adapters that can't do it.
+    pub anisotropy: u8,
+}
+
+impl Default for SamplerSettings {
+    /// Tiling, trilinear and 16x anisotropic.
+    fn default() -> Self {
+        Self {
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            anisotropy: 16,
+        }
+    }
+}
+
+impl SamplerSettings {
+    pub fn create_sampler(&self, device: &wgpu::Device, label: Option<&str>) -> wgpu::Sampler {
+        // wgpu only takes anisotropy when every filter is linear
+        let all_linear = [self.mag_filter, self.min_filter, self.mipmap_filter]
+            .iter()
+            .all(|filter| *filter == wgpu::FilterMode::Linear);
+        let anisotropy_clamp = Some(self.anisotropy.clamp(1, 16).next_power_of_two())
+            .filter(|anisotropy| all_linear && *anisotropy > 1)
+            .and_then(std::num::NonZeroU8::new);
+
+        device.create_sampler(&wgpu::SamplerDescriptor {
+            label,
+            address_mode_u: self.address_mode_u,
+            address_mode_v: self.address_mode_v,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: self.mag_filter,
+            min_filter: self.min_filter,
+            mipmap_filter: self.mipmap_filter,
+            anisotropy_clamp,
+            ..Default::default()
+        })
+    }
+}
+
+/// Builds mip chains on the GPU by rendering each level from the one above
+/// it. Pipelines are made the first time a format is seen and kept around.
+#[derive(Debug)]
+pub struct MipmapGenerator {
+    shader: wgpu::ShaderModule,
+    layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    pipelines: HashMap<wgpu::TextureFormat, wgpu::RenderPipeline>,
+}
+
+impl MipmapGenerator {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let shader =
+            device.create_shader_module(&wgpu::include_wgsl!("../shaders/wgsl/mipmap.wgsl"));
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler {
+                        comparison: false,
+                        filtering: true,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("mipmap_bind_group_layout"),
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        Self {
+            shader,
+            layout,
+            sampler,
+            pipelines: HashMap::new(),
+        }
+    }
+
+    /// Fills in every level of `texture` after the first, for each of its
+    /// layers. It needs to have been created with `RENDER_ATTACHMENT` usage.
+    pub fn generate(
+        &mut self,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
-        bytes: &[u8],
-        label: &str,
-        is_normal_map: bool,
-    ) -> Result<Self> {
-        let img = image::load_from_memory(bytes)?;
-        Self::from_image(device, queue, &img, Some(label), is_normal_map)
+        texture: &wgpu::Texture,
+        format: wgpu::TextureFormat,
+        mip_level_count: u32,
+        layer_count: u32,
+    ) {
+        let Self {
+            shader,
+            layout,
+            sampler,
+            pipelines,
+        } = self;
+        let pipeline = pipelines.entry(format).or_insert_with(|| {
+            let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Mipmap Pipeline Layout"),
+                bind_group_layouts: &[layout],
+                push_constant_ranges: &[],
+            });
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("mipmap"),
+                layout: Some(&pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: shader,
+                    entry_point: "fullscreen",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: shader,
+                    entry_point: "downsample",
+                    targets: &[wgpu::ColorTargetState {
+                        format,
+                        blend: None,
+                        write_mask: wgpu::ColorWrites::ALL,
+                    }],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+            })
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Mipmap Encoder"),
+        });
+        for layer in 0..layer_count {
+            let views = (0..mip_level_count)
+                .map(|level| {
+                    texture.create_view(&wgpu::TextureViewDescriptor {
+                        label: Some("mip"),
+                        dimension: Some(wgpu::TextureViewDimension::D2),
+                        base_mip_level: level,
+                        mip_level_count: NonZeroU32::new(1),
+                        base_array_layer: layer,
+                        array_layer_count: NonZeroU32::new(1),
+                        ..Default::default()
+                    })
+                })
+                .collect::<Vec<_>>();
+            Self::downsample(device, &mut encoder, pipeline, layout, sampler, &views);
+        }
+        queue.submit(std::iter::once(encoder.finish()));
     }
 
-    pub fn load(
+    fn downsample(
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        pipeline: &wgpu::RenderPipeline,
+        layout: &wgpu::BindGroupLayout,
+        sampler: &wgpu::Sampler,
+        views: &[wgpu::TextureView],
+    ) {
+        for pair in views.windows(2) {
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&pair[0]),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(sampler),
+                    },
+                ],
+                label: Some("mipmap_bind_group"),
+            });
+
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Mipmap Pass"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: &pair[1],
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(0, &bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+    }
+}
+
+/// An image that's been read and decoded, ready to upload.
+#[derive(Debug)]
+pub enum ImageData {
+    Uncompressed(image::DynamicImage),
+    /// From a KTX2 or DDS file, along with its mips.
+    Compressed(CompressedImage),
+    /// Every mip level already made, largest first. For images like atlases
+    /// where a full chain would blur things together that shouldn't be.
+    Mipmapped(Vec<image::RgbaImage>),
+    /// The layers of a texture array, which all have to be the same size.
+    Layers(Vec<image::RgbaImage>),
+}
+
+/// How many levels a full mip chain down to 1x1 has.
+pub fn mip_level_count(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
+fn rgba8_format(is_linear: bool) -> wgpu::TextureFormat {
+    if is_linear {
+        wgpu::TextureFormat::Rgba8Unorm
+    } else {
+        wgpu::TextureFormat::Rgba8UnormSrgb
+    }
+}
+
+fn write_rgba8(
+    queue: &wgpu::Queue,
+    texture: &wgpu::Texture,
+    layer: u32,
+    level: u32,
+    image: &image::RgbaImage,
+) {
+    queue.write_texture(
+        wgpu::ImageCopyTexture {
+            aspect: wgpu::TextureAspect::All,
+            texture,
+            mip_level: level,
+            origin: wgpu::Origin3d {
+                x: 0,
+                y: 0,
+                z: layer,
+            },
+        },
+        image,
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: NonZeroU32::new(4 * image.width()),
+            rows_per_image: NonZeroU32::new(image.height()),
+        },
+        wgpu::Extent3d {
+            width: image.width(),
+            height: image.height(),
+            depth_or_array_layers: 1,
+        },
+    );
+}
+
+/// Materials sample every texture as a `texture_2d_array`, so even single
+/// images get an array view.
+fn array_view(texture: &wgpu::Texture, label: Option<&str>) -> wgpu::TextureView {
+    texture.create_view(&wgpu::TextureViewDescriptor {
+        label,
+        dimension: Some(wgpu::TextureViewDimension::D2Array),
+        ..Default::default()
+    })
+}
+
+impl ImageData {
+    /// Decodes anything `image` can, or a KTX2 or DDS container.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if compressed::is_compressed(bytes) {
+            Ok(Self::Compressed(CompressedImage::from_bytes(bytes)?))
+        } else {
+            Ok(Self::Uncompressed(image::load_from_memory(bytes)?))
+        }
+    }
+}
+
+impl Texture {
+    pub fn from_data(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
-        path: impl AsRef<Path>,
-        is_normal_map: bool,
+        data: &ImageData,
+        label: Option<&str>,
+        is_linear: bool,
+        sampler: &SamplerSettings,
+        mipmaps: Option<&mut MipmapGenerator>,
     ) -> Result<Self> {
-        // Needed to appease the borrow checker
-        let path_copy = path.as_ref().to_path_buf();
-        let label = path_copy.to_str();
+        match data {
+            ImageData::Uncompressed(img) => {
+                Self::from_image(device, queue, img, label, is_linear, sampler, mipmaps)
+            }
+            ImageData::Compressed(image) => {
+                Self::from_compressed(device, queue, image, label, is_linear, sampler)
+            }
+            ImageData::Mipmapped(levels) => {
+                Self::from_mip_chain(device, queue, levels, label, is_linear, sampler)
+            }
+            ImageData::Layers(layers) => {
+                Self::from_layers(device, queue, layers, label, is_linear, sampler, mipmaps)
+            }
+        }
+    }
 
-        let img = image::open(path)?;
-        Self::from_image(device, queue, &img, label, is_normal_map)
+    /// A single pixel texture, for placeholders and materials that don't
+    /// have a texture of their own.
+    pub fn solid(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        color: [u8; 4],
+        label: &str,
+        is_linear: bool,
+    ) -> Self {
+        let img =
+            image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, image::Rgba(color)));
+        Self::from_image(
+            device,
+            queue,
+            &img,
+            Some(label),
+            is_linear,
+            &SamplerSettings::default(),
+            None,
+        )
+        .expect("Uploading a 1x1 texture can't fail")
     }
 
+    /// Uploads `img` with a full mip chain. The mips are rendered with
+    /// `mipmaps` when there is one, otherwise they're downscaled on the CPU.
     pub fn from_image(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         img: &image::DynamicImage,
         label: Option<&str>,
-        is_normal_map: bool,
+        is_linear: bool,
+        sampler: &SamplerSettings,
+        mipmaps: Option<&mut MipmapGenerator>,
     ) -> Result<Self> {
-        let rgba = img.to_rgba8();
-        let dimensions = img.dimensions();
+        let texture =
+            Self::upload_layers(device, queue, &[img.to_rgba8()], label, is_linear, mipmaps);
+
+        let view = array_view(&texture, label);
+        let sampler = sampler.create_sampler(device, label);
+
+        Ok(Self {
+            texture,
+            view,
+            sampler,
+        })
+    }
+
+    /// Uploads `layers` as a 2D texture array, each with a full mip chain
+    /// like `from_image`.
+    pub fn from_layers(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layers: &[image::RgbaImage],
+        label: Option<&str>,
+        is_linear: bool,
+        sampler: &SamplerSettings,
+        mipmaps: Option<&mut MipmapGenerator>,
+    ) -> Result<Self> {
+        let dimensions = layers
+            .first()
+            .context("Texture array has no layers")?
+            .dimensions();
+        ensure!(
+            layers.iter().all(|layer| layer.dimensions() == dimensions),
+            "Texture array layers aren't all the same size"
+        );
+        let texture = Self::upload_layers(device, queue, layers, label, is_linear, mipmaps);
+
+        let view = array_view(&texture, label);
+        let sampler = sampler.create_sampler(device, label);
+
+        Ok(Self {
+            texture,
+            view,
+            sampler,
+        })
+    }
+
+    /// Creates a texture with a layer for each of `layers`, which all have
+    /// to be the same size, and fills in their mip chains.
+    fn upload_layers(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layers: &[image::RgbaImage],
+        label: Option<&str>,
+        is_linear: bool,
+        mipmaps: Option<&mut MipmapGenerator>,
+    ) -> wgpu::Texture {
+        let dimensions = layers[0].dimensions();
+        let mip_level_count = mip_level_count(dimensions.0, dimensions.1);
+        let format = rgba8_format(is_linear);
+
+        let mut usage = wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST;
+        if mipmaps.is_some() {
+            usage |= wgpu::TextureUsages::RENDER_ATTACHMENT;
+        }
 
-        let size = wgpu::Extent3d {
-            width: dimensions.0,
-            height: dimensions.1,
-            depth_or_array_layers: 1,
-        };
         let texture = device.create_texture(&wgpu::TextureDescriptor {
             label,
-            size,
-            mip_level_count: 1,
+            size: wgpu::Extent3d {
+                width: dimensions.0,
+                height: dimensions.1,
+                depth_or_array_layers: layers.len() as u32,
+            },
+            mip_level_count,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
-            format: if is_normal_map {
-                wgpu::TextureFormat::Rgba8Unorm
-            } else {
-                wgpu::TextureFormat::Rgba8UnormSrgb
+            format,
+            usage,
+        });
+
+        for (layer, image) in layers.iter().enumerate() {
+            write_rgba8(queue, &texture, layer as u32, 0, image);
+        }
+
+        match mipmaps {
+            Some(mipmaps) => mipmaps.generate(
+                device,
+                queue,
+                &texture,
+                format,
+                mip_level_count,
+                layers.len() as u32,
+            ),
+            None => {
+                // Averaging sRGB values directly comes out a little dark,
+                // but it's only the fallback
+                for (layer, image) in layers.iter().enumerate() {
+                    let mut previous = image.clone();
+                    for level in 1..mip_level_count {
+                        let width = (dimensions.0 >> level).max(1);
+                        let height = (dimensions.1 >> level).max(1);
+                        previous = image::imageops::resize(
+                            &previous,
+                            width,
+                            height,
+                            image::imageops::FilterType::Triangle,
+                        );
+                        write_rgba8(queue, &texture, layer as u32, level, &previous);
+                    }
+                }
+            }
+        }
+
+        texture
+    }
+
+    /// Uploads `levels` as they are, the first being the full size image
+    /// and each after it half the one before.
+    pub fn from_mip_chain(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        levels: &[image::RgbaImage],
+        label: Option<&str>,
+        is_linear: bool,
+        sampler: &SamplerSettings,
+    ) -> Result<Self> {
+        let (width, height) = levels.first().context("Mip chain is empty")?.dimensions();
+        for (level, image) in levels.iter().enumerate() {
+            let expected = ((width >> level).max(1), (height >> level).max(1));
+            ensure!(
+                image.dimensions() == expected,
+                "Mip level {} is {:?}, expected {:?}",
+                level,
+                image.dimensions(),
+                expected
+            );
+        }
+
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label,
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
             },
+            mip_level_count: levels.len() as u32,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: rgba8_format(is_linear),
             usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
         });
+        for (level, image) in levels.iter().enumerate() {
+            write_rgba8(queue, &texture, 0, level as u32, image);
+        }
 
-        queue.write_texture(
-            wgpu::ImageCopyTexture {
-                aspect: wgpu::TextureAspect::All,
-                texture: &texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-            },
-            &rgba,
-            wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: std::num::NonZeroU32::new(4 * dimensions.0),
-                rows_per_image: std::num::NonZeroU32::new(dimensions.1),
-            },
-            size,
+        let view = array_view(&texture, label);
+        let sampler = sampler.create_sampler(device, label);
+
+        Ok(Self {
+            texture,
+            view,
+            sampler,
+        })
+    }
+
+    /// Uploads a KTX2 or DDS image with the mips it came with. Formats the
+    /// device can't sample get decoded on the CPU instead.
+    ///
+    /// Like `from_image`, `is_linear` wins over whether the file says it's
+    /// sRGB.
+    pub fn from_compressed(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        image: &CompressedImage,
+        label: Option<&str>,
+        is_linear: bool,
+        sampler: &SamplerSettings,
+    ) -> Result<Self> {
+        let format = compressed::with_srgb(image.format, !is_linear);
+        let info = format.describe();
+        let (block_width, block_height) = (
+            info.block_dimensions.0 as u32,
+            info.block_dimensions.1 as u32,
         );
 
-        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            address_mode_u: wgpu::AddressMode::ClampToEdge,
-            address_mode_v: wgpu::AddressMode::ClampToEdge,
-            address_mode_w: wgpu::AddressMode::ClampToEdge,
-            mag_filter: wgpu::FilterMode::Linear,
-            min_filter: wgpu::FilterMode::Nearest,
-            mipmap_filter: wgpu::FilterMode::Nearest,
-            ..Default::default()
+        // Block compressed textures have to be a whole number of blocks
+        let aligned = image.width % block_width == 0 && image.height % block_height == 0;
+        if !device.features().contains(info.required_features) || !aligned {
+            log::debug!("Decompressing {:?} on the CPU", label.unwrap_or("texture"));
+            let decompressed = image.decompress()?;
+            return Self::from_compressed(device, queue, &decompressed, label, is_linear, sampler);
+        }
+
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label,
+            size: wgpu::Extent3d {
+                width: image.width,
+                height: image.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: image.levels.len() as u32,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
         });
 
+        for (level, data) in image.levels.iter().enumerate() {
+            // Mips smaller than a block still take up a whole one
+            let blocks_wide = ((image.width >> level).max(1) + block_width - 1) / block_width;
+            let blocks_high = ((image.height >> level).max(1) + block_height - 1) / block_height;
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture,
+                    mip_level: level as u32,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                data,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(blocks_wide * info.block_size as u32),
+                    rows_per_image: NonZeroU32::new(blocks_high),
+                },
+                wgpu::Extent3d {
+                    width: blocks_wide * block_width,
+                    height: blocks_high * block_height,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+
+        let view = array_view(&texture, label);
+        let sampler = sampler.create_sampler(device, label);
+
         Ok(Self {
             texture,
             view,
@@ -145,4 +653,46 @@ impl Texture {
             sampler,
         }
     }
+
+    /// A texture that can be rendered to and then sampled in a later pass,
+    /// e.g. one of the G-buffer targets.
+    pub fn create_render_target(
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+        format: wgpu::TextureFormat,
+        label: &str,
+    ) -> Self {
+        let size = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        });
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        Self {
+            texture,
+            view,
+            sampler,
+        }
+    }
 }
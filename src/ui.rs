@@ -0,0 +1,377 @@
+use std::{sync::Arc, time::Instant};
+
+use egui::{paint::ClippedMesh, CtxRef};
+use egui_winit_platform::{Platform, PlatformDescriptor};
+use glam::Vec3;
+use winit::{event::Event, window::Window};
+
+use crate::{
+    animation::LoopMode,
+    controller::CameraController,
+    deferred::MAX_LIGHTS,
+    fog::FogMode,
+    render::{DebugView, LightUniform, Render},
+};
+
+/// Owns the egui context and feeds it winit input.
+pub struct Gui {
+    platform: Platform,
+    start_time: Instant,
+}
+
+/// Everything `Render` needs to paint one frame of the UI.
+pub struct GuiFrame {
+    pub paint_jobs: Vec<ClippedMesh>,
+    pub texture: Arc<egui::Texture>,
+    pub scale_factor: f32,
+}
+
+impl Gui {
+    pub fn new(window: &Window) -> Self {
+        let size = window.inner_size();
+        let platform = Platform::new(PlatformDescriptor {
+            physical_width: size.width,
+            physical_height: size.height,
+            scale_factor: window.scale_factor(),
+            font_definitions: egui::FontDefinitions::default(),
+            style: Default::default(),
+        });
+
+        Self {
+            platform,
+            start_time: Instant::now(),
+        }
+    }
+
+    pub fn handle_event<T>(&mut self, event: &Event<T>) {
+        self.platform.handle_event(event);
+    }
+
+    /// Returns true if egui wants this event to itself, e.g. typing into a
+    /// text field or clicking on a panel.
+    pub fn captures_event<T>(&self, event: &Event<T>) -> bool {
+        self.platform.captures_event(event)
+    }
+
+    pub fn wants_pointer_input(&self) -> bool {
+        self.platform.context().wants_pointer_input()
+    }
+
+    pub fn frame(&mut self, window: &Window, build: impl FnOnce(&CtxRef)) -> GuiFrame {
+        self.platform
+            .update_time(self.start_time.elapsed().as_secs_f64());
+        self.platform.begin_frame();
+
+        let ctx = self.platform.context();
+        build(&ctx);
+
+        let (_output, shapes) = self.platform.end_frame(Some(window));
+
+        GuiFrame {
+            paint_jobs: ctx.tessellate(shapes),
+            texture: ctx.texture(),
+            scale_factor: window.scale_factor() as f32,
+        }
+    }
+}
+
+/// The tweak panel, so we don't have to recompile to try out new values.
+pub fn tweaks(ctx: &CtxRef, render: &mut Render, controller: &mut CameraController) {
+    egui::Window::new("Tweaks").show(ctx, |ui| {
+        let pending = render.pending_loads();
+        if pending > 0 {
+            ui.label(format!("Loading {} assets...", pending));
+        }
+
+        egui::CollapsingHeader::new("Light")
+            .default_open(true)
+            .show(ui, |ui| {
+                let light = render.light_mut();
+                ui.horizontal(|ui| {
+                    ui.label("position");
+                    ui.add(egui::DragValue::new(&mut light.position.x).speed(0.1));
+                    ui.add(egui::DragValue::new(&mut light.position.y).speed(0.1));
+                    ui.add(egui::DragValue::new(&mut light.position.z).speed(0.1));
+                });
+
+                let mut color = light.color.to_array();
+                ui.horizontal(|ui| {
+                    ui.label("color");
+                    if ui.color_edit_button_rgb(&mut color).changed() {
+                        light.color = color.into();
+                    }
+                });
+                ui.add(egui::Slider::new(&mut light.intensity, 0.0..=4.0).text("intensity"));
+            });
+
+        if let Some(lights) = render.point_lights_mut() {
+            egui::CollapsingHeader::new("Point lights")
+                .default_open(false)
+                .show(ui, |ui| {
+                    let mut removed = None;
+                    for (i, light) in lights.iter_mut().enumerate() {
+                        ui.push_id(("point light", i), |ui| {
+                            ui.horizontal(|ui| {
+                                ui.label(format!("light {}", i + 1));
+                                if ui.button("remove").clicked() {
+                                    removed = Some(i);
+                                }
+                            });
+                            ui.horizontal(|ui| {
+                                ui.label("position");
+                                ui.add(egui::DragValue::new(&mut light.position.x).speed(0.1));
+                                ui.add(egui::DragValue::new(&mut light.position.y).speed(0.1));
+                                ui.add(egui::DragValue::new(&mut light.position.z).speed(0.1));
+                            });
+
+                            let mut color = light.color.to_array();
+                            ui.horizontal(|ui| {
+                                ui.label("color");
+                                if ui.color_edit_button_rgb(&mut color).changed() {
+                                    light.color = color.into();
+                                }
+                            });
+                            ui.add(egui::Slider::new(&mut light.radius, 0.0..=50.0).text("radius"));
+                            ui.add(
+                                egui::Slider::new(&mut light.intensity, 0.0..=4.0)
+                                    .text("intensity"),
+                            );
+                        });
+                    }
+                    if let Some(i) = removed {
+                        lights.remove(i);
+                    }
+
+                    // The main light takes up one of the slots
+                    if lights.len() + 1 < MAX_LIGHTS && ui.button("add").clicked() {
+                        lights.push(LightUniform::new(Vec3::new(0.0, 2.0, 0.0), 8.0, Vec3::ONE));
+                    }
+                });
+        }
+
+        egui::CollapsingHeader::new("Animation")
+            .default_open(false)
+            .show(ui, |ui| {
+                for (i, player) in render.animations_mut().iter_mut().enumerate() {
+                    ui.push_id(i, |ui| {
+                        ui.label(player.clip().name.as_str());
+                        ui.horizontal(|ui| {
+                            let label = if player.is_playing() { "pause" } else { "play" };
+                            if ui.button(label).clicked() {
+                                if player.is_playing() {
+                                    player.pause();
+                                } else {
+                                    player.play();
+                                }
+                            }
+                            if ui.button("stop").clicked() {
+                                player.stop();
+                            }
+                        });
+
+                        egui::ComboBox::from_label("mode")
+                            .selected_text(format!("{:?}", player.mode))
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(&mut player.mode, LoopMode::Once, "Once");
+                                ui.selectable_value(&mut player.mode, LoopMode::Loop, "Loop");
+                                ui.selectable_value(
+                                    &mut player.mode,
+                                    LoopMode::PingPong,
+                                    "PingPong",
+                                );
+                            });
+                        ui.add(egui::Slider::new(&mut player.speed, -4.0..=4.0).text("speed"));
+
+                        let duration = player.clip().duration();
+                        let mut time = player.time();
+                        if ui
+                            .add(egui::Slider::new(&mut time, 0.0..=duration).text("time"))
+                            .changed()
+                        {
+                            player.seek(time);
+                        }
+                    });
+                }
+
+                let animated = render.animated_model_animations();
+                for (i, (names, playing)) in animated.iter().enumerate() {
+                    if names.is_empty() {
+                        continue;
+                    }
+                    let mut selected = *playing;
+                    ui.push_id(("animated model", i), |ui| {
+                        egui::ComboBox::from_label(format!("model {}", i))
+                            .selected_text(names.get(selected).map_or("", String::as_str))
+                            .show_ui(ui, |ui| {
+                                for (j, name) in names.iter().enumerate() {
+                                    ui.selectable_value(&mut selected, j, name.as_str());
+                                }
+                            });
+                    });
+                    if selected != *playing {
+                        render.play_animated_model_animation(i, selected);
+                    }
+                }
+            });
+
+        egui::CollapsingHeader::new("Camera")
+            .default_open(true)
+            .show(ui, |ui| {
+                ui.add(egui::Slider::new(controller.speed_mut(), 0.0..=50.0).text("speed"));
+                ui.add(
+                    egui::Slider::new(controller.sensitivity_mut(), 0.0..=2.0).text("sensitivity"),
+                );
+            });
+
+        egui::CollapsingHeader::new("Projection")
+            .default_open(true)
+            .show(ui, |ui| {
+                let projection = render.projection_mut();
+
+                let mut fov = projection.fov.to_degrees();
+                if ui
+                    .add(egui::Slider::new(&mut fov, 10.0..=120.0).text("fov"))
+                    .changed()
+                {
+                    projection.fov = fov.to_radians();
+                }
+
+                ui.add(egui::Slider::new(&mut projection.znear, 0.01..=10.0).text("near"));
+                // A far plane at or before the near one leaves no depth range
+                let min_far = projection.znear + 0.01;
+                projection.zfar = projection.zfar.max(min_far);
+                ui.add(egui::Slider::new(&mut projection.zfar, min_far..=1000.0).text("far"));
+            });
+
+        egui::CollapsingHeader::new("Textures")
+            .default_open(false)
+            .show(ui, |ui| {
+                let mut sampler = render.sampler_settings();
+
+                egui::ComboBox::from_label("address mode")
+                    .selected_text(format!("{:?}", sampler.address_mode_u))
+                    .show_ui(ui, |ui| {
+                        for mode in [
+                            wgpu::AddressMode::Repeat,
+                            wgpu::AddressMode::MirrorRepeat,
+                            wgpu::AddressMode::ClampToEdge,
+                        ] {
+                            ui.selectable_value(
+                                &mut sampler.address_mode_u,
+                                mode,
+                                format!("{:?}", mode),
+                            );
+                        }
+                    });
+                sampler.address_mode_v = sampler.address_mode_u;
+
+                for (label, filter) in [
+                    ("mag filter", &mut sampler.mag_filter),
+                    ("min filter", &mut sampler.min_filter),
+                    ("mip filter", &mut sampler.mipmap_filter),
+                ] {
+                    egui::ComboBox::from_label(label)
+                        .selected_text(format!("{:?}", filter))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut *filter, wgpu::FilterMode::Nearest, "Nearest");
+                            ui.selectable_value(&mut *filter, wgpu::FilterMode::Linear, "Linear");
+                        });
+                }
+
+                ui.add(egui::Slider::new(&mut sampler.anisotropy, 1..=16).text("anisotropy"));
+
+                render.set_sampler_settings(sampler);
+            });
+
+        egui::CollapsingHeader::new("Debug")
+            .default_open(true)
+            .show(ui, |ui| {
+                let debug = render.debug_mut();
+
+                egui::ComboBox::from_label("view")
+                    .selected_text(format!("{:?}", debug.view))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut debug.view, DebugView::Lit, "Lit");
+                        ui.selectable_value(&mut debug.view, DebugView::Albedo, "Albedo");
+                        ui.selectable_value(&mut debug.view, DebugView::Normals, "Normals");
+                        ui.selectable_value(&mut debug.view, DebugView::Lighting, "Lighting");
+                    });
+
+                ui.checkbox(&mut debug.show_light, "show light");
+            });
+
+        egui::CollapsingHeader::new("Fog")
+            .default_open(false)
+            .show(ui, |ui| {
+                let fog = render.fog_mut();
+
+                egui::ComboBox::from_label("mode")
+                    .selected_text(format!("{:?}", fog.mode))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut fog.mode, FogMode::Off, "Off");
+                        ui.selectable_value(&mut fog.mode, FogMode::Linear, "Linear");
+                        ui.selectable_value(&mut fog.mode, FogMode::Exponential, "Exponential");
+                        ui.selectable_value(
+                            &mut fog.mode,
+                            FogMode::ExponentialSquared,
+                            "ExponentialSquared",
+                        );
+                    });
+
+                let mut color = fog.color.to_array();
+                ui.horizontal(|ui| {
+                    ui.label("color");
+                    if ui.color_edit_button_rgb(&mut color).changed() {
+                        fog.color = color.into();
+                    }
+                });
+                ui.checkbox(&mut fog.use_as_clear_color, "use as clear color");
+
+                ui.add(egui::Slider::new(&mut fog.start, 0.0..=200.0).text("start"));
+                ui.add(egui::Slider::new(&mut fog.end, 0.0..=200.0).text("end"));
+                ui.add(egui::Slider::new(&mut fog.density, 0.0..=0.2).text("density"));
+
+                ui.checkbox(&mut fog.height_fog, "height fog");
+                ui.add(egui::Slider::new(&mut fog.height, -10.0..=10.0).text("height"));
+                ui.add(egui::Slider::new(&mut fog.height_density, 0.0..=0.5).text("density"));
+                ui.add(egui::Slider::new(&mut fog.height_falloff, 0.01..=2.0).text("falloff"));
+            });
+
+        egui::CollapsingHeader::new("SSAO")
+            .default_open(false)
+            .show(ui, |ui| {
+                let ssao = render.ssao_mut();
+
+                ui.checkbox(&mut ssao.enabled, "enabled");
+                ui.add(egui::Slider::new(&mut ssao.radius, 0.05..=2.0).text("radius"));
+                ui.add(egui::Slider::new(&mut ssao.bias, 0.0..=0.1).text("bias"));
+                ui.add(egui::Slider::new(&mut ssao.intensity, 0.5..=4.0).text("intensity"));
+                ui.add(egui::Slider::new(&mut ssao.sample_count, 1..=32).text("samples"));
+            });
+
+        egui::CollapsingHeader::new("Post")
+            .default_open(false)
+            .show(ui, |ui| {
+                let post = render.post_mut();
+
+                ui.add(egui::Slider::new(&mut post.exposure, 0.1..=4.0).text("exposure"));
+
+                ui.checkbox(&mut post.bloom, "bloom");
+                ui.add(egui::Slider::new(&mut post.bloom_threshold, 0.0..=4.0).text("threshold"));
+                ui.add(egui::Slider::new(&mut post.bloom_knee, 0.0..=1.0).text("knee"));
+                ui.add(egui::Slider::new(&mut post.bloom_intensity, 0.0..=2.0).text("intensity"));
+
+                ui.checkbox(&mut post.color_grading, "color grading");
+                ui.add(egui::Slider::new(&mut post.lut_strength, 0.0..=1.0).text("strength"));
+
+                ui.checkbox(&mut post.fxaa, "fxaa");
+
+                ui.checkbox(&mut post.vignette, "vignette");
+                ui.add(
+                    egui::Slider::new(&mut post.vignette_intensity, 0.0..=1.0).text("intensity"),
+                );
+                ui.add(egui::Slider::new(&mut post.vignette_radius, 0.0..=1.5).text("radius"));
+                ui.add(egui::Slider::new(&mut post.vignette_softness, 0.01..=1.0).text("softness"));
+            });
+    });
+}
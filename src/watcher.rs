@@ -0,0 +1,54 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver},
+    time::Duration,
+};
+
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+
+/// How long a file has to be left alone before we reload it, so we don't
+/// pick up a half written export.
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// Watches the asset directory for files that change on disk.
+pub struct AssetWatcher {
+    // Stops watching when dropped
+    _watcher: RecommendedWatcher,
+    events: Receiver<DebouncedEvent>,
+}
+
+impl AssetWatcher {
+    pub fn new(root: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let (sender, events) = mpsc::channel();
+        let mut watcher = notify::watcher(sender, DEBOUNCE)?;
+        watcher.watch(root, RecursiveMode::Recursive)?;
+
+        Ok(Self {
+            _watcher: watcher,
+            events,
+        })
+    }
+
+    /// Every file written or replaced since the last call.
+    pub fn changed(&self) -> Vec<PathBuf> {
+        let mut changed = Vec::new();
+        for event in self.events.try_iter() {
+            let path = match event {
+                DebouncedEvent::Create(path) | DebouncedEvent::Write(path) => path,
+                // A lot of editors save by writing a temporary file and
+                // renaming it over the original
+                DebouncedEvent::Rename(_, path) => path,
+                DebouncedEvent::Error(e, path) => {
+                    log::warn!("Error watching {:?}: {}", path, e);
+                    continue;
+                }
+                _ => continue,
+            };
+
+            if !changed.contains(&path) {
+                changed.push(path);
+            }
+        }
+        changed
+    }
+}